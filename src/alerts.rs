@@ -0,0 +1,169 @@
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::time::SystemTime;
+use crate::models;
+
+// Per-node capacity alerts. Operators list the nodes they care about in a TOML
+// file (`ALERT_THRESHOLDS_PATH`), each with the capacity change, in percent,
+// that's worth an alert:
+//
+//     "02abc...def" = 1.0
+//     "03123...456" = 25
+//
+// Nodes that aren't listed never alert. The worker checks the file before each
+// store and reloads it when it changes, so thresholds can be tuned without a restart.
+
+/// The thresholds file and what was last loaded from it.
+#[derive(Clone, Debug)]
+pub struct ThresholdsFile {
+    path: String,
+    modified: Option<SystemTime>,
+    thresholds: HashMap<String, f64>,
+}
+
+impl ThresholdsFile {
+    /// Reads and checks the file at `path`.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let thresholds = read_thresholds(path)?;
+        log_thresholds(&thresholds);
+        Ok(ThresholdsFile { path: path.to_string(), modified, thresholds })
+    }
+
+    /// Reloads the file if it changed since it was last read. A file that no
+    /// longer checks out is logged and the thresholds already loaded are kept.
+    pub fn reload_if_changed(&mut self) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.modified {
+            return;
+        }
+        // Remembered either way, so a bad file is reported once, not every cycle.
+        self.modified = modified;
+        match read_thresholds(&self.path) {
+            Ok(thresholds) => {
+                info!("[Alerts] Reloaded {}.", self.path);
+                log_thresholds(&thresholds);
+                self.thresholds = thresholds;
+            }
+            Err(e) => error!("[Alerts] Keeping the previous thresholds, {}", e),
+        }
+    }
+
+    /// Capacity-change thresholds in percent, by public key.
+    pub fn thresholds(&self) -> &HashMap<String, f64> {
+        &self.thresholds
+    }
+}
+
+/// Parses the thresholds file: a flat TOML table of public key to percent.
+fn read_thresholds(path: &str) -> Result<HashMap<String, f64>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("can't read '{}': {}", path, e))?;
+    let table: toml::Table = text.parse().map_err(|e| format!("'{}' isn't valid TOML: {}", path, e))?;
+
+    let mut thresholds = HashMap::new();
+    for (key, value) in table {
+        if !models::is_valid_pubkey(&key) {
+            return Err(format!("'{}' in '{}' isn't a public key (66 lowercase hex characters, starting 02 or 03)", key, path));
+        }
+        let percent = match value {
+            toml::Value::Float(f) => f,
+            toml::Value::Integer(i) => i as f64,
+            _ => return Err(format!("the threshold for '{}' in '{}' must be a number", key, path)),
+        };
+        if !percent.is_finite() || percent <= 0.0 {
+            return Err(format!("the threshold for '{}' in '{}' must be more than 0", key, path));
+        }
+        thresholds.insert(key, percent);
+    }
+    Ok(thresholds)
+}
+
+fn log_thresholds(thresholds: &HashMap<String, f64>) {
+    let mut keys: Vec<&String> = thresholds.keys().collect();
+    keys.sort();
+    info!("[Alerts] Custom capacity thresholds for {} nodes.", keys.len());
+    for key in keys {
+        info!("[Alerts] {}: {}%", key, thresholds[key]);
+    }
+}
+
+/// A watched node whose capacity moved by at least its threshold in one fetch.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CapacityAlert {
+    pub public_key: String,
+    pub alias: String,
+    pub from_sats: i64,
+    pub to_sats: i64,
+    /// How much it changed, in percent of `from_sats`. `null` when that was 0.
+    pub change_percent: Option<f64>,
+    pub threshold_percent: f64,
+}
+
+/// Checks one change against a node's threshold. From 0 sats, any change alerts.
+pub fn check(public_key: &str, alias: &str, from_sats: i64, to_sats: i64, threshold_percent: f64) -> Option<CapacityAlert> {
+    if from_sats == to_sats {
+        return None;
+    }
+    let change_percent = (from_sats != 0).then(|| (to_sats - from_sats) as f64 / from_sats as f64 * 100.0);
+    if change_percent.is_some_and(|p| p.abs() < threshold_percent) {
+        return None;
+    }
+    warn!(
+        "[Alerts] Capacity of {} ({}) went from {} to {} sats, past its {}% threshold.",
+        alias, public_key, from_sats, to_sats, threshold_percent
+    );
+    Some(CapacityAlert {
+        public_key: public_key.to_string(),
+        alias: alias.to_string(),
+        from_sats,
+        to_sats,
+        change_percent,
+        threshold_percent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_at_or_past_the_threshold() {
+        let key = "02".to_string() + &"a".repeat(64);
+        assert_eq!(check(&key, "A", 1000, 1000, 1.0), None);
+        assert_eq!(check(&key, "A", 1000, 1009, 1.0), None);
+        let up = check(&key, "A", 1000, 1010, 1.0).unwrap();
+        assert_eq!((up.from_sats, up.to_sats, up.change_percent), (1000, 1010, Some(1.0)));
+        assert_eq!(check(&key, "A", 1000, 990, 1.0).unwrap().change_percent, Some(-1.0));
+        assert_eq!(check(&key, "A", 0, 5, 50.0).unwrap().change_percent, None);
+    }
+
+    #[test]
+    fn thresholds_file_is_checked_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("lnrust-alerts-{}.toml", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let key = "02".to_string() + &"a".repeat(64);
+
+        fs::write(&path, format!("\"{}\" = 1.5\n", key)).unwrap();
+        let mut file = ThresholdsFile::load(path_str).unwrap();
+        assert_eq!(file.thresholds().get(&key), Some(&1.5));
+
+        // A bad edit is reported and the old thresholds stay.
+        fs::write(&path, format!("\"{}\" = -1\n", key)).unwrap();
+        file.modified = None;
+        file.reload_if_changed();
+        assert_eq!(file.thresholds().get(&key), Some(&1.5));
+
+        fs::write(&path, format!("\"{}\" = 10\n", key)).unwrap();
+        file.modified = None;
+        file.reload_if_changed();
+        assert_eq!(file.thresholds().get(&key), Some(&10.0));
+
+        for bad in ["\"02zz\" = 1.0", &format!("\"{}\" = \"1%\"", key), &format!("\"{}\" = 0", key), "not toml ="] {
+            fs::write(&path, bad).unwrap();
+            assert!(ThresholdsFile::load(path_str).is_err(), "{}", bad);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
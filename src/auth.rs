@@ -0,0 +1,53 @@
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{HttpRequest, HttpResponse};
+
+// Access control for the admin endpoints.
+
+/// Checks that the request carries `Authorization: Bearer <ADMIN_TOKEN>`.
+/// `token` is the configured `ADMIN_TOKEN`, from `Config::admin_token`.
+///
+/// If `ADMIN_TOKEN` isn't set, admin endpoints are turned off entirely rather
+/// than left open. On failure, returns the response to send back (boxed,
+/// since an `HttpResponse` is big to pass around in a `Result`).
+pub fn require_admin(req: &HttpRequest, token: Option<&str>) -> Result<(), Box<HttpResponse>> {
+    let Some(expected) = token else {
+        return Err(Box::new(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin endpoints are disabled. Set ADMIN_TOKEN to enable them."
+        }))));
+    };
+
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or("");
+
+    if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Box::new(
+            HttpResponse::Unauthorized()
+                .insert_header(("WWW-Authenticate", "Bearer"))
+                .json(serde_json::json!({ "error": "Missing or invalid admin token" })),
+        ))
+    }
+}
+
+/// Like `require_admin`, but if `ADMIN_TOKEN` isn't set the endpoint is left open
+/// instead of turned off. For endpoints that are fine to expose on a private deployment.
+pub fn require_admin_if_set(req: &HttpRequest, token: Option<&str>) -> Result<(), Box<HttpResponse>> {
+    if token.is_none() {
+        return Ok(());
+    }
+    require_admin(req, token)
+}
+
+/// Compares two byte strings without bailing out at the first difference,
+/// so the response time doesn't leak how much of the token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
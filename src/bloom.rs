@@ -0,0 +1,66 @@
+// A small bloom filter for checking which pubkeys a client already has.
+//
+// The scheme is kept simple so clients can build a compatible filter easily:
+// pubkeys are already uniformly random, so instead of hashing them we take
+// two u64s straight from the key bytes and use double hashing:
+//
+//   h1 = little-endian u64 of key bytes 1..9   (byte 0 is the 02/03 prefix)
+//   h2 = little-endian u64 of key bytes 9..17
+//   bit_i = (h1 + i * h2) mod m,  for i in 0..hashes
+//
+// where m is the number of bits in the filter. Bit `n` lives in byte `n / 8`,
+// under mask `1 << (n % 8)`.
+
+/// Biggest filter we accept, in bytes.
+pub const MAX_FILTER_BYTES: usize = 1024 * 1024;
+/// Most hash functions we accept.
+pub const MAX_HASHES: u32 = 32;
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Builds a filter from its hex-encoded bit array and number of hashes.
+    pub fn from_hex(bits_hex: &str, hashes: u32) -> Result<Self, String> {
+        if hashes == 0 || hashes > MAX_HASHES {
+            return Err(format!("hashes must be between 1 and {}", MAX_HASHES));
+        }
+        if bits_hex.is_empty() || !bits_hex.len().is_multiple_of(2) {
+            return Err("bits must be a non-empty, even-length hex string".to_string());
+        }
+        if bits_hex.len() / 2 > MAX_FILTER_BYTES {
+            return Err(format!("filter is larger than {} bytes", MAX_FILTER_BYTES));
+        }
+        let bits = decode_hex(bits_hex).ok_or("bits must be valid hex")?;
+        Ok(BloomFilter { bits, hashes })
+    }
+
+    /// True if the pubkey is probably in the filter, false if it definitely isn't.
+    /// A key that isn't a valid 33-byte hex pubkey is never considered present.
+    pub fn might_contain(&self, pubkey: &str) -> bool {
+        let Some(key) = decode_hex(pubkey).filter(|k| k.len() == 33) else {
+            return false;
+        };
+        let h1 = u64::from_le_bytes(key[1..9].try_into().unwrap());
+        let h2 = u64::from_le_bytes(key[9..17].try_into().unwrap());
+        let m = self.bits.len() as u64 * 8;
+
+        (0..self.hashes as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % m;
+            self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+}
+
+/// Decodes a hex string into bytes. Returns `None` if it isn't valid hex.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,147 @@
+use actix_web::HttpRequest;
+use actix_web::http::header::AUTHORIZATION;
+use moka::Expiry;
+use moka::future::Cache;
+use crate::config::{self, ConfigError};
+use crate::formatters::NodeFormat;
+use crate::listing::NodeListing;
+use crate::models::NodeResponse;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Cache helpers. Every endpoint can have its own TTL, so a stats view
+// can live longer than the node list without a separate cache setup.
+
+/// The endpoints with a cache, and the setting for each one's TTL.
+pub const ENDPOINTS: &[(&str, &str)] = &[
+    ("nodes", "CACHE_TTL_NODES"),
+    ("entities", "CACHE_TTL_ENTITIES"),
+    ("stats", "CACHE_TTL_STATS"),
+    ("count", "CACHE_TTL_COUNT"),
+    ("countries", "CACHE_TTL_COUNTRIES"),
+    ("ranked", "CACHE_TTL_RANKED"),
+];
+
+/// Picks how long a cache entry lives based on which endpoint created it.
+///
+/// Cache keys look like `"<endpoint>:<variant>"` (e.g. `"nodes:rfc3339"`),
+/// so we just look at the part before the first `:`.
+#[derive(Clone, Debug)]
+pub struct EndpointExpiry {
+    ttls: HashMap<String, Duration>,
+    default_ttl: Duration,
+}
+
+impl EndpointExpiry {
+    /// Reads the TTLs from the environment.
+    ///
+    /// For each endpoint in `ENDPOINTS` (e.g. `"nodes"`) it looks for its own
+    /// setting (`CACHE_TTL_NODES`). If that isn't set, the global
+    /// `CACHE_TTL_SECONDS` is used.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let default_ttl = Duration::from_secs(config::parse("CACHE_TTL_SECONDS", 10)?);
+
+        let mut ttls = HashMap::new();
+        for (name, var) in ENDPOINTS {
+            let ttl = config::parse(var, default_ttl.as_secs())?;
+            ttls.insert(name.to_string(), Duration::from_secs(ttl));
+        }
+
+        Ok(EndpointExpiry { ttls, default_ttl })
+    }
+
+    /// The TTL for a given cache key.
+    pub fn ttl_for(&self, key: &str) -> Duration {
+        let endpoint = key.split(':').next().unwrap_or(key);
+        self.ttls.get(endpoint).copied().unwrap_or(self.default_ttl)
+    }
+}
+
+impl<V> Expiry<String, V> for EndpointExpiry {
+    fn expire_after_create(&self, key: &String, _value: &V, _created_at: Instant) -> Option<Duration> {
+        Some(self.ttl_for(key))
+    }
+}
+
+/// The last response we successfully built for each cache key, kept without a TTL.
+///
+/// When the database is locked for maintenance, `/nodes` serves from here instead
+/// of waiting on the lock. It's a separate type so it doesn't clash with the
+/// main cache in `web::Data`.
+#[derive(Clone)]
+pub struct LastGoodNodes(pub Cache<String, Vec<NodeResponse>>);
+
+impl LastGoodNodes {
+    pub fn new() -> Self {
+        // Bounded so odd query variants can't grow it forever.
+        LastGoodNodes(Cache::builder().max_capacity(1_000).build())
+    }
+}
+
+/// The /nodes cache key for a parsed request.
+///
+/// Built from the parsed values, not the raw query string, so requests that mean
+/// the same thing share an entry: parameter order, aliases like `time_format`,
+/// defaults left out or spelled out, and limits over the cap all end up the same.
+/// The key case and streaming don't vary the cached data, so they aren't part of it.
+pub fn nodes_key(listing: &NodeListing, format: &NodeFormat) -> String {
+    format!("nodes:{}:{}", format.cache_key(), listing.cache_key())
+}
+
+/// Adds the caller's identity to a cache key, when per-client caching is on.
+///
+/// With `per_client` (`CACHE_PER_CLIENT=true`), requests carrying an
+/// `Authorization` header get their own entries (keyed by a hash of the header,
+/// never the raw credential), so personalized responses can't leak between
+/// clients. Anonymous requests, and every request when the flag is off, share
+/// the plain key.
+pub fn scoped_key(req: &HttpRequest, per_client: bool, key: String) -> String {
+    if !per_client {
+        return key;
+    }
+    match req.headers().get(AUTHORIZATION) {
+        Some(auth) => {
+            let mut hasher = DefaultHasher::new();
+            auth.as_bytes().hash(&mut hasher);
+            format!("{}:client={:016x}", key, hasher.finish())
+        }
+        None => key,
+    }
+}
+
+/// Hit and miss counts for each endpoint's cache.
+///
+/// moka doesn't keep these itself, so handlers call `record` after each lookup.
+/// Like `EndpointExpiry`, the endpoint is taken from the cache key's prefix.
+pub struct CacheStats {
+    counters: HashMap<String, (AtomicU64, AtomicU64)>,
+}
+
+impl CacheStats {
+    pub fn new(endpoints: &[&str]) -> Self {
+        let counters = endpoints
+            .iter()
+            .map(|name| (name.to_string(), (AtomicU64::new(0), AtomicU64::new(0))))
+            .collect();
+        CacheStats { counters }
+    }
+
+    /// Counts one lookup for the endpoint that owns `key`.
+    pub fn record(&self, key: &str, hit: bool) {
+        let endpoint = key.split(':').next().unwrap_or(key);
+        if let Some((hits, misses)) = self.counters.get(endpoint) {
+            if hit { hits } else { misses }.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `(hits, misses)` for an endpoint so far.
+    pub fn get(&self, endpoint: &str) -> (u64, u64) {
+        self.counters
+            .get(endpoint)
+            .map(|(hits, misses)| (hits.load(Ordering::Relaxed), misses.load(Ordering::Relaxed)))
+            .unwrap_or((0, 0))
+    }
+}
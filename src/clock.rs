@@ -0,0 +1,26 @@
+use chrono::{DateTime, Utc};
+
+// Where "now" comes from. Code that needs the current time takes a `Clock`
+// instead of calling `Utc::now()` directly, so time-dependent behaviour
+// (relative dates, schedules) can be pinned to a fixed instant.
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The real wall clock. This is what the server uses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A fixed instant is a clock that never moves. Handy for tests.
+impl Clock for DateTime<Utc> {
+    fn now(&self) -> DateTime<Utc> {
+        *self
+    }
+}
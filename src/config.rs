@@ -0,0 +1,297 @@
+use reqwest::Url;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::cache::EndpointExpiry;
+use crate::db;
+use crate::formatters::{CapacityTiers, FormatSettings};
+use crate::worker::WorkerConfig;
+
+// App settings, read and checked once at startup. A value that's set but can't
+// be used stops the app with an error naming the setting, instead of quietly
+// falling back to the default.
+//
+// Settings come from the environment (and .env). An optional TOML file at
+// `CONFIG_PATH` can provide them too, with lowercase names
+// (`server_port = 8080`). Precedence: defaults < file < environment.
+
+/// Biggest page a client can ask for with `limit` on /nodes, and the most
+/// `DEFAULT_PAGE_SIZE` can be.
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Most points a sparkline can have, from `SPARKLINE_POINTS` or `?points=`.
+pub const MAX_SPARKLINE_POINTS: usize = 500;
+
+/// Furthest a forecast can look ahead, from `FORECAST_HORIZON_DAYS` or `?horizon_days=`.
+pub const MAX_FORECAST_HORIZON_DAYS: u32 = 365;
+
+/// Most decimal places `STATS_AVG_PRECISION` can ask for. Past this, the
+/// exact average's arithmetic could overflow an i128.
+pub const MAX_AVG_PRECISION: u32 = 18;
+
+/// The settings the app reads at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// `DATABASE_PATH`
+    pub database_path: String,
+    /// `DB_INIT_RETRIES`: tries while another process holds the DB lock at startup.
+    pub db_init_retries: u32,
+    /// `DB_POOL_SIZE`
+    pub db_pool_size: u32,
+    /// `DB_BUSY_TIMEOUT_MS`: how long a connection waits on a lock held by
+    /// another one before failing. Used for every connection we open.
+    pub db_busy_timeout: Duration,
+    /// `SERVER_PORT`
+    pub server_port: u16,
+    /// `ENABLE_COMPRESSION`
+    pub enable_compression: bool,
+    /// `CORS_ALLOWED_ORIGINS`: origins, or `*` for any. Empty means none.
+    pub cors_allowed_origins: Vec<String>,
+    /// `RATE_LIMIT_PER_MINUTE`: 0 turns limiting off.
+    pub rate_limit_per_minute: u32,
+    /// `RATE_LIMIT_BEHIND_PROXY`
+    pub rate_limit_behind_proxy: bool,
+    /// `REQUEST_TIMEOUT_SECONDS`: 0 turns the deadline off.
+    pub request_timeout_secs: u64,
+    /// `STATS_AVG_PRECISION`: decimal places of the exact average on /stats.
+    pub stats_avg_precision: u32,
+    /// `DEFAULT_PAGE_SIZE`: /nodes page size when the client doesn't pass `limit`.
+    pub default_page_size: i64,
+    /// `STALE_FLAG_AFTER_SECONDS`: how long before the last successful fetch a
+    /// node's `updated_at` can be before `?include_stale_flag=true` calls it stale.
+    pub stale_flag_after_secs: u64,
+    /// `SPARKLINE_POINTS`: how many values /nodes/{public_key}/sparkline returns by default.
+    pub sparkline_points: usize,
+    /// `FORECAST_HORIZON_DAYS`: how far /nodes/{public_key}/forecast projects by default.
+    pub forecast_horizon_days: u32,
+    /// `DB_JOURNAL_MODE`: one of `db::JOURNAL_MODES`, lowercase.
+    pub db_journal_mode: String,
+    /// `READ_BUSY_TIMEOUT_MS`: how long /nodes waits on a locked DB before
+    /// serving the last good response.
+    pub read_busy_timeout: Duration,
+    /// `ADMIN_TOKEN`: bearer token for the admin endpoints. `None` turns them off.
+    pub admin_token: Option<String>,
+    /// `CACHE_TTL_SECONDS` and `CACHE_TTL_<ENDPOINT>`.
+    pub cache_expiry: EndpointExpiry,
+    /// `ALLOW_CACHE_BYPASS`: lets /nodes take `nocache=true`.
+    pub allow_cache_bypass: bool,
+    /// `CACHE_PER_CLIENT`: separate cache entries per `Authorization` header.
+    pub cache_per_client: bool,
+    /// `PUBKEY_PREFIX_MIN_LENGTH`: shortest prefix /nodes/search/pubkey accepts.
+    pub pubkey_prefix_min_length: usize,
+    /// `ENTITY_SEPARATOR`: /entities' default `separator`.
+    pub entity_separator: String,
+    /// `ENTITY_PREFIX_DEPTH`: /entities' default `depth`.
+    pub entity_prefix_depth: usize,
+    /// `CAPACITY_TIER_WHALE`, `CAPACITY_TIER_LARGE`, `CAPACITY_TIER_MEDIUM`.
+    pub capacity_tiers: CapacityTiers,
+    /// `CAPACITY_GROUPING`, `FIRST_SEEN_ZERO` and `DISPLAY_TIMEZONE`, handed to
+    /// the formatters once at startup.
+    pub format: FormatSettings,
+    /// `DATA_QUALITY_INTERVAL_SECONDS`: how often the data-quality gauges on /metrics are recomputed.
+    pub data_quality_interval_secs: u64,
+    /// `PUSHGATEWAY_URL`: where to push metrics to, if anywhere.
+    pub pushgateway_url: Option<String>,
+    /// `PUSH_INTERVAL_SECONDS`: how often to push them.
+    pub push_interval_secs: u64,
+    pub worker: WorkerConfig,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let config = Config {
+            database_path: raw("DATABASE_PATH").unwrap_or("nodes.db".to_string()),
+            db_init_retries: parse("DB_INIT_RETRIES", 5)?,
+            db_pool_size: parse("DB_POOL_SIZE", 8)?,
+            db_busy_timeout: Duration::from_millis(parse("DB_BUSY_TIMEOUT_MS", 5000)?),
+            server_port: parse("SERVER_PORT", 8080)?,
+            enable_compression: flag("ENABLE_COMPRESSION", true)?,
+            cors_allowed_origins: list("CORS_ALLOWED_ORIGINS"),
+            rate_limit_per_minute: parse("RATE_LIMIT_PER_MINUTE", 0)?,
+            rate_limit_behind_proxy: flag("RATE_LIMIT_BEHIND_PROXY", false)?,
+            request_timeout_secs: parse("REQUEST_TIMEOUT_SECONDS", 30)?,
+            stats_avg_precision: parse("STATS_AVG_PRECISION", 8)?,
+            default_page_size: parse("DEFAULT_PAGE_SIZE", 50)?,
+            stale_flag_after_secs: parse("STALE_FLAG_AFTER_SECONDS", 86400)?,
+            sparkline_points: parse("SPARKLINE_POINTS", 30)?,
+            forecast_horizon_days: parse("FORECAST_HORIZON_DAYS", 30)?,
+            db_journal_mode: raw("DB_JOURNAL_MODE").unwrap_or("wal".to_string()).to_lowercase(),
+            read_busy_timeout: Duration::from_millis(parse("READ_BUSY_TIMEOUT_MS", 500)?),
+            admin_token: raw("ADMIN_TOKEN"),
+            cache_expiry: EndpointExpiry::from_env()?,
+            allow_cache_bypass: flag("ALLOW_CACHE_BYPASS", false)?,
+            cache_per_client: flag("CACHE_PER_CLIENT", false)?,
+            pubkey_prefix_min_length: parse("PUBKEY_PREFIX_MIN_LENGTH", 6)?,
+            // Not trimmed like other settings, so a space can be the separator.
+            entity_separator: env::var("ENTITY_SEPARATOR").ok().filter(|s| !s.is_empty()).unwrap_or("-".to_string()),
+            entity_prefix_depth: parse("ENTITY_PREFIX_DEPTH", 1)?,
+            capacity_tiers: CapacityTiers::from_env()?,
+            format: FormatSettings::from_env()?,
+            data_quality_interval_secs: parse("DATA_QUALITY_INTERVAL_SECONDS", 60)?,
+            pushgateway_url: pushgateway_url()?,
+            push_interval_secs: parse("PUSH_INTERVAL_SECONDS", 15)?,
+            worker: WorkerConfig::from_env()?,
+        };
+        if config.db_pool_size == 0 {
+            return Err(ConfigError::new("DB_POOL_SIZE", "must be at least 1"));
+        }
+        if config.server_port == 0 {
+            return Err(ConfigError::new("SERVER_PORT", "must be between 1 and 65535"));
+        }
+        if !(1..=MAX_PAGE_SIZE).contains(&config.default_page_size) {
+            return Err(ConfigError::new("DEFAULT_PAGE_SIZE", format!("must be between 1 and {}", MAX_PAGE_SIZE)));
+        }
+        if !(1..=MAX_SPARKLINE_POINTS).contains(&config.sparkline_points) {
+            return Err(ConfigError::new("SPARKLINE_POINTS", format!("must be between 1 and {}", MAX_SPARKLINE_POINTS)));
+        }
+        if !(1..=MAX_FORECAST_HORIZON_DAYS).contains(&config.forecast_horizon_days) {
+            return Err(ConfigError::new("FORECAST_HORIZON_DAYS", format!("must be between 1 and {}", MAX_FORECAST_HORIZON_DAYS)));
+        }
+        if !db::JOURNAL_MODES.contains(&config.db_journal_mode.as_str()) {
+            return Err(ConfigError::new("DB_JOURNAL_MODE", format!("must be one of: {}", db::JOURNAL_MODES.join(", "))));
+        }
+        if !(1..=66).contains(&config.pubkey_prefix_min_length) {
+            return Err(ConfigError::new("PUBKEY_PREFIX_MIN_LENGTH", "must be between 1 and 66"));
+        }
+        if config.entity_prefix_depth == 0 {
+            return Err(ConfigError::new("ENTITY_PREFIX_DEPTH", "must be at least 1"));
+        }
+        if config.data_quality_interval_secs == 0 {
+            return Err(ConfigError::new("DATA_QUALITY_INTERVAL_SECONDS", "must be at least 1"));
+        }
+        if config.push_interval_secs == 0 {
+            return Err(ConfigError::new("PUSH_INTERVAL_SECONDS", "must be at least 1"));
+        }
+        if config.stats_avg_precision > MAX_AVG_PRECISION {
+            return Err(ConfigError::new("STATS_AVG_PRECISION", format!("can't be more than {}", MAX_AVG_PRECISION)));
+        }
+        Ok(config)
+    }
+}
+
+/// A setting that's missing something or has a value we can't use.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: &'static str,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: &'static str, message: impl Into<String>) -> Self {
+        ConfigError { key, message: message.into() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads the TOML file at `CONFIG_PATH`, if set, and copies its values into the
+/// environment, skipping any that are already set there. Everything that reads
+/// a setting then sees the file's value, with env vars still taking precedence.
+///
+/// Must run before the server, the worker or the logger start other threads.
+pub fn load_file() -> Result<(), ConfigError> {
+    let Some(path) = raw("CONFIG_PATH") else {
+        return Ok(());
+    };
+    let text = fs::read_to_string(&path).map_err(|e| ConfigError::new("CONFIG_PATH", format!("can't read '{}': {}", path, e)))?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e| ConfigError::new("CONFIG_PATH", format!("'{}' isn't valid TOML: {}", path, e)))?;
+
+    for (key, value) in table {
+        let value = match value {
+            // Lists are joined, so `api_url = ["a", "b"]` works like `API_URL=a,b`.
+            toml::Value::Array(items) => items.iter().map(|item| file_value(&key, item)).collect::<Result<Vec<_>, _>>()?.join(","),
+            other => file_value(&key, &other)?,
+        };
+        let var = key.to_uppercase();
+        if env::var_os(&var).is_none() {
+            // SAFETY: called at the top of `main`, before anything else runs that
+            // could read or write the environment from another thread.
+            unsafe { env::set_var(var, value) };
+        }
+    }
+    Ok(())
+}
+
+/// A single value from the config file as the string an env var would hold.
+fn file_value(key: &str, value: &toml::Value) -> Result<String, ConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(ConfigError::new("CONFIG_PATH", format!("'{}' must be a string, number, boolean or a list of those", key))),
+    }
+}
+
+/// The raw value of a setting. Blank counts as not set.
+pub fn raw(key: &str) -> Option<String> {
+    env::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// A setting parsed as `T`, or `default` when it isn't set.
+pub fn parse<T: FromStr>(key: &'static str, default: T) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    match raw(key) {
+        None => Ok(default),
+        Some(value) => value.parse().map_err(|e| ConfigError::new(key, format!("can't use '{}': {}", value, e))),
+    }
+}
+
+/// A `true`/`false` setting.
+pub fn flag(key: &'static str, default: bool) -> Result<bool, ConfigError> {
+    match raw(key).as_deref() {
+        None => Ok(default),
+        Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(other) => Err(ConfigError::new(key, format!("must be 'true' or 'false', got '{}'", other))),
+    }
+}
+
+/// A comma-separated setting, with blank entries dropped.
+pub fn list(key: &str) -> Vec<String> {
+    raw(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Upstream URLs must be https. Plain http is only allowed for local test
+/// servers and .onion mirrors, which Tor already encrypts.
+pub fn check_api_url(key: &'static str, value: &str) -> Result<(), ConfigError> {
+    let url = Url::parse(value).map_err(|e| ConfigError::new(key, format!("'{}' is not a valid URL: {}", value, e)))?;
+    let host = url.host_str().unwrap_or_default();
+    let plain_ok = matches!(host, "localhost" | "127.0.0.1" | "[::1]") || host.ends_with(".onion");
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if plain_ok => Ok(()),
+        _ => Err(ConfigError::new(key, format!("'{}' must use https", value))),
+    }
+}
+
+/// Reads `PUSHGATEWAY_URL`. Unlike the upstream URLs, plain http is fine:
+/// a Pushgateway usually sits on the same internal network.
+fn pushgateway_url() -> Result<Option<String>, ConfigError> {
+    let Some(value) = raw("PUSHGATEWAY_URL") else {
+        return Ok(None);
+    };
+    let url = Url::parse(&value).map_err(|e| ConfigError::new("PUSHGATEWAY_URL", format!("'{}' is not a valid URL: {}", value, e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ConfigError::new("PUSHGATEWAY_URL", format!("'{}' must use http or https", value)));
+    }
+    Ok(Some(value))
+}
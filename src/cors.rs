@@ -0,0 +1,25 @@
+use actix_cors::Cors;
+use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+
+// Cross-origin access, so browser apps on other sites can call the API.
+
+/// Builds the CORS middleware for the given origins. Preflight (OPTIONS)
+/// requests are answered here and never reach the handlers.
+///
+/// With no origins, browsers only allow same-origin calls. Requests from other
+/// origins aren't rejected outright, they just don't get the CORS headers,
+/// so non-browser clients keep working.
+pub fn middleware(origins: &[String]) -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(["GET"])
+        .allowed_header(IF_NONE_MATCH)
+        // Lets scripts read the ETag, to send it back in If-None-Match.
+        .expose_headers([ETAG])
+        .block_on_origin_mismatch(false)
+        .max_age(3600);
+
+    if origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+    origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin))
+}
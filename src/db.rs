@@ -1,9 +1,78 @@
-use rusqlite::{Connection, Result, OpenFlags};
+use rusqlite::{Connection, ErrorCode, Result, OpenFlags, TransactionBehavior};
+use r2d2_sqlite::SqliteConnectionManager;
+use std::fmt;
+use std::time::Duration;
 use chrono::DateTime;
 use log::{error, info};
 
 // This module handles all the database setup and migration logic.
 
+/// Journal modes `DB_JOURNAL_MODE` may pick from. The default is WAL, so API
+/// reads don't block the worker's writes (and the other way around).
+pub const JOURNAL_MODES: &[&str] = &["wal", "delete", "truncate", "persist", "memory", "off"];
+
+/// Sets the journal mode and logs what SQLite actually picked. SQLite silently
+/// keeps the old mode when it can't switch, so that case is an error too.
+fn set_journal_mode(conn: &Connection, requested: &str) -> std::result::Result<(), DbError> {
+    if !JOURNAL_MODES.contains(&requested) {
+        return Err(DbError::Config(format!("Unknown DB_JOURNAL_MODE '{}'. Use one of: {}", requested, JOURNAL_MODES.join(", "))));
+    }
+    let actual: String = conn.query_row(&format!("PRAGMA journal_mode={}", requested), [], |row| row.get(0))?;
+    if !actual.eq_ignore_ascii_case(requested) {
+        return Err(DbError::Config(format!("Couldn't set journal mode to '{}', SQLite kept '{}'", requested, actual)));
+    }
+    info!("[DB] Journal mode is '{}'.", actual);
+    Ok(())
+}
+
+/// The connection pool shared by the API handlers and the worker.
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+/// The current schema of the `nodes` table.
+/// IF NOT EXISTS so it can be run again safely (and by two instances at once).
+const CREATE_NODES_TABLE: &str = "CREATE TABLE IF NOT EXISTS nodes (
+    public_key    TEXT PRIMARY KEY,
+    alias         TEXT NOT NULL,
+    capacity      INTEGER NOT NULL,
+    first_seen    INTEGER NOT NULL,
+    channels      INTEGER NOT NULL DEFAULT 0,
+    last_seen     INTEGER,
+    city          TEXT,
+    country       TEXT,
+    country_code  TEXT,
+    updated_at    INTEGER
+)";
+
+/// Named copies of the `nodes` table, taken on demand by an admin
+/// (POST /admin/snapshot/{name}) so two points in time can be compared.
+const CREATE_SNAPSHOT_TABLES: &str = "
+CREATE TABLE IF NOT EXISTS snapshots (
+    name          TEXT PRIMARY KEY,
+    created_at    INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS snapshot_nodes (
+    snapshot      TEXT NOT NULL REFERENCES snapshots(name) ON DELETE CASCADE,
+    public_key    TEXT NOT NULL,
+    alias         TEXT NOT NULL,
+    capacity      INTEGER NOT NULL,
+    first_seen    INTEGER NOT NULL,
+    channels      INTEGER NOT NULL DEFAULT 0,
+    country       TEXT,
+    country_code  TEXT,
+    PRIMARY KEY (snapshot, public_key)
+);";
+
+/// Capacity over time, one row per change. Appended by the worker in `store_nodes`.
+const CREATE_HISTORY_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS node_history (
+    id            INTEGER PRIMARY KEY,
+    public_key    TEXT NOT NULL,
+    capacity      INTEGER NOT NULL,
+    channels      INTEGER NOT NULL,
+    recorded_at   INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_history_node ON node_history(public_key, recorded_at);";
+
 /// Checks if we need to update the database schema.
 /// The old schema used TEXT for `first_seen`, but the new one uses INTEGER.
 fn needs_migration(conn: &Connection) -> Result<bool> {
@@ -13,37 +82,46 @@ fn needs_migration(conn: &Connection) -> Result<bool> {
         .collect::<Result<Vec<_>, _>>()?;
 
     // If the 'first_seen' column is TEXT, we need to migrate.
-    if let Some((_, col_type)) = column_types.iter().find(|(name, _)| name == "first_seen") {
-        if col_type.eq_ignore_ascii_case("TEXT") {
-            return Ok(true);
-        }
+    if let Some((_, col_type)) = column_types.iter().find(|(name, _)| name == "first_seen")
+        && col_type.eq_ignore_ascii_case("TEXT")
+    {
+        return Ok(true);
     }
 
     Ok(false)
 }
 
+/// How long an instance waits for another one to finish migrating.
+const MIGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
 /// Updates the database from the old schema to the new one.
 /// It renames the old table, creates a new one, and copies the data over,
 /// converting `first_seen` from text to a number.
-/// It's all in a transaction, so it's safe.
+///
+/// It's all in an EXCLUSIVE transaction, so if several instances start at once
+/// against the same file, only one of them migrates. The others wait for the lock,
+/// then see the new schema and skip.
 fn run_migration(conn: &mut Connection) -> Result<()> {
     info!("[DB] Old schema found, running migration...");    
-    
-    let tx = conn.transaction()?;
+
+    // Migrating can take a while on a big table, so wait longer than usual for the lock.
+    conn.busy_timeout(MIGRATION_LOCK_TIMEOUT)?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+
+    // Someone else may have migrated while we were waiting for the lock.
+    if !needs_migration(&tx)? {
+        info!("[DB] Migration was already done by another instance.");
+        return Ok(());
+    }
 
     // 1. Rename the old table so we don't lose data.
+    // The old `nodes` table still has all the data at this point, so a stray
+    // temp table from some earlier attempt can go.
+    tx.execute("DROP TABLE IF EXISTS nodes_old_migration_temp", [])?;
     tx.execute("ALTER TABLE nodes RENAME TO nodes_old_migration_temp", [])?;
 
     // 2. Create the new table with the correct schema.
-    tx.execute(
-        "CREATE TABLE nodes (
-            public_key    TEXT PRIMARY KEY,
-            alias         TEXT NOT NULL,
-            capacity      INTEGER NOT NULL,
-            first_seen    INTEGER NOT NULL
-        )",
-        [],
-    )?;
+    tx.execute(CREATE_NODES_TABLE, [])?;
 
     // 3. Copy data from the old table to the new one.
     {
@@ -93,16 +171,65 @@ fn run_migration(conn: &mut Connection) -> Result<()> {
 }
 
 
+/// Adds a column to an existing table if it isn't there yet (tables created
+/// before the column existed). Safe to run on every startup.
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let has_column = |conn: &Connection| -> Result<bool> {
+        conn.query_row(&format!("SELECT EXISTS (SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1)", table), [column], |row| row.get(0))
+    };
+    if has_column(conn)? {
+        return Ok(());
+    }
+    info!("[DB] Adding column '{}' to '{}'.", column, table);
+    match conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), []) {
+        Ok(_) => Ok(()),
+        // Another instance may have added it in the meantime.
+        Err(_) if has_column(conn)? => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Indexes on `nodes`, by name.
+const NODE_INDEXES: &[(&str, &str)] = &[
+    // Sorting by capacity, the default order.
+    ("idx_capacity", "CREATE INDEX IF NOT EXISTS idx_capacity ON nodes(capacity DESC)"),
+    // Sorting by `first_seen`.
+    ("idx_first_seen", "CREATE INDEX IF NOT EXISTS idx_first_seen ON nodes(first_seen)"),
+    // Case-insensitive alias lookups. SQLite's LIKE ignores ASCII case, so it
+    // can use this one for prefix matches.
+    ("idx_alias_nocase", "CREATE INDEX IF NOT EXISTS idx_alias_nocase ON nodes(alias COLLATE NOCASE)"),
+    // GET /nodes/changes.
+    ("idx_updated_at", "CREATE INDEX IF NOT EXISTS idx_updated_at ON nodes(updated_at)"),
+    // GET /nodes/departed and stale-node pruning.
+    ("idx_last_seen", "CREATE INDEX IF NOT EXISTS idx_last_seen ON nodes(last_seen)"),
+];
+
+/// Creates the indexes we rely on, logging the ones that were missing.
+/// Safe to run on every startup, so existing databases pick up new indexes.
+fn ensure_indexes(conn: &Connection) -> Result<()> {
+    for (name, sql) in NODE_INDEXES {
+        let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?1)", [name], |row| row.get(0))?;
+        conn.execute(sql, [])?;
+        if !exists {
+            info!("[DB] Created index '{}'.", name);
+        }
+    }
+    Ok(())
+}
+
 /// Gets the database ready to use.
 /// It creates the DB file and the `nodes` table if they don't exist.
 /// If the table is old, it runs the migration.
-pub fn initialize_database(db_path: &str) -> Result<()> {
+///
+/// Every step is idempotent, so a DB left half-initialized (e.g. a crash
+/// between statements) gets finished off on the next start.
+pub fn initialize_database(db_path: &str, busy_timeout: Duration, journal_mode: &str) -> std::result::Result<(), DbError> {
     // Open the DB connection.
     // We set a busy timeout just in case the database is locked for a moment.
-    let mut conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_NO_MUTEX).map(|c| {
-        c.busy_timeout(std::time::Duration::from_secs(5));
-        c
-    })?;
+    let mut conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    conn.busy_timeout(busy_timeout)?;
+    // The journal mode is stored in the file, so every later connection gets it.
+    set_journal_mode(&conn, journal_mode)?;
 
     // Check if the 'nodes' table already exists.
     let table_exists: bool = conn.query_row(
@@ -113,23 +240,231 @@ pub fn initialize_database(db_path: &str) -> Result<()> {
 
     if !table_exists {
         info!("[DB] 'nodes' table not found, creating it.");
-        conn.execute(
-            "CREATE TABLE nodes (
-                public_key    TEXT PRIMARY KEY,
-                alias         TEXT NOT NULL,
-                capacity      INTEGER NOT NULL,
-                first_seen    INTEGER NOT NULL
-            )",
-            [],
-        )?;
-        // Add an index to make sorting by capacity faster.
-        conn.execute("CREATE INDEX IF NOT EXISTS idx_capacity ON nodes(capacity DESC)", [])?;
-    } else {
+        conn.execute(CREATE_NODES_TABLE, [])?;
+    } else if needs_migration(&conn)? {
         // If the table exists, check if we need to update its schema.
-        if needs_migration(&conn)? {
-            run_migration(&mut conn)?;
-        }
+        run_migration(&mut conn)?;
+        conn.busy_timeout(busy_timeout)?;
     }
 
+    conn.execute_batch(CREATE_SNAPSHOT_TABLES)?;
+    conn.execute_batch(CREATE_HISTORY_TABLE)?;
+    ensure_column(&conn, "nodes", "channels", "INTEGER NOT NULL DEFAULT 0")?;
+    ensure_column(&conn, "snapshot_nodes", "channels", "INTEGER NOT NULL DEFAULT 0")?;
+    ensure_column(&conn, "nodes", "last_seen", "INTEGER")?;
+    ensure_column(&conn, "nodes", "city", "TEXT")?;
+    ensure_column(&conn, "nodes", "country", "TEXT")?;
+    ensure_column(&conn, "nodes", "country_code", "TEXT")?;
+    ensure_column(&conn, "snapshot_nodes", "country", "TEXT")?;
+    ensure_column(&conn, "snapshot_nodes", "country_code", "TEXT")?;
+    ensure_column(&conn, "nodes", "updated_at", "INTEGER")?;
+    // Rows from before `last_seen` existed count as seen now, so stale-node
+    // pruning gives them a full TTL instead of deleting them right away.
+    conn.execute("UPDATE nodes SET last_seen = CAST(strftime('%s', 'now') AS INTEGER) WHERE last_seen IS NULL", [])?;
+    // We don't know when older rows last changed. Their last sighting is the
+    // best guess, and it means they show up once in /nodes/changes.
+    conn.execute("UPDATE nodes SET updated_at = last_seen WHERE updated_at IS NULL", [])?;
+
+    // Always make sure the indexes are there. The migration recreates the
+    // table without them, and an interrupted first start may have skipped them.
+    // After the columns above, since some indexes use them.
+    ensure_indexes(&conn)?;
+
     Ok(())
-} 
\ No newline at end of file
+}
+
+
+/// True if the error means another connection is holding the database
+/// (a write lock, VACUUM, an index rebuild...), rather than a real failure.
+pub fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(e, _)
+            if e.code == ErrorCode::DatabaseBusy || e.code == ErrorCode::DatabaseLocked
+    )
+}
+
+/// Builds the connection pool. Every connection gets `busy_timeout`
+/// (`DB_BUSY_TIMEOUT_MS`), and `size` (`DB_POOL_SIZE`) caps how many are open at once.
+///
+/// `synchronous` is per connection, so it's set here. NORMAL is safe with WAL
+/// (a crash can lose the last commits, but never corrupts the file), so it's
+/// only used when `journal_mode` (`DB_JOURNAL_MODE`) is `wal`.
+pub fn build_pool(db_path: &str, size: u32, busy_timeout: Duration, journal_mode: &str) -> std::result::Result<DbPool, r2d2::Error> {
+    let wal = journal_mode == "wal";
+    let manager = SqliteConnectionManager::file(db_path).with_init(move |conn| {
+        conn.busy_timeout(busy_timeout)?;
+        if wal {
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+        }
+        Ok(())
+    });
+    r2d2::Pool::builder().max_size(size).build(manager)
+}
+
+/// Either no pooled connection was available in time, or using it failed.
+#[derive(Debug)]
+pub enum DbError {
+    Pool(r2d2::Error),
+    Sqlite(rusqlite::Error),
+    /// The database settings in .env can't be applied.
+    Config(String),
+}
+
+impl DbError {
+    pub fn is_busy(&self) -> bool {
+        matches!(self, DbError::Sqlite(e) if is_busy(e))
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Pool(e) => write!(f, "no database connection available: {}", e),
+            DbError::Sqlite(e) => e.fmt(f),
+            DbError::Config(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<r2d2::Error> for DbError {
+    fn from(e: r2d2::Error) -> Self {
+        DbError::Pool(e)
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
+
+/// Helpers for tests that need a real database file.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use std::path::PathBuf;
+
+    pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A fresh database path under the temp dir, removed (with its WAL files) on drop.
+    /// `name` must be unique per test, since tests run in parallel.
+    pub struct TempDb(PathBuf);
+
+    impl TempDb {
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("lnrust-db-{}-{}.db", name, std::process::id()));
+            let db = TempDb(path);
+            db.remove();
+            db
+        }
+
+        pub fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        /// Initializes the schema and opens a pool on it.
+        pub fn pool(&self) -> DbPool {
+            initialize_database(self.path(), BUSY_TIMEOUT, "wal").unwrap();
+            build_pool(self.path(), 2, BUSY_TIMEOUT, "wal").unwrap()
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.path(), suffix));
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{TempDb, BUSY_TIMEOUT};
+
+    fn names(conn: &Connection, sql: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_>>().unwrap()
+    }
+
+    /// Checks every table, column and index `initialize_database` is meant to create.
+    fn assert_schema(path: &str) {
+        let conn = Connection::open(path).unwrap();
+        let tables = names(&conn, "SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name");
+        assert_eq!(tables, ["node_history", "nodes", "snapshot_nodes", "snapshots"]);
+
+        let columns = names(&conn, "SELECT name FROM pragma_table_info('nodes') ORDER BY cid");
+        assert_eq!(
+            columns,
+            ["public_key", "alias", "capacity", "first_seen", "channels", "last_seen", "city", "country", "country_code", "updated_at"]
+        );
+
+        let indexes = names(&conn, "SELECT name FROM sqlite_master WHERE type = 'index' AND name LIKE 'idx_%' ORDER BY name");
+        let mut expected: Vec<&str> = NODE_INDEXES.iter().map(|(name, _)| *name).chain(["idx_history_node"]).collect();
+        expected.sort();
+        assert_eq!(indexes, expected);
+    }
+
+    #[test]
+    fn initialize_twice() {
+        let db = TempDb::new("twice");
+        initialize_database(db.path(), BUSY_TIMEOUT, "wal").unwrap();
+        Connection::open(db.path())
+            .unwrap()
+            .execute("INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES ('k', 'a', 1, 2)", [])
+            .unwrap();
+
+        initialize_database(db.path(), BUSY_TIMEOUT, "wal").unwrap();
+        assert_schema(db.path());
+        let count: i64 = Connection::open(db.path()).unwrap().query_row("SELECT COUNT(*) FROM nodes", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1, "a second run must keep the data");
+    }
+
+    #[test]
+    fn initialize_from_two_threads() {
+        let db = TempDb::new("threads");
+        let path = db.path().to_string();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || initialize_database(&path, BUSY_TIMEOUT, "wal"))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        assert_schema(db.path());
+    }
+
+    #[test]
+    fn initialize_migrates_old_schema() {
+        let db = TempDb::new("migrate");
+        Connection::open(db.path())
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE nodes (public_key TEXT PRIMARY KEY, alias TEXT NOT NULL, capacity INTEGER NOT NULL, first_seen TEXT NOT NULL);
+                 INSERT INTO nodes VALUES ('a', 'A', 1, '2020-09-13T12:26:40Z'), ('b', 'B', 2, 'not a date');",
+            )
+            .unwrap();
+
+        initialize_database(db.path(), BUSY_TIMEOUT, "wal").unwrap();
+        assert_schema(db.path());
+        let conn = Connection::open(db.path()).unwrap();
+        let first_seen: Vec<i64> = conn
+            .prepare("SELECT first_seen FROM nodes ORDER BY public_key")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(first_seen, [1_600_000_000, 0]);
+    }
+}
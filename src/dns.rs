@@ -0,0 +1,42 @@
+use log::debug;
+use moka::future::Cache;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// A small DNS cache for the worker's HTTP client.
+// The worker hits the same host over and over, so there's no need to ask
+// the resolver every time a connection is opened.
+
+/// Resolves host names with the system resolver and remembers the answer for `ttl`.
+pub struct CachingResolver {
+    cache: Cache<String, Arc<Vec<SocketAddr>>>,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration) -> Self {
+        CachingResolver {
+            cache: Cache::builder().time_to_live(ttl).max_capacity(256).build(),
+        }
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            if let Some(addrs) = cache.get(&host).await {
+                debug!("[DNS] Cache hit for {}", host);
+                return Ok(Box::new(addrs.as_ref().clone().into_iter()) as Addrs);
+            }
+
+            // Port 0 is replaced with the URL's port by reqwest.
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            debug!("[DNS] Resolved {} to {} addresses", host, addrs.len());
+            cache.insert(host, Arc::new(addrs.clone())).await;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
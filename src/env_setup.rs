@@ -1,26 +1,117 @@
+use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
+use crate::worker;
 
-// This module handles the creation of a default .env file.
+// This module handles the creation of a default .env file,
+// or a default config.toml when `CONFIG_PATH` points at one.
 
-/// Creates a default .env file if one doesn't already exist.
-/// This helps a new developer get started quickly without any manual setup.
-pub fn setup_env() -> std::io::Result<()> {
-    let env_path = Path::new(".env");
-    if !env_path.exists() {
-        let mut file = File::create(env_path)?;
-        let content = r#"
+/// The default settings, in .env form. The default config.toml is made from these too.
+/// `{API_URL}` is filled in by `default_env`.
+const DEFAULT_ENV: &str = r#"
 DATABASE_PATH="nodes.db"
-API_URL="https://mempool.space/api/v1/lightning/nodes/rankings/connectivity"
+DB_INIT_RETRIES=5
+DB_POOL_SIZE=8
+DB_BUSY_TIMEOUT_MS=5000
+DB_JOURNAL_MODE=wal
+API_URL="{API_URL}"
+API_CAPACITY_UNIT=sats
+API_FIELD_CASE=camel
 FETCH_INTERVAL_SECONDS=1
 FETCH_TIMEOUT_SECONDS=30
+MAX_RESPONSE_BYTES=52428800
+FETCH_MAX_ATTEMPTS=3
+FETCH_BASE_BACKOFF_SECONDS=1
+FETCH_MAX_BACKOFF_SECONDS=60
+DNS_CACHE_TTL_SECONDS=0
+SEED_FROM_BUNDLE=false
+PRUNE_STALE_NODES=false
+STALE_NODE_TTL_SECONDS=604800
+WEBHOOK_URL=
+ALERT_THRESHOLDS_PATH=
+FETCH_CRON=
 SERVER_PORT=8080
+ENABLE_COMPRESSION=true
+CORS_ALLOWED_ORIGINS=
+RATE_LIMIT_PER_MINUTE=0
+RATE_LIMIT_BEHIND_PROXY=false
+REQUEST_TIMEOUT_SECONDS=30
+ADMIN_TOKEN=
+READ_BUSY_TIMEOUT_MS=500
+DEFAULT_PAGE_SIZE=50
+STALE_FLAG_AFTER_SECONDS=86400
+SPARKLINE_POINTS=30
+FORECAST_HORIZON_DAYS=30
+DATA_QUALITY_INTERVAL_SECONDS=60
+PUSHGATEWAY_URL=
+PUSH_INTERVAL_SECONDS=15
+STATS_AVG_PRECISION=8
 CACHE_TTL_SECONDS=10
+CACHE_TTL_NODES=10
+CACHE_TTL_ENTITIES=60
+CACHE_TTL_STATS=10
+CACHE_TTL_COUNT=10
+CACHE_TTL_COUNTRIES=60
+CACHE_TTL_RANKED=60
+CACHE_PER_CLIENT=false
+ALLOW_CACHE_BYPASS=false
+PUBKEY_PREFIX_MIN_LENGTH=6
+FIRST_SEEN_ZERO=unknown
+DISPLAY_TIMEZONE=UTC
+CAPACITY_GROUPING=false
+CAPACITY_TIER_WHALE=1000000000
+CAPACITY_TIER_LARGE=100000000
+CAPACITY_TIER_MEDIUM=10000000
+ENTITY_SEPARATOR="-"
+ENTITY_PREFIX_DEPTH=1
 RUST_LOG=info
+LOG_FORMAT=text
 "#;
-        file.write_all(content.as_bytes())?;
+
+/// Creates a default .env file if one doesn't already exist.
+/// This helps a new developer get started quickly without any manual setup.
+///
+/// With `CONFIG_PATH` set, the defaults go into that TOML file instead, since
+/// a full .env would override every value in it.
+pub fn setup_env() -> std::io::Result<()> {
+    if let Some(config_path) = env::var("CONFIG_PATH").ok().filter(|p| !p.is_empty()) {
+        let config_path = Path::new(&config_path);
+        if !config_path.exists() {
+            File::create(config_path)?.write_all(default_toml().as_bytes())?;
+            println!("[Env] Created {} with default settings.", config_path.display());
+        }
+        return Ok(());
+    }
+    let env_path = Path::new(".env");
+    if !env_path.exists() {
+        let mut file = File::create(env_path)?;
+        file.write_all(default_env().as_bytes())?;
         println!("[Env] Created .env file with default settings.");
     }
     Ok(())
-} 
\ No newline at end of file
+}
+
+/// `DEFAULT_ENV` with the placeholders filled in.
+fn default_env() -> String {
+    DEFAULT_ENV.replace("{API_URL}", worker::DEFAULT_API_URL)
+}
+
+/// The default .env as TOML: `SERVER_PORT=8080` becomes `server_port = 8080`.
+/// Numbers and booleans stay bare, everything else becomes a string.
+fn default_toml() -> String {
+    let mut toml = String::new();
+    for line in default_env().lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim_matches('"');
+        let bare = value.parse::<i64>().is_ok() || value == "true" || value == "false";
+        if bare {
+            toml.push_str(&format!("{} = {}\n", key.to_lowercase(), value));
+        } else {
+            toml.push_str(&format!("{} = {:?}\n", key.to_lowercase(), value));
+        }
+    }
+    toml
+}
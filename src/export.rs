@@ -0,0 +1,136 @@
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+
+// Compression for the streamed /nodes export (`?compress=gzip|zstd`). Unlike
+// the Compress middleware, this doesn't depend on the client's Accept-Encoding,
+// so a plain `curl -o` still gets a small file.
+
+/// How a streamed export is compressed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Parses the `compress` query value. `None` means no compression.
+    pub fn parse(value: Option<&str>) -> Result<Option<Self>, String> {
+        match value {
+            None => Ok(None),
+            Some("gzip") => Ok(Some(Compression::Gzip)),
+            Some("zstd") => Ok(Some(Compression::Zstd)),
+            Some(other) => Err(format!("Invalid compress '{}'. Allowed: gzip, zstd", other)),
+        }
+    }
+
+    /// The `Content-Encoding` header value.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// What goes after the format's extension in the download's filename.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Compresses a body chunk by chunk, so only the encoder's window is held in
+/// memory rather than the whole export.
+pub enum ChunkEncoder {
+    Identity,
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl ChunkEncoder {
+    pub fn new(compression: Option<Compression>) -> io::Result<Self> {
+        Ok(match compression {
+            None => ChunkEncoder::Identity,
+            Some(Compression::Gzip) => ChunkEncoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+            Some(Compression::Zstd) => ChunkEncoder::Zstd(zstd::Encoder::new(Vec::new(), zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        })
+    }
+
+    /// Feeds `data` in and gives back whatever output is ready. That can be
+    /// empty while the encoder is still filling its window.
+    pub fn encode(&mut self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            ChunkEncoder::Identity => Ok(data),
+            // Both encoders only ever append to their inner Vec, so it can be emptied in between.
+            ChunkEncoder::Gzip(encoder) => {
+                encoder.write_all(&data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            ChunkEncoder::Zstd(encoder) => {
+                encoder.write_all(&data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// Feeds in the last of the data and gives back the rest of the output,
+    /// trailer included.
+    pub fn finish(self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            ChunkEncoder::Identity => Ok(data),
+            ChunkEncoder::Gzip(mut encoder) => {
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            ChunkEncoder::Zstd(mut encoder) => {
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Runs `chunks` through an encoder the way the stream does.
+    fn encode_all(compression: Option<Compression>, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = ChunkEncoder::new(compression).unwrap();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(encoder.encode(chunk.to_vec()).unwrap());
+        }
+        out.extend(encoder.finish(b"tail".to_vec()).unwrap());
+        out
+    }
+
+    #[test]
+    fn parse_compress() {
+        assert_eq!(Compression::parse(None), Ok(None));
+        assert_eq!(Compression::parse(Some("gzip")), Ok(Some(Compression::Gzip)));
+        assert_eq!(Compression::parse(Some("zstd")), Ok(Some(Compression::Zstd)));
+        assert!(Compression::parse(Some("brotli")).is_err());
+        assert!(Compression::parse(Some("")).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let chunks: &[&[u8]] = &[b"[{\"alias\":\"a\"}", b",", &[b'x'; 100_000], b"]"];
+        let expected: Vec<u8> = chunks.concat().into_iter().chain(*b"tail").collect();
+
+        assert_eq!(encode_all(None, chunks), expected);
+
+        let gzip = encode_all(Some(Compression::Gzip), chunks);
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(gzip.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, expected);
+        assert!(gzip.len() < expected.len() / 10, "gzip should shrink this a lot");
+
+        let zstd = encode_all(Some(Compression::Zstd), chunks);
+        assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), expected);
+        assert!(zstd.len() < expected.len() / 10, "zstd should shrink this a lot");
+    }
+}
@@ -1,25 +1,563 @@
 use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{self, ConfigError};
+use serde_json::Value;
+use std::sync::OnceLock;
 
 // A couple of helper functions to format data for the API response.
 
 const SATS_PER_BTC: f64 = 100_000_000.0;
+const SATS_PER_MBTC: f64 = 100_000.0;
 
-/// Converts capacity from sats (i64) to a formatted BTC string.
-pub fn format_capacity(sats: i64) -> String {
-    let btc = sats as f64 / SATS_PER_BTC;
-    format!("{:.8}", btc)
+/// The unit capacities are shown in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DisplayUnit {
+    #[default]
+    Btc,
+    MilliBtc,
+    Sats,
 }
 
-/// Converts a Unix timestamp (i64) into a readable date string (RFC3339 format).
+impl DisplayUnit {
+    /// Parses the `unit` query value. No value means BTC.
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("btc") => Ok(DisplayUnit::Btc),
+            Some("mbtc") => Ok(DisplayUnit::MilliBtc),
+            Some("sats") => Ok(DisplayUnit::Sats),
+            Some(other) => Err(format!("Invalid unit '{}'. Allowed: sats, btc, mbtc", other)),
+        }
+    }
+
+    /// Short name used when building cache keys.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisplayUnit::Btc => "btc",
+            DisplayUnit::MilliBtc => "mbtc",
+            DisplayUnit::Sats => "sats",
+        }
+    }
+}
+
+/// Settings that change how every value is rendered, read once at startup.
+#[derive(Clone, Copy, Debug)]
+pub struct FormatSettings {
+    /// `CAPACITY_GROUPING=true` adds thousands separators to BTC and mBTC amounts
+    /// too (`1,234.56789000`). Off by default, since clients may parse those as numbers.
+    pub group_decimals: bool,
+    /// `FIRST_SEEN_ZERO`: the migration stores `first_seen = 0` when it can't
+    /// parse an old date. By default (`unknown`) we show that as "unknown"
+    /// instead of a fake January 1970 date; `epoch` gets the old behaviour back.
+    pub first_seen_zero_is_unknown: bool,
+    /// `DISPLAY_TIMEZONE`: the zone dates are shown in, as an IANA name like
+    /// `America/Sao_Paulo`. UTC when unset.
+    pub timezone: Tz,
+}
+
+impl Default for FormatSettings {
+    fn default() -> Self {
+        FormatSettings { group_decimals: false, first_seen_zero_is_unknown: true, timezone: Tz::UTC }
+    }
+}
+
+impl FormatSettings {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let timezone = match config::raw("DISPLAY_TIMEZONE") {
+            None => Tz::UTC,
+            Some(name) => name
+                .parse()
+                .map_err(|_| ConfigError::new("DISPLAY_TIMEZONE", format!("'{}' isn't a known time zone", name)))?,
+        };
+        Ok(FormatSettings {
+            group_decimals: config::flag("CAPACITY_GROUPING", false)?,
+            first_seen_zero_is_unknown: first_seen_zero_is_unknown(config::raw("FIRST_SEEN_ZERO").as_deref())?,
+            timezone,
+        })
+    }
+}
+
+static SETTINGS: OnceLock<FormatSettings> = OnceLock::new();
+
+/// Installs the settings the formatters use. Called once at startup, before
+/// anything is rendered; later calls are ignored. Until then (e.g. in tests)
+/// the defaults apply.
+pub fn configure(settings: FormatSettings) {
+    let _ = SETTINGS.set(settings);
+}
+
+fn settings() -> &'static FormatSettings {
+    SETTINGS.get_or_init(FormatSettings::default)
+}
+
+/// Formats a capacity in sats for display: BTC with 8 decimals, mBTC with 5,
+/// or whole sats with thousands separators (`1,234,567`).
+///
+/// Takes anything up to an i128, so totals that overflow an i64 can be shown too.
+pub fn format_capacity(sats: impl Into<i128>, unit: DisplayUnit) -> String {
+    format_capacity_grouped(sats.into(), unit, settings().group_decimals)
+}
+
+/// `format_capacity`, with the `CAPACITY_GROUPING` setting passed in.
+fn format_capacity_grouped(sats: i128, unit: DisplayUnit, group_decimals: bool) -> String {
+    let decimal = match unit {
+        DisplayUnit::Btc => format!("{:.8}", sats as f64 / SATS_PER_BTC),
+        DisplayUnit::MilliBtc => format!("{:.5}", sats as f64 / SATS_PER_MBTC),
+        DisplayUnit::Sats => return group_thousands(&sats.to_string()),
+    };
+    if !group_decimals {
+        return decimal;
+    }
+    // Only the integer part gets grouped, the decimals stay as they are.
+    match decimal.split_once('.') {
+        Some((whole, fraction)) => format!("{}.{}", group_thousands(whole), fraction),
+        None => group_thousands(&decimal),
+    }
+}
+
+/// Puts a comma between every three digits of an integer string, e.g.
+/// `-1234567` -> `-1,234,567`.
+fn group_thousands(digits: &str) -> String {
+    let (sign, digits) = digits.split_at(if digits.starts_with('-') { 1 } else { 0 });
+    let mut out = String::with_capacity(sign.len() + digits.len() + digits.len() / 3);
+    out.push_str(sign);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+
+/// Converts a Unix timestamp (i64) into a readable date string (RFC3339 format),
+/// in the `DISPLAY_TIMEZONE` with its offset (`Z` for UTC).
 pub fn format_timestamp(ts: i64) -> String {
     // Create a `DateTime<Utc>` object from the timestamp.
     let datetime = Utc.timestamp_opt(ts, 0).single();
 
     // Format it into a standard date string.
     if let Some(dt) = datetime {
-        dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        dt.with_timezone(&settings().timezone).to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
     } else {
         // Fallback for invalid timestamps.
         "Invalid Timestamp".to_string()
     }
-} 
\ No newline at end of file
+}
+
+/// How `first_seen` should be rendered in the API response.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeFormat {
+    Rfc3339,
+    Relative,
+    /// Unix seconds, as a JSON number.
+    Unix,
+}
+
+impl TimeFormat {
+    /// Parses the `date_format` query value. No value means RFC3339.
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("rfc3339") => Ok(TimeFormat::Rfc3339),
+            Some("relative") => Ok(TimeFormat::Relative),
+            Some("unix") => Ok(TimeFormat::Unix),
+            Some(other) => Err(format!("Invalid date_format '{}'. Allowed: rfc3339, unix, relative", other)),
+        }
+    }
+
+    /// Short name used when building cache keys.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeFormat::Rfc3339 => "rfc3339",
+            TimeFormat::Relative => "relative",
+            TimeFormat::Unix => "unix",
+        }
+    }
+}
+
+/// Reads a `FIRST_SEEN_ZERO` value: `epoch` keeps the 1970 date, `unknown`
+/// (the default) hides it. Case doesn't matter.
+fn first_seen_zero_is_unknown(value: Option<&str>) -> Result<bool, ConfigError> {
+    match value {
+        None => Ok(true),
+        Some(v) if v.eq_ignore_ascii_case("unknown") => Ok(true),
+        Some(v) if v.eq_ignore_ascii_case("epoch") => Ok(false),
+        Some(other) => Err(ConfigError::new("FIRST_SEEN_ZERO", format!("must be 'unknown' or 'epoch', got '{}'", other))),
+    }
+}
+
+/// Formats a timestamp using the chosen `TimeFormat`.
+/// `clock` is only consulted for relative times.
+///
+/// Unix times are passed straight through as a number (0 included), everything
+/// else comes out as a string.
+pub fn format_time(ts: i64, format: TimeFormat, clock: &dyn Clock) -> Value {
+    let text = match format {
+        TimeFormat::Unix => return Value::from(ts),
+        _ if ts == 0 && settings().first_seen_zero_is_unknown => "unknown".to_string(),
+        TimeFormat::Rfc3339 => format_timestamp(ts),
+        TimeFormat::Relative => format_relative_time(ts, clock.now().timestamp()),
+    };
+    Value::String(text)
+}
+
+/// Turns a Unix timestamp into a human string like "3 years ago", relative to `now`.
+///
+/// Anything under a minute away (in either direction) is "just now".
+/// Timestamps in the future come out as "in 2 days".
+pub fn format_relative_time(ts: i64, now: i64) -> String {
+    const MINUTE: i64 = 60;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+    const MONTH: i64 = 30 * DAY;
+    const YEAR: i64 = 365 * DAY;
+
+    let diff = now.saturating_sub(ts);
+    let secs = diff.saturating_abs();
+    if secs < MINUTE {
+        return "just now".to_string();
+    }
+
+    // Pick the biggest unit that fits at least once.
+    let (value, unit) = if secs >= YEAR {
+        (secs / YEAR, "year")
+    } else if secs >= MONTH {
+        (secs / MONTH, "month")
+    } else if secs >= DAY {
+        (secs / DAY, "day")
+    } else if secs >= HOUR {
+        (secs / HOUR, "hour")
+    } else {
+        (secs / MINUTE, "minute")
+    };
+
+    let plural = if value == 1 { "" } else { "s" };
+    if diff > 0 {
+        format!("{} {}{} ago", value, unit, plural)
+    } else {
+        format!("in {} {}{}", value, unit, plural)
+    }
+}
+
+/// Sats thresholds for the `capacity_tier` labels. A node gets the highest
+/// tier whose threshold it reaches (thresholds are inclusive).
+#[derive(Clone, Copy, Debug)]
+pub struct CapacityTiers {
+    pub whale: i64,
+    pub large: i64,
+    pub medium: i64,
+}
+
+impl CapacityTiers {
+    /// Reads `CAPACITY_TIER_WHALE`, `CAPACITY_TIER_LARGE` and `CAPACITY_TIER_MEDIUM`
+    /// (in sats). Defaults are 10 BTC, 1 BTC and 0.1 BTC.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Ok(CapacityTiers {
+            whale: config::parse("CAPACITY_TIER_WHALE", 1_000_000_000)?,
+            large: config::parse("CAPACITY_TIER_LARGE", 100_000_000)?,
+            medium: config::parse("CAPACITY_TIER_MEDIUM", 10_000_000)?,
+        })
+    }
+
+    /// Returns "whale", "large", "medium" or "small" for a capacity in sats.
+    pub fn tier_for(&self, sats: i64) -> &'static str {
+        if sats >= self.whale {
+            "whale"
+        } else if sats >= self.large {
+            "large"
+        } else if sats >= self.medium {
+            "medium"
+        } else {
+            "small"
+        }
+    }
+}
+
+/// Everything that changes how a node is rendered for a single request.
+#[derive(Clone, Copy)]
+pub struct NodeFormat {
+    pub time_format: TimeFormat,
+    pub unit: DisplayUnit,
+    /// Set when the client asked for `capacity_tier`.
+    pub tiers: Option<CapacityTiers>,
+    /// Used for relative times.
+    pub clock: &'static dyn Clock,
+}
+
+impl Default for NodeFormat {
+    fn default() -> Self {
+        NodeFormat { time_format: TimeFormat::Rfc3339, unit: DisplayUnit::Btc, tiers: None, clock: &SystemClock }
+    }
+}
+
+impl NodeFormat {
+    /// The part of the cache key that identifies this rendering. The tier
+    /// thresholds and the clock are the same for every request, so only
+    /// whether tiers are shown matters.
+    pub fn cache_key(&self) -> String {
+        format!("{}:{}:tier={}", self.time_format.as_str(), self.unit.as_str(), self.tiers.is_some())
+    }
+}
+
+/// Which casing to use for JSON keys in responses.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyCase {
+    Snake,
+    Camel,
+}
+
+impl KeyCase {
+    /// Parses the `case` query value. No value means snake_case.
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("snake") => Ok(KeyCase::Snake),
+            Some("camel") => Ok(KeyCase::Camel),
+            Some(other) => Err(format!("Invalid case '{}'. Allowed: snake, camel", other)),
+        }
+    }
+}
+
+/// What the body of a node listing is encoded as.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BodyFormat {
+    Json,
+    Csv,
+}
+
+impl BodyFormat {
+    /// Parses the `format` query value. Without one, an `Accept` header asking
+    /// for `text/csv` picks CSV. Everything else gets JSON.
+    pub fn parse(value: Option<&str>, accept: Option<&str>) -> Result<Self, String> {
+        match value {
+            Some("json") => Ok(BodyFormat::Json),
+            Some("csv") => Ok(BodyFormat::Csv),
+            Some(other) => Err(format!("Invalid format '{}'. Allowed: json, csv", other)),
+            None if accept.is_some_and(|a| a.contains("text/csv")) => Ok(BodyFormat::Csv),
+            None => Ok(BodyFormat::Json),
+        }
+    }
+}
+
+/// `first_seen` -> `firstSeen`.
+fn snake_to_camel(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper_next = false;
+    for c in key.chars() {
+        if c == '_' {
+            upper_next = true;
+        } else if upper_next {
+            out.push(c.to_ascii_uppercase());
+            upper_next = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// The `NodeResponse` fields a client can pick with `?fields=`.
+pub const NODE_FIELDS: &[&str] = &[
+    "public_key",
+    "alias",
+    "capacity",
+    "first_seen",
+    "channels",
+    "country",
+    "country_code",
+    "rank",
+    "capacity_tier",
+];
+
+/// Parses `?fields=public_key,alias`. Names can be snake_case or camelCase.
+/// `None` means every field.
+pub fn parse_fields(value: Option<&str>) -> Result<Option<Vec<&'static str>>, String> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let mut fields = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match NODE_FIELDS.iter().find(|field| **field == name || snake_to_camel(field) == name) {
+            Some(field) => fields.push(*field),
+            None => return Err(format!("Unknown field '{}'. Valid fields: {}", name, NODE_FIELDS.join(", "))),
+        }
+    }
+    if fields.is_empty() {
+        return Err(format!("fields can't be empty. Valid fields: {}", NODE_FIELDS.join(", ")));
+    }
+    Ok(Some(fields))
+}
+
+/// Drops every key not in `fields` from an object, or from each object in an array.
+/// Expects snake_case keys, so it runs before `camel_case_keys`.
+pub fn select_fields(value: Value, fields: &[&str]) -> Value {
+    match value {
+        Value::Object(mut map) => {
+            map.retain(|key, _| fields.contains(&key.as_str()));
+            Value::Object(map)
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(|item| select_fields(item, fields)).collect()),
+        other => other,
+    }
+}
+
+/// Rewrites every object key in a JSON value to camelCase, all the way down.
+pub fn camel_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (snake_to_camel(&k), camel_case_keys(v)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(camel_case_keys).collect()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    /// 2024-01-01T00:00:00Z, the "now" every relative time is measured from.
+    fn fixed_clock() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    fn relative(ts: i64) -> Value {
+        format_time(ts, TimeFormat::Relative, &fixed_clock())
+    }
+
+    #[test]
+    fn relative_time_seconds() {
+        let now = fixed_clock().timestamp();
+        assert_eq!(relative(now), "just now");
+        assert_eq!(relative(now - 59), "just now");
+        assert_eq!(relative(now + 59), "just now");
+    }
+
+    #[test]
+    fn relative_time_minutes() {
+        let now = fixed_clock().timestamp();
+        assert_eq!(relative(now - 60), "1 minute ago");
+        assert_eq!(relative(now - 59 * 60), "59 minutes ago");
+    }
+
+    #[test]
+    fn relative_time_hours() {
+        let now = fixed_clock().timestamp();
+        assert_eq!(relative(now - 3600), "1 hour ago");
+        assert_eq!(relative(now - 23 * 3600 - 59), "23 hours ago");
+    }
+
+    #[test]
+    fn relative_time_days() {
+        let now = fixed_clock().timestamp();
+        assert_eq!(relative(now - 86_400), "1 day ago");
+        assert_eq!(relative(now - 29 * 86_400), "29 days ago");
+        assert_eq!(relative(now - 30 * 86_400), "1 month ago");
+        assert_eq!(relative(now - 2 * 365 * 86_400), "2 years ago");
+    }
+
+    #[test]
+    fn relative_time_future() {
+        let now = fixed_clock().timestamp();
+        assert_eq!(relative(now + 60), "in 1 minute");
+        assert_eq!(relative(now + 2 * 86_400), "in 2 days");
+    }
+
+    #[test]
+    fn relative_time_follows_the_clock() {
+        let ts = fixed_clock().timestamp();
+        let later = fixed_clock() + chrono::Duration::hours(5);
+        assert_eq!(format_time(ts, TimeFormat::Relative, &later), "5 hours ago");
+    }
+
+    #[test]
+    fn time_format_parse() {
+        assert_eq!(TimeFormat::parse(None), Ok(TimeFormat::Rfc3339));
+        assert_eq!(TimeFormat::parse(Some("rfc3339")), Ok(TimeFormat::Rfc3339));
+        assert_eq!(TimeFormat::parse(Some("relative")), Ok(TimeFormat::Relative));
+        assert_eq!(TimeFormat::parse(Some("unix")), Ok(TimeFormat::Unix));
+        assert!(TimeFormat::parse(Some("iso")).is_err());
+    }
+
+    #[test]
+    fn time_format_unix_and_rfc3339() {
+        let ts = fixed_clock().timestamp();
+        assert_eq!(format_time(ts, TimeFormat::Unix, &fixed_clock()), Value::from(ts));
+        assert_eq!(format_time(ts, TimeFormat::Rfc3339, &fixed_clock()), "2024-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn first_seen_zero_is_unknown_by_default() {
+        assert_eq!(format_time(0, TimeFormat::Rfc3339, &fixed_clock()), "unknown");
+        assert_eq!(format_time(0, TimeFormat::Relative, &fixed_clock()), "unknown");
+        // Unix times stay numbers, so the sentinel comes through as 0.
+        assert_eq!(format_time(0, TimeFormat::Unix, &fixed_clock()), Value::from(0));
+    }
+
+    #[test]
+    fn first_seen_real_value_is_formatted() {
+        assert_eq!(format_time(1, TimeFormat::Rfc3339, &fixed_clock()), "1970-01-01T00:00:01Z");
+        assert_eq!(format_time(1_600_000_000, TimeFormat::Rfc3339, &fixed_clock()), "2020-09-13T12:26:40Z");
+    }
+
+    #[test]
+    fn first_seen_zero_setting() {
+        assert!(first_seen_zero_is_unknown(None).unwrap());
+        assert!(first_seen_zero_is_unknown(Some("unknown")).unwrap());
+        assert!(!first_seen_zero_is_unknown(Some("epoch")).unwrap());
+        assert!(!first_seen_zero_is_unknown(Some("EPOCH")).unwrap());
+        assert!(first_seen_zero_is_unknown(Some("1970")).is_err());
+    }
+
+    #[test]
+    fn capacity_tiers_at_boundaries() {
+        let tiers = CapacityTiers { whale: 1_000, large: 100, medium: 10 };
+        assert_eq!(tiers.tier_for(0), "small");
+        assert_eq!(tiers.tier_for(9), "small");
+        assert_eq!(tiers.tier_for(10), "medium");
+        assert_eq!(tiers.tier_for(99), "medium");
+        assert_eq!(tiers.tier_for(100), "large");
+        assert_eq!(tiers.tier_for(999), "large");
+        assert_eq!(tiers.tier_for(1_000), "whale");
+        assert_eq!(tiers.tier_for(i64::MAX), "whale");
+    }
+
+    #[test]
+    fn group_thousands_digits() {
+        assert_eq!(group_thousands("0"), "0");
+        assert_eq!(group_thousands("7"), "7");
+        assert_eq!(group_thousands("999"), "999");
+        assert_eq!(group_thousands("1000"), "1,000");
+        assert_eq!(group_thousands("1000000000"), "1,000,000,000");
+        assert_eq!(group_thousands("-1234567"), "-1,234,567");
+        assert_eq!(group_thousands("-123"), "-123");
+    }
+
+    #[test]
+    fn format_capacity_in_each_unit() {
+        assert_eq!(format_capacity_grouped(0, DisplayUnit::Sats, false), "0");
+        assert_eq!(format_capacity_grouped(7, DisplayUnit::Sats, false), "7");
+        assert_eq!(format_capacity_grouped(1_000_000_000, DisplayUnit::Sats, false), "1,000,000,000");
+
+        assert_eq!(format_capacity_grouped(0, DisplayUnit::Btc, false), "0.00000000");
+        assert_eq!(format_capacity_grouped(7, DisplayUnit::Btc, false), "0.00000007");
+        assert_eq!(format_capacity_grouped(1_000_000_000, DisplayUnit::Btc, false), "10.00000000");
+
+        assert_eq!(format_capacity_grouped(0, DisplayUnit::MilliBtc, false), "0.00000");
+        assert_eq!(format_capacity_grouped(7, DisplayUnit::MilliBtc, false), "0.00007");
+        assert_eq!(format_capacity_grouped(1_000_000_000, DisplayUnit::MilliBtc, false), "10000.00000");
+    }
+
+    #[test]
+    fn format_capacity_with_grouping() {
+        // Only the whole part gets separators.
+        assert_eq!(format_capacity_grouped(1_000_000_000, DisplayUnit::MilliBtc, true), "10,000.00000");
+        assert_eq!(format_capacity_grouped(123_456_789_000, DisplayUnit::Btc, true), "1,234.56789000");
+        assert_eq!(format_capacity_grouped(7, DisplayUnit::Btc, true), "0.00000007");
+        // Sats are grouped either way.
+        assert_eq!(format_capacity_grouped(1_000_000_000, DisplayUnit::Sats, true), "1,000,000,000");
+    }
+}
@@ -0,0 +1,215 @@
+use rusqlite::types::Value;
+
+// Builds the SQL for the /nodes listing from the query options.
+// Everything a client sends ends up as a bound parameter, never as SQL text.
+
+/// Columns /nodes can be sorted by. Mapped to fixed SQL names, so the raw
+/// query value never ends up in the statement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortColumn {
+    #[default]
+    Capacity,
+    Alias,
+    FirstSeen,
+    Channels,
+}
+
+impl SortColumn {
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("capacity") => Ok(SortColumn::Capacity),
+            Some("alias") => Ok(SortColumn::Alias),
+            Some("first_seen") => Ok(SortColumn::FirstSeen),
+            Some("channels") => Ok(SortColumn::Channels),
+            Some(other) => Err(format!("Invalid sort_by '{}'. Use 'capacity', 'alias', 'first_seen' or 'channels'.", other)),
+        }
+    }
+
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            SortColumn::Capacity => "capacity",
+            SortColumn::Alias => "alias",
+            SortColumn::FirstSeen => "first_seen",
+            SortColumn::Channels => "channels",
+        }
+    }
+
+    /// Biggest, newest and best connected first; names A to Z.
+    pub fn default_order(self) -> SortOrder {
+        match self {
+            SortColumn::Capacity | SortColumn::FirstSeen | SortColumn::Channels => SortOrder::Desc,
+            SortColumn::Alias => SortOrder::Asc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    /// Parses `?order=`. `None` means the column's default order.
+    pub fn parse(value: Option<&str>) -> Result<Option<Self>, String> {
+        match value {
+            None => Ok(None),
+            Some("asc") => Ok(Some(SortOrder::Asc)),
+            Some("desc") => Ok(Some(SortOrder::Desc)),
+            Some(other) => Err(format!("Invalid order '{}'. Use 'asc' or 'desc'.", other)),
+        }
+    }
+
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Which nodes the /nodes listing should return.
+#[derive(Clone, Debug, Default)]
+pub struct NodeListing {
+    pub include_rank: bool,
+    /// Flag nodes whose `updated_at` is before this unix time as `stale`.
+    /// `None` leaves the flag out.
+    pub stale_before: Option<i64>,
+    /// Only nodes holding at least this fraction (0..=1) of the total network capacity.
+    pub min_share: Option<f64>,
+    /// Capacity band in sats, both ends inclusive. Either end can be left open.
+    pub min_capacity: Option<i64>,
+    pub max_capacity: Option<i64>,
+    /// Page size. `None` returns every matching node.
+    pub limit: Option<i64>,
+    pub offset: i64,
+    pub sort_by: SortColumn,
+    pub order: SortOrder,
+}
+
+impl NodeListing {
+    /// The SQL and its parameters. Selects the columns `read_nodes` expects,
+    /// plus the rank and the stale flag when asked for, in the requested order.
+    ///
+    /// The rank is always by capacity, computed over the whole table with a
+    /// window function in an inner query, so filters and sorting don't change it.
+    /// Ties share a rank; `public_key` keeps the order of equal rows stable.
+    pub fn to_sql(&self) -> (String, Vec<Value>) {
+        let rank_column = if self.include_rank { ", RANK() OVER (ORDER BY capacity DESC) AS rank" } else { "" };
+        let (where_clause, mut params) = self.where_clause();
+        let stale_column = match self.stale_before {
+            Some(cutoff) => {
+                params.push(Value::Integer(cutoff));
+                format!(", updated_at < ?{} AS stale", params.len())
+            }
+            None => String::new(),
+        };
+        let mut sql = format!(
+            "SELECT * FROM (SELECT public_key, alias, capacity, first_seen, channels, country, country_code{}{} FROM nodes) {} ORDER BY {} {}, public_key",
+            rank_column,
+            stale_column,
+            where_clause,
+            self.sort_by.as_sql(),
+            self.order.as_sql()
+        );
+        if self.limit.is_some() || self.offset > 0 {
+            // SQLite only takes OFFSET after a LIMIT, where -1 means none.
+            params.push(Value::Integer(self.limit.unwrap_or(-1)));
+            params.push(Value::Integer(self.offset));
+            sql.push_str(&format!(" LIMIT ?{} OFFSET ?{}", params.len() - 1, params.len()));
+        }
+        (sql, params)
+    }
+
+    /// SQL counting the nodes that match the filters, ignoring paging and sorting.
+    pub fn count_sql(&self) -> (String, Vec<Value>) {
+        let (where_clause, params) = self.where_clause();
+        (format!("SELECT COUNT(*) FROM nodes {}", where_clause), params)
+    }
+
+    /// SQL for the count, average, largest and smallest capacity of the
+    /// matching nodes, ignoring paging and sorting.
+    pub fn aggregate_sql(&self) -> (String, Vec<Value>) {
+        let (where_clause, params) = self.where_clause();
+        (format!("SELECT COUNT(*), AVG(capacity), MAX(capacity), MIN(capacity) FROM nodes {}", where_clause), params)
+    }
+
+    /// SQL selecting the capacity of each matching node, for adding them up
+    /// outside SQLite.
+    pub fn capacities_sql(&self) -> (String, Vec<Value>) {
+        let (where_clause, params) = self.where_clause();
+        (format!("SELECT capacity FROM nodes {}", where_clause), params)
+    }
+
+    /// SQL for the capacity of the `n`th matching node (from 0) in capacity
+    /// order, smallest first. Walks `idx_capacity` instead of sorting.
+    pub fn nth_capacity_sql(&self, n: i64) -> (String, Vec<Value>) {
+        let (where_clause, mut params) = self.where_clause();
+        params.push(Value::Integer(n));
+        (format!("SELECT capacity FROM nodes {} ORDER BY capacity LIMIT 1 OFFSET ?{}", where_clause, params.len()), params)
+    }
+
+    /// SQL for the smallest and largest capacity of the matching nodes. Each in
+    /// its own subquery, so SQLite can read both straight off `idx_capacity`.
+    pub fn capacity_range_sql(&self) -> (String, Vec<Value>) {
+        let (where_clause, params) = self.where_clause();
+        (format!("SELECT (SELECT MIN(capacity) FROM nodes {w}), (SELECT MAX(capacity) FROM nodes {w})", w = where_clause), params)
+    }
+
+    /// SQL for the capacity of the first matching node at or after a rowid,
+    /// which the caller appends to the parameters. Used to sample nodes cheaply.
+    pub fn sample_sql(&self) -> (String, Vec<Value>) {
+        let (where_clause, params) = self.where_clause();
+        let rowid = format!("rowid >= ?{}", params.len() + 1);
+        let where_clause = if where_clause.is_empty() { format!("WHERE {}", rowid) } else { format!("{} AND {}", where_clause, rowid) };
+        (format!("SELECT capacity FROM nodes {} ORDER BY rowid LIMIT 1", where_clause), params)
+    }
+
+    /// The `WHERE` clause for the filters (empty when there are none), with its parameters.
+    fn where_clause(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(share) = self.min_share {
+            // The total is an uncorrelated subquery, so SQLite works it out once
+            // per query. TOTAL() returns a float, which is plenty for a threshold.
+            params.push(Value::Real(share));
+            conditions.push(format!("capacity >= (SELECT TOTAL(capacity) FROM nodes) * ?{}", params.len()));
+        }
+        if let Some(min) = self.min_capacity {
+            params.push(Value::Integer(min));
+            conditions.push(format!("capacity >= ?{}", params.len()));
+        }
+        if let Some(max) = self.max_capacity {
+            params.push(Value::Integer(max));
+            conditions.push(format!("capacity <= ?{}", params.len()));
+        }
+
+        let where_clause = if conditions.is_empty() { String::new() } else { format!("WHERE {}", conditions.join(" AND ")) };
+        (where_clause, params)
+    }
+
+    /// The part of the cache key that identifies this listing.
+    pub fn cache_key(&self) -> String {
+        let limit = self.limit.map(|l| l.to_string()).unwrap_or_default();
+        format!(
+            "rank={}:stale={}:{}:limit={}:offset={}:sort={}:{}",
+            self.include_rank,
+            self.stale_before.map(|c| c.to_string()).unwrap_or_default(),
+            self.filter_key(),
+            limit,
+            self.offset,
+            self.sort_by.as_sql(),
+            self.order.as_sql()
+        )
+    }
+
+    /// The part of the cache key that identifies the filters alone.
+    pub fn filter_key(&self) -> String {
+        let min_share = self.min_share.map(|s| s.to_string()).unwrap_or_default();
+        let min_capacity = self.min_capacity.map(|c| c.to_string()).unwrap_or_default();
+        let max_capacity = self.max_capacity.map(|c| c.to_string()).unwrap_or_default();
+        format!("min_share={}:capacity={}-{}", min_share, min_capacity, max_capacity)
+    }
+}
@@ -0,0 +1,30 @@
+use chrono::{SecondsFormat, Utc};
+use env_logger::Builder;
+use log::warn;
+use std::env;
+use std::io::Write;
+
+// Logger setup. Plain text by default; `LOG_FORMAT=json` switches to one JSON
+// object per line, for log aggregators that want fields instead of text.
+
+/// Starts the logger. `RUST_LOG` picks the levels either way.
+pub fn init() {
+    let format = env::var("LOG_FORMAT").unwrap_or_default();
+    let mut builder = Builder::from_default_env();
+    if format == "json" {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+
+    if !format.is_empty() && format != "json" && format != "text" {
+        warn!("[Main] Unknown LOG_FORMAT '{}', using plain text.", format);
+    }
+}
@@ -1,7 +1,10 @@
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use rusqlite::Connection;
-use std::env;
-use log::{error, info};
+use actix_web::http::header::{EntityTag, ETag, IfNoneMatch, ACCEPT, CONTENT_DISPOSITION, CONTENT_ENCODING};
+use actix_web::{get, middleware, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder};
+use serde::Serialize;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use log::{error, info, warn};
 use dotenvy::dotenv;
 use moka::future::Cache;
 mod db;
@@ -9,63 +12,2259 @@ mod worker;
 mod formatters;
 mod env_setup;
 mod models;
-use models::{NodeResponse, NodeFromDb};
+mod cache;
+mod bloom;
+mod auth;
+mod listing;
+mod clock;
+mod dns;
+mod traffic;
+mod metrics;
+mod cors;
+mod ratelimit;
+mod logging;
+mod config;
+mod timeout;
+mod webhook;
+mod retry;
+mod export;
+mod alerts;
+use models::{is_valid_pubkey, AliasSearchQuery, HistoryEntry, NodeHistory, StatsResponse, StatsQuery, CacheStatsEntry, CacheStatsReport, DiffQuery, EndpointInfo, NodeChange, SnapshotDiff, SnapshotInfo, EntitiesQuery, EntityGroup, ForeignKeyViolation, IntegrityReport, KnownNodesRequest, NodeResponse, NodeFromDb, NodesQuery, NodeCountQuery, NodeCountResponse, NodeChangesQuery, NodeChangesResponse, RefreshQuery, SparklineQuery, TimelineEvent, TimelineQuery, NodeTimeline, ForecastPoint, ForecastQuery, NodeForecast, DepartedNode, DepartedNodesQuery, DepartedNodesResponse, RankedNode, RankedNodesQuery, RankedNodesResponse, RankingWeights, CountryStat, WorkerStatusResponse, PubkeyPrefixQuery};
+use formatters::{BodyFormat, DisplayUnit, KeyCase, NodeFormat, TimeFormat};
+use cache::{CacheStats, LastGoodNodes};
+use db::{DbError, DbPool};
+use listing::{NodeListing, SortColumn, SortOrder};
+use metrics::Metrics;
+use std::sync::Arc;
+use worker::{CycleOutcome, RefreshStart, Refresher, WorkerState, WorkerStatus};
+use config::{Config, MAX_FORECAST_HORIZON_DAYS, MAX_PAGE_SIZE, MAX_SPARKLINE_POINTS};
+use retry::{Failure, RetryPolicy};
+use clock::{Clock, SystemClock};
+use export::{ChunkEncoder, Compression};
+
+/// Every route we serve, listed at GET / so the API is easy to discover.
+/// Keep this in sync when adding endpoints.
+const ENDPOINTS: &[EndpointInfo] = &[
+    EndpointInfo { method: "GET", path: "/", description: "This index of available endpoints" },
+    EndpointInfo { method: "GET", path: "/healthz", description: "Health check: database reachable, worker running, worker has fetched" },
+    EndpointInfo { method: "GET", path: "/worker/status", description: "Worker state and panics, last worker success, last error, failures in a row and fetch time" },
+    EndpointInfo { method: "GET", path: "/metrics", description: "Prometheus metrics" },
+    EndpointInfo { method: "POST", path: "/refresh", description: "Fetch from the upstream now (needs ADMIN_TOKEN if set)" },
+    EndpointInfo { method: "GET", path: "/nodes", description: "All nodes, biggest capacity first" },
+    EndpointInfo { method: "GET", path: "/nodes/top/{n}", description: "The n biggest nodes by capacity (1 to 100)" },
+    EndpointInfo { method: "GET", path: "/nodes/count", description: "How many nodes match the /nodes filters" },
+    EndpointInfo { method: "GET", path: "/nodes/changes?since=", description: "Nodes added or changed since a unix time" },
+    EndpointInfo { method: "GET", path: "/nodes/departed", description: "Nodes missing from the last successful fetch, most recent first" },
+    EndpointInfo { method: "GET", path: "/nodes/ranked?w_capacity=&w_channels=&w_age=", description: "Nodes by a weighted score of normalized capacity, channels and age" },
+    EndpointInfo { method: "GET", path: "/nodes/by-country", description: "Node count and total capacity per country" },
+    EndpointInfo { method: "GET", path: "/nodes/search?q=", description: "Nodes whose alias contains a term" },
+    EndpointInfo { method: "GET", path: "/nodes/search/pubkey?prefix=", description: "Nodes whose public key starts with a hex prefix" },
+    EndpointInfo { method: "GET", path: "/nodes/{public_key}", description: "A single node by its 66-character public key" },
+    EndpointInfo { method: "GET", path: "/nodes/{public_key}/history", description: "A node's capacity over time" },
+    EndpointInfo { method: "GET", path: "/nodes/{public_key}/timeline", description: "When a node appeared, changed capacity and departed" },
+    EndpointInfo { method: "GET", path: "/nodes/{public_key}/sparkline", description: "A node's capacity history as a short array of sats, for mini charts" },
+    EndpointInfo { method: "GET", path: "/nodes/{public_key}/forecast", description: "A straight-line projection of a node's capacity from its history" },
+    EndpointInfo { method: "POST", path: "/nodes/unknown", description: "Nodes missing from a list or bloom filter of pubkeys you already have" },
+    EndpointInfo { method: "GET", path: "/stats", description: "Node count and total/average/max/min capacity (exact=true for an exact average, min_capacity=&max_capacity= for a band)" },
+    EndpointInfo { method: "GET", path: "/entities", description: "Nodes grouped into likely operators by alias prefix" },
+    EndpointInfo { method: "GET", path: "/cache/stats", description: "Cache entries, hit rates and TTLs (needs ADMIN_TOKEN if set)" },
+    EndpointInfo { method: "GET", path: "/admin/integrity", description: "Run SQLite integrity and foreign key checks (admin token required)" },
+    EndpointInfo { method: "GET", path: "/admin/cache/stats", description: "Cache entries, hits, misses, hit rates and TTLs (admin token required)" },
+    EndpointInfo { method: "POST", path: "/admin/snapshot/{name}", description: "Save a named copy of the nodes table (admin token required)" },
+    EndpointInfo { method: "GET", path: "/admin/diff?from=&to=", description: "Nodes added, removed or changed between two snapshots (admin token required)" },
+];
+
+/// How many columns a node query selects, not counting the optional ones.
+const NODE_COLUMNS: usize = 7;
+
+/// Where a node query put the optional `rank` and `stale` columns, if it selected them.
+#[derive(Clone, Copy, Default)]
+struct ExtraColumns {
+    rank: Option<usize>,
+    stale: Option<usize>,
+}
+
+impl ExtraColumns {
+    fn of(stmt: &rusqlite::Statement) -> Self {
+        ExtraColumns { rank: stmt.column_index("rank").ok(), stale: stmt.column_index("stale").ok() }
+    }
+}
+
+/// Reads one row of a node query.
+///
+/// The query must select `public_key, alias, capacity, first_seen, channels, country, country_code`
+/// in that order, then any of the `extra` columns.
+fn node_from_row(row: &rusqlite::Row, extra: ExtraColumns) -> rusqlite::Result<NodeFromDb> {
+    Ok(NodeFromDb {
+        public_key: row.get(0)?,
+        alias: row.get(1)?,
+        capacity: row.get(2)?,
+        first_seen: row.get(3)?,
+        channels: row.get(4)?,
+        country: row.get(5)?,
+        country_code: row.get(6)?,
+        rank: extra.rank.map(|i| row.get(i)).transpose()?,
+        // NULL when `updated_at` is, which only rows that were never stored have.
+        stale: extra.stale.map(|i| row.get::<_, Option<bool>>(i)).transpose()?.map(|stale| stale.unwrap_or(false)),
+    })
+}
+
+/// Formats a node from the database for the API response.
+fn to_response(node_db: NodeFromDb, format: &NodeFormat) -> NodeResponse {
+    NodeResponse {
+        capacity: formatters::format_capacity(node_db.capacity, format.unit),
+        first_seen: formatters::format_time(node_db.first_seen, format.time_format, format.clock),
+        channels: node_db.channels,
+        country: node_db.country,
+        country_code: node_db.country_code,
+        rank: node_db.rank,
+        stale: node_db.stale,
+        capacity_tier: format.tiers.map(|tiers| tiers.tier_for(node_db.capacity).to_string()),
+        public_key: node_db.public_key,
+        alias: node_db.alias,
+    }
+}
+
+/// Runs a node query and formats every row for the API response.
+/// See `node_from_row` for the columns the query must select.
+fn read_nodes<P: rusqlite::Params>(conn: &Connection, sql: &str, params: P, format: &NodeFormat) -> rusqlite::Result<Vec<NodeResponse>> {
+    let mut stmt = conn.prepare(sql)?;
+    let extra = ExtraColumns::of(&stmt);
+
+    let node_iter = stmt.query_map(params, |row| node_from_row(row, extra))?;
+
+    let mut nodes = Vec::new();
+    for node_result in node_iter {
+        nodes.push(to_response(node_result?, format));
+    }
+    Ok(nodes)
+}
+
+/// Appends one CSV record to `buf`, quoting fields that need it.
+fn write_csv_record<const N: usize>(buf: &mut Vec<u8>, fields: [&str; N]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::WriterBuilder::new().buffer_capacity(1024).from_writer(buf);
+    writer.write_record(fields)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Streams the full node listing as a chunked JSON array, or as CSV.
+///
+/// Rows are read from a SQLite cursor on a blocking thread and written out as
+/// they come, so we never hold the whole table in memory. If the client goes
+/// away we stop reading. If the DB fails halfway, we abort the response, since
+/// the status line has already been sent.
+///
+/// With a `compression`, each chunk goes through the encoder on the same
+/// thread, and the response is sent as a download with `Content-Encoding` set.
+fn stream_nodes(
+    pool: DbPool,
+    listing: NodeListing,
+    format: NodeFormat,
+    case: KeyCase,
+    fields: Option<Vec<&'static str>>,
+    body_format: BodyFormat,
+    compression: Option<Compression>,
+) -> HttpResponse {
+    // Flush to the client roughly every 16 KB.
+    const CHUNK_SIZE: usize = 16 * 1024;
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<web::Bytes, std::io::Error>>(8);
+
+    tokio::task::spawn_blocking(move || {
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let conn = pool.get()?;
+            let (sql, params) = listing.to_sql();
+            let mut stmt = conn.prepare(&sql)?;
+            let extra = ExtraColumns::of(&stmt);
+            let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+            let mut encoder = ChunkEncoder::new(compression)?;
+
+            let mut buf = Vec::with_capacity(CHUNK_SIZE);
+            match body_format {
+                BodyFormat::Json => buf.push(b'['),
+                BodyFormat::Csv => write_csv_record(&mut buf, ["public_key", "alias", "capacity", "first_seen"])?,
+            }
+            let mut first = true;
+            while let Some(row) = rows.next()? {
+                let node = to_response(node_from_row(row, extra)?, &format);
+                match (body_format, case) {
+                    (BodyFormat::Csv, _) => {
+                        let first_seen = match &node.first_seen {
+                            serde_json::Value::String(text) => text.clone(),
+                            other => other.to_string(),
+                        };
+                        write_csv_record(&mut buf, [&node.public_key, &node.alias, &node.capacity, &first_seen])?;
+                    }
+                    (BodyFormat::Json, case) => {
+                        if !first {
+                            buf.push(b',');
+                        }
+                        match (case, &fields) {
+                            (KeyCase::Snake, None) => serde_json::to_writer(&mut buf, &node)?,
+                            (case, fields) => {
+                                let mut value = serde_json::to_value(&node)?;
+                                if let Some(fields) = fields {
+                                    value = formatters::select_fields(value, fields);
+                                }
+                                if case == KeyCase::Camel {
+                                    value = formatters::camel_case_keys(value);
+                                }
+                                serde_json::to_writer(&mut buf, &value)?;
+                            }
+                        }
+                    }
+                }
+                first = false;
+                if buf.len() >= CHUNK_SIZE {
+                    let chunk = encoder.encode(std::mem::replace(&mut buf, Vec::with_capacity(CHUNK_SIZE)))?;
+                    if !chunk.is_empty() && tx.blocking_send(Ok(web::Bytes::from(chunk))).is_err() {
+                        return Ok(()); // Client went away.
+                    }
+                }
+            }
+            if body_format == BodyFormat::Json {
+                buf.push(b']');
+            }
+            let _ = tx.blocking_send(Ok(web::Bytes::from(encoder.finish(buf)?)));
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            error!("[API] Failed while streaming /nodes: {}", e);
+            let _ = tx.blocking_send(Err(std::io::Error::other("stream aborted")));
+        }
+    });
+
+    let body = futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) });
+    let mut response = HttpResponse::Ok();
+    match body_format {
+        BodyFormat::Json => response.content_type("application/json"),
+        BodyFormat::Csv => response.content_type("text/csv; charset=utf-8"),
+    };
+    match (body_format, compression) {
+        (BodyFormat::Json, None) => {}
+        (BodyFormat::Csv, None) => {
+            response.insert_header((CONTENT_DISPOSITION, "attachment; filename=\"nodes.csv\""));
+        }
+        (body_format, Some(compression)) => {
+            let extension = if body_format == BodyFormat::Csv { "csv" } else { "json" };
+            // The Compress middleware leaves a body alone once it has a Content-Encoding.
+            response
+                .insert_header((CONTENT_ENCODING, compression.content_encoding()))
+                .insert_header((CONTENT_DISPOSITION, format!("attachment; filename=\"nodes.{}{}\"", extension, compression.extension())));
+        }
+    }
+    response.streaming(body)
+}
+
+/// Sends `body` as JSON, with camelCase keys if the client asked for them.
+fn json_response<T: Serialize>(mut builder: HttpResponseBuilder, body: &T, case: KeyCase) -> HttpResponse {
+    match case {
+        KeyCase::Snake => builder.json(body),
+        KeyCase::Camel => match serde_json::to_value(body) {
+            Ok(value) => builder.json(formatters::camel_case_keys(value)),
+            Err(e) => {
+                error!("Failed to serialize response: {}", e);
+                HttpResponse::InternalServerError().body("Internal server error")
+            }
+        },
+    }
+}
+
+/// Sends a node list as JSON, trimmed to `fields` when the client picked some.
+fn nodes_response(builder: HttpResponseBuilder, nodes: &[NodeResponse], case: KeyCase, fields: Option<&[&str]>) -> HttpResponse {
+    let Some(fields) = fields else {
+        return json_response(builder, &nodes, case);
+    };
+    match serde_json::to_value(nodes) {
+        Ok(value) => json_response(builder, &formatters::select_fields(value, fields), case),
+        Err(e) => {
+            error!("Failed to serialize response: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Checks the /nodes filters that `web::Query` can't check on its own.
+fn check_filters(min_share: Option<f64>, min_capacity: Option<i64>, max_capacity: Option<i64>) -> Result<(), &'static str> {
+    if let Some(share) = min_share
+        && !(0.0..=1.0).contains(&share)
+    {
+        return Err("min_share must be between 0 and 1");
+    }
+    if let (Some(min), Some(max)) = (min_capacity, max_capacity)
+        && min > max
+    {
+        return Err("min_capacity can't be greater than max_capacity");
+    }
+    Ok(())
+}
+
+/// Biggest `n` for /nodes/top/{n}.
+const MAX_TOP_NODES: i64 = 100;
 
 /// Handler for the GET /nodes endpoint.
 ///
-/// It serves node data, trying the cache first. If the cache is empty,
-/// it falls back to querying the database. The database itself is updated
-/// by a background worker, so this function is read-only.
-#[get("/nodes")]
-async fn get_nodes(cache: web::Data<Cache<String, Vec<NodeResponse>>>) -> impl Responder {
-    let db_path = env::var("DATABASE_PATH").unwrap_or("nodes.db".to_string());
-    let cache_key = "nodes".to_string();
+/// It serves node data, trying the cache first. If the cache is empty,
+/// it falls back to querying the database. The database itself is updated
+/// by a background worker, so this function is read-only.
+///
+/// `?date_format=relative` renders `first_seen` as "3 years ago" instead of RFC3339,
+/// and `?date_format=unix` as plain Unix seconds. (`time_format` works too.)
+/// `?unit=sats|btc|mbtc` picks the unit `capacity` is shown in (BTC by default).
+/// `?include_rank=true` adds each node's position by capacity.
+/// `?include_tier=true` adds a `capacity_tier` label (thresholds come from env).
+/// `?include_stale_flag=true` adds `stale`: whether the node's `updated_at` is more
+/// than `STALE_FLAG_AFTER_SECONDS` before the worker's last successful fetch.
+/// `?case=camel` switches the JSON keys to camelCase (`publicKey`, `firstSeen`...).
+/// `?fields=public_key,alias` returns only those fields of each node.
+/// `?stream=true` streams the whole table straight from the DB, skipping the cache.
+/// `?format=csv` (or `Accept: text/csv`) streams it as a CSV download instead of JSON.
+/// `?compress=gzip|zstd` streams it compressed, as a `nodes.json.gz`, `nodes.csv.zst`... download.
+/// `?nocache=true` skips the cache read (the fresh result is still cached), when `ALLOW_CACHE_BYPASS=true`.
+/// `?min_share=0.01` keeps only nodes holding at least 1% of the total network capacity.
+/// `?min_capacity=&max_capacity=` keep only nodes within a capacity band (in sats, inclusive).
+/// `?limit=50&offset=0` pages through the results (default `DEFAULT_PAGE_SIZE`, capped at `MAX_PAGE_SIZE`).
+/// `?limit=0` or `?all=true` returns every matching node in one response, uncapped.
+/// `?sort_by=first_seen&order=asc` changes the order (default: capacity, biggest first).
+///
+/// Responses carry a weak `ETag` that changes when the worker changes the data,
+/// so pollers can send `If-None-Match` and get a 304 instead of the full list.
+/// Streams and `date_format=relative` (whose text changes over time) don't get one.
+///
+/// If the database is locked (e.g. a VACUUM or an index rebuild is running),
+/// we don't wait out the busy timeout. We serve the last good response instead,
+/// with an `X-Maintenance` header so clients know it may be a bit old.
+#[get("/nodes")]
+#[allow(clippy::too_many_arguments)]
+async fn get_nodes(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cache: web::Data<Cache<String, Vec<NodeResponse>>>,
+    stats: web::Data<CacheStats>,
+    last_good: web::Data<LastGoodNodes>,
+    metrics: web::Data<Metrics>,
+    worker_status: web::Data<WorkerStatus>,
+    config: web::Data<Config>,
+    clock: web::Data<&'static dyn Clock>,
+    query: web::Query<NodesQuery>,
+) -> impl Responder {
+    serve_nodes(req, pool, cache, stats, last_good, metrics, worker_status, config, clock, query.into_inner()).await
+}
+
+/// Handler for GET /nodes/top/{n}.
+///
+/// The `n` biggest nodes by capacity, for leaderboards. Same as
+/// `/nodes?limit=n&sort_by=capacity`, so it shares its cache entries. The
+/// formatting and filter params of /nodes work here too; paging and sorting
+/// ones are ignored.
+#[get("/nodes/top/{n}")]
+#[allow(clippy::too_many_arguments)]
+async fn get_top_nodes(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cache: web::Data<Cache<String, Vec<NodeResponse>>>,
+    stats: web::Data<CacheStats>,
+    last_good: web::Data<LastGoodNodes>,
+    metrics: web::Data<Metrics>,
+    worker_status: web::Data<WorkerStatus>,
+    config: web::Data<Config>,
+    clock: web::Data<&'static dyn Clock>,
+    path: web::Path<String>,
+    query: web::Query<NodesQuery>,
+) -> impl Responder {
+    let n = match path.parse::<i64>() {
+        Ok(n) if (1..=MAX_TOP_NODES).contains(&n) => n,
+        _ => return HttpResponse::BadRequest().body(format!("n must be a number from 1 to {}", MAX_TOP_NODES)),
+    };
+    let query = NodesQuery {
+        limit: Some(n),
+        all: None,
+        offset: None,
+        sort_by: Some("capacity".to_string()),
+        order: Some("desc".to_string()),
+        stream: None,
+        compress: None,
+        ..query.into_inner()
+    };
+    serve_nodes(req, pool, cache, stats, last_good, metrics, worker_status, config, clock, query).await
+}
+
+/// Builds the node listing for /nodes and /nodes/top/{n}.
+#[allow(clippy::too_many_arguments)]
+async fn serve_nodes(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cache: web::Data<Cache<String, Vec<NodeResponse>>>,
+    stats: web::Data<CacheStats>,
+    last_good: web::Data<LastGoodNodes>,
+    metrics: web::Data<Metrics>,
+    worker_status: web::Data<WorkerStatus>,
+    config: web::Data<Config>,
+    clock: web::Data<&'static dyn Clock>,
+    query: NodesQuery,
+) -> HttpResponse {
+    metrics.nodes_requests.inc();
+    let time_format = match TimeFormat::parse(query.date_format.as_deref()) {
+        Ok(format) => format,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    // The key case and field selection are applied when sending, so they don't
+    // need their own cache entries.
+    let case = match KeyCase::parse(query.case.as_deref()) {
+        Ok(case) => case,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let fields = match formatters::parse_fields(query.fields.as_deref()) {
+        Ok(fields) => fields,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    if let Err(msg) = check_filters(query.min_share, query.min_capacity, query.max_capacity) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    // Non-numeric values are already rejected with a 400 by `web::Query`.
+    let offset = query.offset.unwrap_or(0);
+    if offset < 0 || query.limit.is_some_and(|l| l < 0) {
+        return HttpResponse::BadRequest().body("limit and offset can't be negative");
+    }
+    let body_format = match BodyFormat::parse(query.format.as_deref(), req.headers().get(ACCEPT).and_then(|h| h.to_str().ok())) {
+        Ok(body_format) => body_format,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let compression = match Compression::parse(query.compress.as_deref()) {
+        Ok(compression) => compression,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    // CSV and compressed bodies are exports, so they're always streamed.
+    let stream = query.stream.unwrap_or(false) || body_format == BodyFormat::Csv || compression.is_some();
+    // Pages are `DEFAULT_PAGE_SIZE` nodes unless asked otherwise, and never more
+    // than `MAX_PAGE_SIZE`. `limit=0` and `all=true` are the explicit way out.
+    // A stream is meant for the whole table, so it's only limited when asked.
+    let all = query.all.unwrap_or(false);
+    if all && query.limit.is_some_and(|l| l > 0) {
+        return HttpResponse::BadRequest().body("Pass either limit or all=true, not both");
+    }
+    let limit = match query.limit {
+        _ if all => None,
+        Some(0) => None,
+        Some(limit) => Some(limit.min(MAX_PAGE_SIZE)),
+        None if stream => None,
+        None => Some(config.default_page_size),
+    };
+    let sort_by = match SortColumn::parse(query.sort_by.as_deref()) {
+        Ok(column) => column,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let order = match SortOrder::parse(query.order.as_deref()) {
+        Ok(order) => order.unwrap_or(sort_by.default_order()),
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    // Relative to the last successful fetch, so a failing worker doesn't make
+    // everything stale. Before the first one, nothing is.
+    let stale_before = query.include_stale_flag.unwrap_or(false).then(|| match worker_status.last_success_unix() {
+        Some(last_success) => last_success.saturating_sub_unsigned(config.stale_flag_after_secs),
+        None => i64::MIN,
+    });
+    let listing = NodeListing {
+        include_rank: query.include_rank.unwrap_or(false),
+        stale_before,
+        min_share: query.min_share,
+        min_capacity: query.min_capacity,
+        max_capacity: query.max_capacity,
+        limit,
+        offset,
+        sort_by,
+        order,
+    };
+    let unit = match DisplayUnit::parse(query.unit.as_deref()) {
+        Ok(unit) => unit,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let include_tier = query.include_tier.unwrap_or(false);
+    let format = NodeFormat {
+        time_format,
+        unit,
+        tiers: include_tier.then_some(config.capacity_tiers),
+        clock: *clock.get_ref(),
+    };
+    // Only for debugging, and off unless enabled, so it can't be used to hammer the DB.
+    let nocache = query.nocache.unwrap_or(false);
+    if nocache && !config.allow_cache_bypass {
+        return HttpResponse::Forbidden().body("nocache is disabled. Set ALLOW_CACHE_BYPASS=true to enable it.");
+    }
+    if stream {
+        info!("[API] Streaming /nodes");
+        return stream_nodes(pool.get_ref().clone(), listing, format, case, fields, body_format, compression);
+    }
+
+    // Read the version before anything else: if the data changes while we build
+    // the response, the client just gets a tag that won't match next time.
+    let version = worker_status.data_version();
+    // Relative times change without the data changing, so those get no ETag.
+    // Stale flags change with every successful fetch, even one that changed
+    // nothing, so their cutoff is part of the tag.
+    let etag = (time_format != TimeFormat::Relative).then(|| match stale_before {
+        Some(cutoff) => EntityTag::new_weak(format!("{:x}-{:x}", version, cutoff)),
+        None => EntityTag::new_weak(format!("{:x}", version)),
+    });
+    if let Some(etag) = etag.as_ref().filter(|_| !nocache)
+        && if_none_match(&req, etag)
+    {
+        return HttpResponse::NotModified().insert_header(ETag(etag.clone())).finish();
+    }
+    let ok = || {
+        let mut builder = HttpResponse::Ok();
+        if let Some(etag) = &etag {
+            builder.insert_header(ETag(etag.clone()));
+        }
+        builder
+    };
+
+    // Each variant of the response gets its own cache entry.
+    let cache_key = cache::scoped_key(&req, config.cache_per_client, cache::nodes_key(&listing, &format));
+    // The response cache is also per data version, so a cached body never goes
+    // out with the ETag of newer data.
+    let versioned_key = format!("{}:v={:x}", cache_key, version);
+
+    // Try to get the response from the cache, unless asked to skip it.
+    // A bypass still stores its result below, so it refreshes the entry.
+    if nocache {
+        info!("[API] Cache bypass requested for /nodes, reading from the DB.");
+    } else {
+        let cached = cache.get(&versioned_key).await;
+        stats.record(&versioned_key, cached.is_some());
+        if let Some(cached_nodes) = cached {
+            info!("[API] Cache hit for /nodes");
+            metrics.nodes_cache_hits.inc();
+            return nodes_response(ok(), &cached_nodes, case, fields.as_deref());
+        }
+        info!("[API] Cache miss for /nodes");
+        metrics.nodes_cache_misses.inc();
+    }
+
+    // If cache is empty, query the database.
+    // We run this in a blocking thread to avoid holding up the server.
+    let read_busy_timeout = config.read_busy_timeout;
+    let result = web::block(move || -> Result<Vec<NodeResponse>, DbError> {
+        let conn = pool.get()?;
+        // Pooled connections get reused, so the usual timeout is put back after.
+        let usual_timeout_ms: u64 = conn.pragma_query_value(None, "busy_timeout", |row| row.get(0))?;
+        conn.busy_timeout(read_busy_timeout)?;
+        let (sql, params) = listing.to_sql();
+        let nodes = read_nodes(&conn, &sql, rusqlite::params_from_iter(params), &format);
+        conn.busy_timeout(std::time::Duration::from_millis(usual_timeout_ms))?;
+        Ok(nodes?)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(nodes)) => {
+            // Put the result in the cache for next time.
+            cache.insert(versioned_key, nodes.clone()).await;
+            last_good.0.insert(cache_key, nodes.clone()).await;
+            nodes_response(ok(), &nodes, case, fields.as_deref())
+        }
+        Ok(Err(e)) if e.is_busy() => match last_good.0.get(&cache_key).await {
+            Some(nodes) => {
+                warn!("[API] Database is busy, serving last good /nodes response.");
+                let mut builder = HttpResponse::Ok();
+                builder.insert_header(("X-Maintenance", "true"));
+                nodes_response(builder, &nodes, case, fields.as_deref())
+            }
+            None => {
+                error!("DB busy and nothing to fall back on: {}", e);
+                HttpResponse::ServiceUnavailable().body("Database is busy, try again shortly")
+            }
+        },
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /nodes/count.
+///
+/// How many nodes /nodes would return without paging, so clients can size
+/// their pages up front. Takes the same filters. Cached with `CACHE_TTL_COUNT`.
+#[get("/nodes/count")]
+async fn get_node_count(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cache: web::Data<Cache<String, NodeCountResponse>>,
+    stats: web::Data<CacheStats>,
+    config: web::Data<Config>,
+    query: web::Query<NodeCountQuery>,
+) -> impl Responder {
+    if let Err(msg) = check_filters(query.min_share, query.min_capacity, query.max_capacity) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    let listing = NodeListing {
+        min_share: query.min_share,
+        min_capacity: query.min_capacity,
+        max_capacity: query.max_capacity,
+        ..NodeListing::default()
+    };
+    let cache_key = cache::scoped_key(&req, config.cache_per_client, format!("count:{}", listing.filter_key()));
+
+    let cached = cache.get(&cache_key).await;
+    stats.record(&cache_key, cached.is_some());
+    if let Some(response) = cached {
+        return HttpResponse::Ok().json(response);
+    }
+
+    let result = web::block(move || -> Result<i64, DbError> {
+        let conn = pool.get()?;
+        let (sql, params) = listing.count_sql();
+        Ok(conn.query_row(&sql, rusqlite::params_from_iter(params), |row| row.get(0))?)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(count)) => {
+            let response = NodeCountResponse { count };
+            cache.insert(cache_key, response.clone()).await;
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error counting nodes in database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /nodes/by-country.
+///
+/// Node counts and total capacity per country, most nodes first. Nodes the
+/// upstream couldn't place (Tor-only, for one) are grouped as "unknown".
+/// Cached with `CACHE_TTL_COUNTRIES`.
+#[get("/nodes/by-country")]
+async fn get_nodes_by_country(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cache: web::Data<Cache<String, Vec<CountryStat>>>,
+    stats: web::Data<CacheStats>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let cache_key = cache::scoped_key(&req, config.cache_per_client, "countries:all".to_string());
+
+    let cached = cache.get(&cache_key).await;
+    stats.record(&cache_key, cached.is_some());
+    if let Some(response) = cached {
+        return HttpResponse::Ok().json(response);
+    }
+
+    let result = web::block(move || -> Result<Vec<CountryStat>, DbError> {
+        let conn = pool.get()?;
+        // Added up here rather than with SUM(), which fails with "integer
+        // overflow" past i64::MAX. An i128 total can't overflow.
+        let mut stmt = conn.prepare("SELECT COALESCE(country_code, 'unknown'), country, capacity FROM nodes")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, i64>(2)?)))?;
+
+        let mut totals: std::collections::HashMap<String, (Option<String>, i64, i128)> = std::collections::HashMap::new();
+        for row in rows {
+            let (code, name, capacity) = row?;
+            let entry = totals.entry(code).or_insert((None, 0, 0));
+            entry.0 = entry.0.take().max(name);
+            entry.1 += 1;
+            entry.2 += i128::from(capacity);
+        }
+
+        let mut countries: Vec<CountryStat> = totals
+            .into_iter()
+            .map(|(country, (country_name, node_count, total))| CountryStat {
+                country,
+                country_name,
+                node_count,
+                total_capacity: formatters::format_capacity(total, DisplayUnit::Btc),
+                total_capacity_sats: total,
+            })
+            .collect();
+        countries.sort_by(|a, b| b.node_count.cmp(&a.node_count).then_with(|| a.country.cmp(&b.country)));
+        Ok(countries)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(countries)) => {
+            cache.insert(cache_key, countries.clone()).await;
+            HttpResponse::Ok().json(countries)
+        }
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error grouping nodes by country")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /nodes/search/pubkey?prefix=02abc.
+///
+/// Finds nodes whose public key starts with the given hex prefix.
+/// The prefix must be valid hex and at least `PUBKEY_PREFIX_MIN_LENGTH`
+/// characters long, so a one-char prefix can't pull half the table.
+#[get("/nodes/search/pubkey")]
+async fn search_nodes_by_pubkey(pool: web::Data<DbPool>, config: web::Data<Config>, query: web::Query<PubkeyPrefixQuery>) -> impl Responder {
+    const MAX_RESULTS: i64 = 100;
+    let min_len = config.pubkey_prefix_min_length;
+
+    let prefix = query.prefix.to_ascii_lowercase();
+    if prefix.len() < min_len {
+        return HttpResponse::BadRequest().body(format!("prefix must be at least {} characters", min_len));
+    }
+    if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return HttpResponse::BadRequest().body("prefix must be hex");
+    }
+    let case = match KeyCase::parse(query.case.as_deref()) {
+        Ok(case) => case,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+
+    let result = web::block(move || -> Result<Vec<NodeResponse>, DbError> {
+        let conn = pool.get()?;
+        // A range scan instead of LIKE, so SQLite can use the primary key index.
+        // Pubkeys are lowercase hex, so appending 'g' gives an exclusive upper bound.
+        let upper_bound = format!("{}g", prefix);
+        Ok(read_nodes(
+            &conn,
+            "SELECT public_key, alias, capacity, first_seen, channels, country, country_code FROM nodes
+             WHERE public_key >= ?1 AND public_key < ?2
+             ORDER BY public_key LIMIT ?3",
+            params![prefix, upper_bound, MAX_RESULTS],
+            &NodeFormat::default(),
+        )?)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(nodes)) => json_response(HttpResponse::Ok(), &nodes, case),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Escapes `%`, `_` and the escape char itself, so user input is matched literally
+/// by `LIKE ... ESCAPE '\'`.
+fn escape_like(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Handler for GET /nodes/search?q=bitrefill.
+///
+/// Finds nodes whose alias contains the term (case-insensitive for ASCII,
+/// like SQLite's LIKE). Biggest first, capped at 100 results.
+#[get("/nodes/search")]
+async fn search_nodes(pool: web::Data<DbPool>, query: web::Query<AliasSearchQuery>) -> impl Responder {
+    const MAX_RESULTS: i64 = 100;
+
+    if query.q.chars().count() < 2 {
+        return HttpResponse::BadRequest().body("q must be at least 2 characters");
+    }
+    let case = match KeyCase::parse(query.case.as_deref()) {
+        Ok(case) => case,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let pattern = format!("%{}%", escape_like(&query.q));
+
+    let result = web::block(move || -> Result<Vec<NodeResponse>, DbError> {
+        let conn = pool.get()?;
+        Ok(read_nodes(
+            &conn,
+            "SELECT public_key, alias, capacity, first_seen, channels, country, country_code FROM nodes
+             WHERE alias LIKE ?1 ESCAPE '\\'
+             ORDER BY capacity DESC, public_key LIMIT ?2",
+            params![pattern, MAX_RESULTS],
+            &NodeFormat::default(),
+        )?)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(nodes)) => json_response(HttpResponse::Ok(), &nodes, case),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /nodes/changes?since=1700000000.
+///
+/// Nodes added, or whose alias or capacity changed, at or after `since` (unix
+/// seconds), most recent first. Pollers can pass the `until` of one response
+/// (the clock's time) as the next `since`. A node that changed in that very
+/// second shows up in both, which is harmless.
+#[get("/nodes/changes")]
+async fn get_node_changes(pool: web::Data<DbPool>, clock: web::Data<&'static dyn Clock>, query: web::Query<NodeChangesQuery>) -> impl Responder {
+    // Non-numeric or missing `since` is already rejected with a 400 by `web::Query`.
+    let since = query.since;
+    let case = match KeyCase::parse(query.case.as_deref()) {
+        Ok(case) => case,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let until = clock.now().timestamp();
+
+    let result = web::block(move || -> Result<Vec<NodeResponse>, DbError> {
+        let conn = pool.get()?;
+        Ok(read_nodes(
+            &conn,
+            "SELECT public_key, alias, capacity, first_seen, channels, country, country_code FROM nodes
+             WHERE updated_at >= ?1
+             ORDER BY updated_at DESC, public_key",
+            [since],
+            &NodeFormat::default(),
+        )?)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(nodes)) => json_response(HttpResponse::Ok(), &NodeChangesResponse { since, until, nodes }, case),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /nodes/departed.
+///
+/// Nodes still in the database that weren't in the last successful fetch,
+/// most recently departed first, with how long each has been gone.
+///
+/// The last successful fetch is the newest `last_seen` in the table, since
+/// every fetch that stores data stamps all of its nodes with it. That survives
+/// restarts, and a 304 (which doesn't touch `last_seen`) doesn't make every
+/// node look departed. Paginated like /nodes: `limit` defaults to
+/// `DEFAULT_PAGE_SIZE`, is capped at `MAX_PAGE_SIZE`, and 0 means everything.
+#[get("/nodes/departed")]
+async fn get_departed_nodes(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<&'static dyn Clock>,
+    query: web::Query<DepartedNodesQuery>,
+) -> impl Responder {
+    let case = match KeyCase::parse(query.case.as_deref()) {
+        Ok(case) => case,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let offset = query.offset.unwrap_or(0);
+    if offset < 0 || query.limit.is_some_and(|l| l < 0) {
+        return HttpResponse::BadRequest().body("limit and offset can't be negative");
+    }
+    // SQLite reads a negative LIMIT as no limit.
+    let limit = match query.limit {
+        Some(0) => -1,
+        Some(limit) => limit.min(MAX_PAGE_SIZE),
+        None => config.default_page_size,
+    };
+    let now = clock.now().timestamp();
+
+    let result = web::block(move || -> Result<DepartedNodesResponse, DbError> {
+        let conn = pool.get()?;
+        let tx = conn.unchecked_transaction()?;
+        let last_fetch: Option<i64> = tx.query_row("SELECT MAX(last_seen) FROM nodes", [], |row| row.get(0))?;
+        let total: i64 = tx.query_row("SELECT COUNT(*) FROM nodes WHERE last_seen < ?1", [last_fetch], |row| row.get(0))?;
+        let mut stmt = tx.prepare(
+            "SELECT public_key, alias, capacity, first_seen, channels, country, country_code, last_seen FROM nodes
+             WHERE last_seen < ?1
+             ORDER BY last_seen DESC, public_key
+             LIMIT ?2 OFFSET ?3",
+        )?;
+        let nodes = stmt
+            .query_map(params![last_fetch, limit, offset], |row| {
+                let last_seen: i64 = row.get(NODE_COLUMNS)?;
+                Ok(DepartedNode {
+                    node: to_response(node_from_row(row, ExtraColumns::default())?, &NodeFormat::default()),
+                    last_seen,
+                    absent_secs: (now - last_seen).max(0),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(DepartedNodesResponse { last_fetch, total, nodes })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(response)) => json_response(HttpResponse::Ok(), &response, case),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Checks the /nodes/ranked weights and scales them to add up to 1. Without
+/// any, capacity and channels count half each; a weight that isn't given is 0.
+fn ranking_weights(query: &RankedNodesQuery) -> Result<RankingWeights, &'static str> {
+    let given = [query.w_capacity, query.w_channels, query.w_age];
+    let [capacity, channels, age] = if given.iter().all(Option::is_none) { [0.5, 0.5, 0.0] } else { given.map(|w| w.unwrap_or(0.0)) };
+    if [capacity, channels, age].iter().any(|w| !w.is_finite() || *w < 0.0) {
+        return Err("weights must be numbers of 0 or more");
+    }
+    let sum = capacity + channels + age;
+    if sum <= 0.0 {
+        return Err("weights must add up to more than 0");
+    }
+    Ok(RankingWeights { capacity: capacity / sum, channels: channels / sum, age: age / sum })
+}
+
+/// Scales `value` from the `min..=max` range to 0..1. 0 when every value is the same.
+fn min_max(value: i64, min: i64, max: i64) -> f64 {
+    if max > min { (value - min) as f64 / (max - min) as f64 } else { 0.0 }
+}
+
+/// The smallest and largest of `values`, or `(0, 0)` when there are none.
+fn value_range(values: impl Iterator<Item = i64>) -> (i64, i64) {
+    values.fold(None, |range: Option<(i64, i64)>, v| Some(range.map_or((v, v), |(min, max)| (min.min(v), max.max(v))))).unwrap_or_default()
+}
+
+/// Scores every node and sorts them best first (ties: bigger capacity, then public key).
+///
+/// Each component is min-max normalized over all nodes, so the smallest value
+/// gets 0 and the largest 1. Age is how long before the newest node's
+/// `first_seen` a node's is; nodes with the `first_seen = 0` placeholder get 0
+/// for age and don't count towards its range.
+fn rank_nodes(nodes: Vec<NodeFromDb>, weights: RankingWeights) -> Vec<RankedNode> {
+    let (min_capacity, max_capacity) = value_range(nodes.iter().map(|n| n.capacity));
+    let (min_channels, max_channels) = value_range(nodes.iter().map(|n| n.channels));
+    let (oldest, newest) = value_range(nodes.iter().map(|n| n.first_seen).filter(|t| *t > 0));
+
+    let mut scored: Vec<(f64, NodeFromDb)> = nodes
+        .into_iter()
+        .map(|node| {
+            let age = if node.first_seen > 0 { 1.0 - min_max(node.first_seen, oldest, newest) } else { 0.0 };
+            let score = weights.capacity * min_max(node.capacity, min_capacity, max_capacity)
+                + weights.channels * min_max(node.channels, min_channels, max_channels)
+                + weights.age * age;
+            (score, node)
+        })
+        .collect();
+    scored.sort_by(|(a_score, a), (b_score, b)| {
+        b_score.total_cmp(a_score).then(b.capacity.cmp(&a.capacity)).then_with(|| a.public_key.cmp(&b.public_key))
+    });
+    scored
+        .into_iter()
+        .map(|(score, node)| RankedNode {
+            node: to_response(node, &NodeFormat::default()),
+            // Enough digits to tell nodes apart, without float noise like 0.30000000000000004.
+            score: (score * 1e6).round() / 1e6,
+        })
+        .collect()
+}
+
+/// Handler for GET /nodes/ranked?w_capacity=0.7&w_channels=0.3.
+///
+/// Nodes sorted by a composite score mixing capacity, channel count and age
+/// (`w_age`), with the weights given. See `rank_nodes` for how each is
+/// normalized. Weights can't be negative and must add up to more than 0;
+/// they're scaled to add up to 1, so the score is between 0 and 1 and `7,3`
+/// ranks the same as `0.7,0.3` (and shares its cache entry). The full ranking
+/// is cached per weight combination with `CACHE_TTL_RANKED`, and pages are
+/// sliced out of it: `limit` defaults to `DEFAULT_PAGE_SIZE`, is capped at
+/// `MAX_PAGE_SIZE`, and 0 means everything.
+#[get("/nodes/ranked")]
+async fn get_ranked_nodes(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    cache: web::Data<Cache<String, Vec<RankedNode>>>,
+    stats: web::Data<CacheStats>,
+    query: web::Query<RankedNodesQuery>,
+) -> impl Responder {
+    let case = match KeyCase::parse(query.case.as_deref()) {
+        Ok(case) => case,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let weights = match ranking_weights(&query) {
+        Ok(weights) => weights,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    if query.offset.is_some_and(|o| o < 0) || query.limit.is_some_and(|l| l < 0) {
+        return HttpResponse::BadRequest().body("limit and offset can't be negative");
+    }
+    let offset = query.offset.unwrap_or(0) as usize;
+    let limit = match query.limit {
+        Some(0) => usize::MAX,
+        Some(limit) => limit.min(MAX_PAGE_SIZE) as usize,
+        None => config.default_page_size as usize,
+    };
+
+    let cache_key = cache::scoped_key(&req, config.cache_per_client, format!("ranked:{}:{}:{}", weights.capacity, weights.channels, weights.age));
+    let cached = cache.get(&cache_key).await;
+    stats.record(&cache_key, cached.is_some());
+    let ranked = match cached {
+        Some(ranked) => ranked,
+        None => {
+            let result = web::block(move || -> Result<Vec<RankedNode>, DbError> {
+                let conn = pool.get()?;
+                let mut stmt = conn.prepare("SELECT public_key, alias, capacity, first_seen, channels, country, country_code FROM nodes")?;
+                let nodes = stmt
+                    .query_map([], |row| node_from_row(row, ExtraColumns::default()))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(rank_nodes(nodes, weights))
+            })
+            .await;
+
+            match result {
+                Ok(Ok(ranked)) => {
+                    cache.insert(cache_key, ranked.clone()).await;
+                    ranked
+                }
+                Ok(Err(e)) => {
+                    error!("DB error: {}", e);
+                    return HttpResponse::InternalServerError().body("Error fetching nodes from database");
+                }
+                Err(e) => {
+                    error!("Task error: {}", e);
+                    return HttpResponse::InternalServerError().body("Internal server error");
+                }
+            }
+        }
+    };
+
+    let response = RankedNodesResponse {
+        weights,
+        total: ranked.len(),
+        nodes: ranked.into_iter().skip(offset).take(limit).collect(),
+    };
+    json_response(HttpResponse::Ok(), &response, case)
+}
+
+/// Whether the request's `If-None-Match` matches `etag`, weakly.
+fn if_none_match(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+/// Weak ETag for a single node, from its `updated_at` and capacity.
+///
+/// `updated_at` only moves when the alias or capacity changes, so the fields
+/// that can change without it (channels, location) are hashed in as well.
+fn node_etag(node: &NodeResponse, updated_at: i64, capacity: i64) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    (node.channels, &node.country, &node.country_code).hash(&mut hasher);
+    EntityTag::new_weak(format!("{:x}-{:x}-{:x}", updated_at, capacity, hasher.finish()))
+}
+
+/// Handler for GET /nodes/{public_key}.
+///
+/// Returns a single node. The key is checked (`models::is_valid_pubkey`) before touching the DB,
+/// and an unknown key gets a 404 with a small JSON error.
+///
+/// The response has an `ETag` from the node's `updated_at` and capacity, so
+/// clients polling one node can send `If-None-Match` and get a 304 while it
+/// hasn't changed.
+#[get("/nodes/{public_key}")]
+async fn get_node_by_pubkey(req: HttpRequest, pool: web::Data<DbPool>, public_key: web::Path<String>) -> impl Responder {
+    let public_key = public_key.into_inner().to_ascii_lowercase();
+    if !is_valid_pubkey(&public_key) {
+        return HttpResponse::BadRequest().body("public_key must be 66 hex characters starting with 02 or 03");
+    }
+
+    let result = web::block(move || -> Result<Option<(NodeResponse, EntityTag)>, DbError> {
+        let conn = pool.get()?;
+        let node = conn
+            .query_row(
+                "SELECT public_key, alias, capacity, first_seen, channels, country, country_code, updated_at FROM nodes WHERE public_key = ?1",
+                [&public_key],
+                |row| {
+                    let node = node_from_row(row, ExtraColumns::default())?;
+                    let capacity = node.capacity;
+                    // Only NULL for rows the worker never stored, which the migration fills in.
+                    let updated_at = row.get::<_, Option<i64>>(NODE_COLUMNS)?.unwrap_or(0);
+                    let node = to_response(node, &NodeFormat::default());
+                    let etag = node_etag(&node, updated_at, capacity);
+                    Ok((node, etag))
+                },
+            )
+            .optional()?;
+        Ok(node)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some((_, etag)))) if if_none_match(&req, &etag) => HttpResponse::NotModified().insert_header(ETag(etag)).finish(),
+        Ok(Ok(Some((node, etag)))) => HttpResponse::Ok().insert_header(ETag(etag)).json(node),
+        Ok(Ok(None)) => HttpResponse::NotFound().json(serde_json::json!({ "error": "Node not found" })),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /nodes/{public_key}/history.
+///
+/// The node's capacity over time, oldest first. The worker records an entry
+/// each time the capacity changes. 404 if we've never seen the node.
+#[get("/nodes/{public_key}/history")]
+async fn get_node_history(pool: web::Data<DbPool>, public_key: web::Path<String>) -> impl Responder {
+    let public_key = public_key.into_inner().to_ascii_lowercase();
+    if !is_valid_pubkey(&public_key) {
+        return HttpResponse::BadRequest().body("public_key must be 66 hex characters starting with 02 or 03");
+    }
+
+    let result = web::block(move || -> Result<Option<NodeHistory>, DbError> {
+        let conn = pool.get()?;
+        let history = conn
+            .prepare(
+                "SELECT capacity, channels, recorded_at FROM node_history
+                 WHERE public_key = ?1 ORDER BY recorded_at, id",
+            )?
+            .query_map([&public_key], |row| {
+                let capacity: i64 = row.get(0)?;
+                Ok(HistoryEntry {
+                    capacity: formatters::format_capacity(capacity, DisplayUnit::Btc),
+                    capacity_sats: capacity,
+                    channels: row.get(1)?,
+                    recorded_at: formatters::format_timestamp(row.get(2)?),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Nodes stored before history tracking have no entries yet, but still exist.
+        if history.is_empty() {
+            let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM nodes WHERE public_key = ?1)", [&public_key], |row| row.get(0))?;
+            if !exists {
+                return Ok(None);
+            }
+        }
+        Ok(Some(NodeHistory { public_key, history }))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some(history))) => HttpResponse::Ok().json(history),
+        Ok(Ok(None)) => HttpResponse::NotFound().json(serde_json::json!({ "error": "Node not found" })),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching node history from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Turns a node's history into timeline events, oldest first: `appeared` for
+/// the first entry, `capacity_change` for each one after it, and `departed` at
+/// `departed_at` when the node is gone from the last fetch.
+///
+/// `history` is `(capacity, channels, recorded_at)`, oldest first.
+fn timeline_events(history: &[(i64, i64, i64)], departed_at: Option<i64>) -> Vec<TimelineEvent> {
+    let mut events: Vec<TimelineEvent> = history
+        .iter()
+        .enumerate()
+        .map(|(i, &(capacity, channels, at))| match i.checked_sub(1).map(|prev| history[prev].0) {
+            None => TimelineEvent::Appeared { at, capacity_sats: capacity, channels },
+            Some(from_sats) => TimelineEvent::CapacityChange { at, from_sats, to_sats: capacity, channels },
+        })
+        .collect();
+    events.extend(departed_at.map(|at| TimelineEvent::Departed { at }));
+    events
+}
+
+/// Takes one page of `events` (oldest first): those after `after`, up to
+/// `limit` of them. A page never ends in the middle of events sharing a time,
+/// so the next page can start strictly after the last one. Also returns where
+/// that next page starts, if there is one.
+fn timeline_page(events: Vec<TimelineEvent>, after: Option<i64>, limit: usize) -> (Vec<TimelineEvent>, Option<i64>) {
+    let mut page: Vec<TimelineEvent> = events.into_iter().filter(|e| after.is_none_or(|after| e.at() > after)).collect();
+    if page.len() <= limit {
+        return (page, None);
+    }
+    let mut end = limit.max(1);
+    while end < page.len() && page[end].at() == page[end - 1].at() {
+        end += 1;
+    }
+    let more = end < page.len();
+    page.truncate(end);
+    let next = more.then(|| page[end - 1].at());
+    (page, next)
+}
+
+/// Handler for GET /nodes/{public_key}/timeline.
+///
+/// The node's history as typed events, oldest first: when it appeared, each
+/// capacity change, and when it departed (went missing from the last
+/// successful fetch, as on /nodes/departed). Built from `node_history` and the
+/// node's `last_seen`. Aliases aren't recorded over time, so there are no alias
+/// changes; a node that was pruned has no `last_seen` left, so no departure.
+///
+/// Paginated by time: `after` skips events up to that Unix time, and
+/// `next_after` in the response is where the next page starts. `limit`
+/// defaults to `DEFAULT_PAGE_SIZE` and is capped at `MAX_PAGE_SIZE`. A node
+/// with nothing recorded gets an empty timeline.
+#[get("/nodes/{public_key}/timeline")]
+async fn get_node_timeline(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    public_key: web::Path<String>,
+    query: web::Query<TimelineQuery>,
+) -> impl Responder {
+    let public_key = public_key.into_inner().to_ascii_lowercase();
+    if !is_valid_pubkey(&public_key) {
+        return HttpResponse::BadRequest().body("public_key must be 66 hex characters starting with 02 or 03");
+    }
+    let limit = match query.limit {
+        None => config.default_page_size,
+        Some(limit) if limit >= 1 => limit.min(MAX_PAGE_SIZE),
+        Some(_) => return HttpResponse::BadRequest().body("limit must be at least 1"),
+    };
+    let after = query.after;
+
+    let result = web::block(move || -> Result<NodeTimeline, DbError> {
+        let conn = pool.get()?;
+        let tx = conn.unchecked_transaction()?;
+        let history = tx
+            .prepare("SELECT capacity, channels, recorded_at FROM node_history WHERE public_key = ?1 ORDER BY recorded_at, id")?
+            .query_map([&public_key], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // Departed means missing from the last successful fetch, like /nodes/departed.
+        let departed_at: Option<i64> = tx
+            .query_row(
+                "SELECT last_seen FROM nodes WHERE public_key = ?1 AND last_seen < (SELECT MAX(last_seen) FROM nodes)",
+                [&public_key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let (events, next_after) = timeline_page(timeline_events(&history, departed_at), after, limit as usize);
+        Ok(NodeTimeline { public_key, events, next_after })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(timeline)) => HttpResponse::Ok().json(timeline),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching node history from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Picks `points` values spread evenly across `values`, always keeping the
+/// first and the last. Returns them all when there aren't more than `points`.
+fn downsample<T: Copy>(values: &[T], points: usize) -> Vec<T> {
+    if values.len() <= points {
+        return values.to_vec();
+    }
+    if points == 1 {
+        return vec![values[values.len() - 1]];
+    }
+    let last = values.len() - 1;
+    // Rounded to the nearest index, so the gaps differ by at most one.
+    (0..points).map(|i| values[(i * last + (points - 1) / 2) / (points - 1)]).collect()
+}
+
+/// Handler for GET /nodes/{public_key}/sparkline.
+///
+/// The node's recorded capacities in sats, oldest first, as a bare array for
+/// mini charts. Long histories are downsampled evenly to `?points=` values
+/// (default `SPARKLINE_POINTS`, at most `MAX_SPARKLINE_POINTS`). A node with no
+/// history yet gets `[]`, one we've never seen a 404.
+#[get("/nodes/{public_key}/sparkline")]
+async fn get_node_sparkline(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    public_key: web::Path<String>,
+    query: web::Query<SparklineQuery>,
+) -> impl Responder {
+    let public_key = public_key.into_inner().to_ascii_lowercase();
+    if !is_valid_pubkey(&public_key) {
+        return HttpResponse::BadRequest().body("public_key must be 66 hex characters starting with 02 or 03");
+    }
+    let points = query.points.unwrap_or(config.sparkline_points);
+    if !(1..=MAX_SPARKLINE_POINTS).contains(&points) {
+        return HttpResponse::BadRequest().body(format!("points must be between 1 and {}", MAX_SPARKLINE_POINTS));
+    }
+
+    let result = web::block(move || -> Result<Option<Vec<i64>>, DbError> {
+        let conn = pool.get()?;
+        let capacities = conn
+            .prepare("SELECT capacity FROM node_history WHERE public_key = ?1 ORDER BY recorded_at, id")?
+            .query_map([&public_key], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+
+        if capacities.is_empty() {
+            let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM nodes WHERE public_key = ?1)", [&public_key], |row| row.get(0))?;
+            if !exists {
+                return Ok(None);
+            }
+        }
+        Ok(Some(downsample(&capacities, points)))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some(values))) => HttpResponse::Ok().json(values),
+        Ok(Ok(None)) => HttpResponse::NotFound().json(serde_json::json!({ "error": "Node not found" })),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching node history from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Fewest history entries a forecast will fit a line to.
+const MIN_FORECAST_POINTS: usize = 3;
+
+/// A least-squares line through `points`.
+#[derive(Debug, PartialEq)]
+struct LinearFit {
+    slope: f64,
+    intercept: f64,
+    /// Share of the variance the line explains, 0 to 1.
+    r_squared: f64,
+}
+
+/// Ordinary least squares over `(x, y)` points:
+/// `slope = Σ(x - x̄)(y - ȳ) / Σ(x - x̄)²` and `intercept = ȳ - slope * x̄`.
+/// Returns `None` when every x is the same, since no line fits that.
+fn linear_fit(points: &[(f64, f64)]) -> Option<LinearFit> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    let intercept = mean_y - slope * mean_x;
+    let ss_total: f64 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+    let ss_residual: f64 = points.iter().map(|(x, y)| (y - (intercept + slope * x)).powi(2)).sum();
+    // A flat history is fitted exactly by a flat line.
+    let r_squared = if ss_total == 0.0 { 1.0 } else { (1.0 - ss_residual / ss_total).max(0.0) };
+    Some(LinearFit { slope, intercept, r_squared })
+}
+
+/// Handler for GET /nodes/{public_key}/forecast.
+///
+/// Fits a straight line to the node's capacity history (time against sats)
+/// and extends it `?horizon_days=` days past now (default
+/// `FORECAST_HORIZON_DAYS`), one value a day. Projections below zero are
+/// shown as zero. It's a trend line, not a prediction: history only has an
+/// entry when the capacity changed, and capacity moves in steps, so the
+/// response carries `r_squared` and a caveat saying as much.
+///
+/// 404 for a node we've never seen, 422 when it has fewer than
+/// `MIN_FORECAST_POINTS` entries (or they're all at the same time).
+#[get("/nodes/{public_key}/forecast")]
+async fn get_node_forecast(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<&'static dyn Clock>,
+    public_key: web::Path<String>,
+    query: web::Query<ForecastQuery>,
+) -> impl Responder {
+    const DAY_SECS: i64 = 86_400;
+    let public_key = public_key.into_inner().to_ascii_lowercase();
+    if !is_valid_pubkey(&public_key) {
+        return HttpResponse::BadRequest().body("public_key must be 66 hex characters starting with 02 or 03");
+    }
+    let horizon_days = query.horizon_days.unwrap_or(config.forecast_horizon_days);
+    if !(1..=MAX_FORECAST_HORIZON_DAYS).contains(&horizon_days) {
+        return HttpResponse::BadRequest().body(format!("horizon_days must be between 1 and {}", MAX_FORECAST_HORIZON_DAYS));
+    }
+    let now = clock.now().timestamp();
+
+    let key = public_key.clone();
+    let result = web::block(move || -> Result<Option<Vec<(f64, f64)>>, DbError> {
+        let conn = pool.get()?;
+        let points = conn
+            .prepare("SELECT recorded_at, capacity FROM node_history WHERE public_key = ?1 ORDER BY recorded_at, id")?
+            .query_map([&key], |row| Ok((row.get::<_, i64>(0)? as f64, row.get::<_, i64>(1)? as f64)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if points.is_empty() {
+            let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM nodes WHERE public_key = ?1)", [&key], |row| row.get(0))?;
+            if !exists {
+                return Ok(None);
+            }
+        }
+        Ok(Some(points))
+    })
+    .await;
+
+    let points = match result {
+        Ok(Ok(Some(points))) => points,
+        Ok(Ok(None)) => return HttpResponse::NotFound().json(serde_json::json!({ "error": "Node not found" })),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            return HttpResponse::InternalServerError().body("Error fetching node history from database");
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            return HttpResponse::InternalServerError().body("Internal server error");
+        }
+    };
+    let fit = match linear_fit(&points) {
+        Some(fit) if points.len() >= MIN_FORECAST_POINTS => fit,
+        _ => {
+            return HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                "error": format!("A forecast needs at least {} capacity changes recorded at different times", MIN_FORECAST_POINTS),
+                "points": points.len(),
+            }));
+        }
+    };
+
+    let projection = (1..=i64::from(horizon_days))
+        .map(|day| {
+            let at = now + day * DAY_SECS;
+            // `as` saturates, and capacity can't go below zero.
+            let capacity_sats = ((fit.intercept + fit.slope * at as f64).round() as i64).max(0);
+            ForecastPoint {
+                date: formatters::format_timestamp(at),
+                capacity: formatters::format_capacity(capacity_sats, DisplayUnit::Btc),
+                capacity_sats,
+            }
+        })
+        .collect();
+    HttpResponse::Ok().json(NodeForecast {
+        public_key,
+        points_used: points.len(),
+        slope_sats_per_day: fit.slope * DAY_SECS as f64,
+        r_squared: fit.r_squared,
+        projection,
+        caveat: format!(
+            "Straight-line fit over {} recorded capacity changes. Channel opens and closes make capacity jump, so treat this as a trend, not a prediction, especially when r_squared is low.",
+            points.len()
+        ),
+    })
+}
+
+/// Handler for POST /nodes/unknown.
+///
+/// For incremental sync: the client sends the pubkeys it already has, either
+/// as a plain list (`{"pubkeys": [...]}`) or as a bloom filter
+/// (`{"bloom": {"bits": "<hex>", "hashes": k}}`), and gets back only the nodes
+/// it doesn't have yet, biggest first. With a bloom filter, a false positive
+/// means a new node can occasionally be left out; size the filter accordingly.
+#[post("/nodes/unknown")]
+async fn get_unknown_nodes(pool: web::Data<DbPool>, body: web::Json<KnownNodesRequest>) -> impl Responder {
+    const MAX_PUBKEYS: usize = 50_000;
+    let body = body.into_inner();
+
+    // Build a single "does the client have this key?" check from whichever form we got.
+    let is_known: Box<dyn Fn(&str) -> bool + Send> = match (body.pubkeys, body.bloom) {
+        (Some(_), Some(_)) => return HttpResponse::BadRequest().body("Send either pubkeys or bloom, not both"),
+        (None, None) => return HttpResponse::BadRequest().body("Send either pubkeys or bloom"),
+        (Some(pubkeys), None) => {
+            if pubkeys.len() > MAX_PUBKEYS {
+                return HttpResponse::BadRequest().body(format!("At most {} pubkeys per request", MAX_PUBKEYS));
+            }
+            let known: std::collections::HashSet<String> = pubkeys.into_iter().map(|k| k.to_ascii_lowercase()).collect();
+            Box::new(move |key| known.contains(key))
+        }
+        (None, Some(spec)) => match bloom::BloomFilter::from_hex(&spec.bits, spec.hashes) {
+            Ok(filter) => Box::new(move |key| filter.might_contain(key)),
+            Err(msg) => return HttpResponse::BadRequest().body(msg),
+        },
+    };
+
+    let result = web::block(move || -> Result<Vec<NodeResponse>, DbError> {
+        let conn = pool.get()?;
+        let (sql, params) = NodeListing::default().to_sql();
+        let nodes = read_nodes(&conn, &sql, rusqlite::params_from_iter(params), &NodeFormat::default())?;
+        Ok(nodes.into_iter().filter(|node| !is_known(&node.public_key)).collect())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(nodes)) => HttpResponse::Ok().json(nodes),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// The alias prefix used to group a node into an entity.
+///
+/// Keeps the first `depth` parts of the alias when split on `separator`,
+/// so with "-" and depth 1, "ACME-01" and "ACME-02" both become "ACME".
+/// An alias without the separator is its own group.
+fn entity_prefix(alias: &str, separator: &str, depth: usize) -> String {
+    alias.split(separator).take(depth).collect::<Vec<_>>().join(separator).trim().to_string()
+}
+
+/// Adds up the capacity of every node in `listing`.
+///
+/// SQLite's SUM() fails with "integer overflow" past i64::MAX, so the total is
+/// kept in an i128 on our side instead.
+fn sum_capacity(conn: &Connection, listing: &NodeListing) -> rusqlite::Result<i128> {
+    let (sql, params) = listing.capacities_sql();
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+    let mut total: i128 = 0;
+    while let Some(row) = rows.next()? {
+        total += i128::from(row.get::<_, i64>(0)?);
+    }
+    Ok(total)
+}
+
+/// `total / count` rounded to the nearest integer, halves away from zero.
+/// Capacities are never negative, so that's halves up.
+fn rounded_average(total: i128, count: i64) -> i128 {
+    if count == 0 {
+        return 0;
+    }
+    let count = i128::from(count);
+    (total * 2 + count) / (count * 2)
+}
+
+/// `total / count` as a decimal string with `precision` places, worked out in
+/// integers so nothing is lost to floating point. The last place is rounded
+/// half up. `precision` must be at most `config::MAX_AVG_PRECISION`.
+fn exact_average(total: i128, count: i64, precision: u32) -> String {
+    let count = i128::from(count.max(1));
+    let scale = 10i128.pow(precision);
+    let mut whole = total / count;
+    // `remainder < count < 2^63` and `scale <= 10^18 < 2^60`, so this can't overflow.
+    let mut fraction = ((total % count) * scale * 2 + count) / (count * 2);
+    if fraction == scale {
+        whole += 1;
+        fraction = 0;
+    }
+    if precision == 0 {
+        whole.to_string()
+    } else {
+        format!("{}.{:0width$}", whole, fraction, width = precision as usize)
+    }
+}
+
+/// How many nodes `sampled_stats` looks at.
+const STATS_SAMPLE_SIZE: usize = 500;
+
+/// The /stats figures, worked out from every matching node.
+fn exact_stats(conn: &Connection, listing: &NodeListing, exact: bool, precision: u32) -> rusqlite::Result<StatsResponse> {
+    let total = sum_capacity(conn, listing)?;
+    let (sql, params) = listing.aggregate_sql();
+    let (node_count, avg, max, min) = conn.query_row(&sql, rusqlite::params_from_iter(params), |row| {
+        // Everything but COUNT is NULL when no node matches.
+        let node_count: i64 = row.get(0)?;
+        let avg = if exact {
+            // The average of i64s always fits in an i64.
+            rounded_average(total, node_count) as i64
+        } else {
+            row.get::<_, Option<f64>>(1)?.unwrap_or(0.0).round() as i64
+        };
+        Ok((node_count, avg, row.get::<_, Option<i64>>(2)?.unwrap_or(0), row.get::<_, Option<i64>>(3)?.unwrap_or(0)))
+    })?;
+    let (sql, params) = listing.nth_capacity_sql((node_count - 1).max(0) / 2);
+    let median: i64 = conn.query_row(&sql, rusqlite::params_from_iter(params), |row| row.get(0)).optional()?.unwrap_or(0);
+    Ok(stats_response(node_count, total, avg, exact.then(|| exact_average(total, node_count, precision)), max, min, median, false))
+}
+
+/// The /stats figures estimated from `STATS_SAMPLE_SIZE` nodes, for when the
+/// exact ones take too long. The nodes are picked at random rowids, each found
+/// through the primary key, so this doesn't scan the table. The count, max and
+/// min come from `idx_capacity` and stay exact.
+fn sampled_stats(conn: &Connection, listing: &NodeListing) -> rusqlite::Result<StatsResponse> {
+    let (sql, params) = listing.count_sql();
+    let node_count: i64 = conn.query_row(&sql, rusqlite::params_from_iter(params), |row| row.get(0))?;
+    let (sql, params) = listing.capacity_range_sql();
+    let (min, max): (Option<i64>, Option<i64>) = conn.query_row(&sql, rusqlite::params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let (first, last): (Option<i64>, Option<i64>) = conn.query_row("SELECT MIN(rowid), MAX(rowid) FROM nodes", [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut sample = Vec::with_capacity(STATS_SAMPLE_SIZE);
+    if let (Some(first), Some(last)) = (first, last) {
+        let (sql, params) = listing.sample_sql();
+        let mut stmt = conn.prepare(&sql)?;
+        for _ in 0..STATS_SAMPLE_SIZE {
+            let mut params = params.clone();
+            params.push(rusqlite::types::Value::Integer(rand::random_range(first..=last)));
+            sample.extend(stmt.query_row(rusqlite::params_from_iter(params), |row| row.get::<_, i64>(0)).optional()?);
+        }
+    }
+    sample.sort_unstable();
+
+    let sample_total: i128 = sample.iter().map(|&c| i128::from(c)).sum();
+    let avg = rounded_average(sample_total, sample.len() as i64) as i64;
+    let median = sample.get(sample.len().saturating_sub(1) / 2).copied().unwrap_or(0);
+    let total = i128::from(avg) * i128::from(node_count);
+    Ok(stats_response(node_count, total, avg, None, max.unwrap_or(0), min.unwrap_or(0), median, true))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn stats_response(node_count: i64, total: i128, avg: i64, avg_exact: Option<String>, max: i64, min: i64, median: i64, approximate: bool) -> StatsResponse {
+    StatsResponse {
+        node_count,
+        total_capacity: formatters::format_capacity(total, DisplayUnit::Btc),
+        total_capacity_sats: total,
+        avg_capacity: formatters::format_capacity(avg, DisplayUnit::Btc),
+        avg_capacity_sats: avg,
+        avg_capacity_sats_exact: avg_exact,
+        max_capacity: formatters::format_capacity(max, DisplayUnit::Btc),
+        max_capacity_sats: max,
+        min_capacity: formatters::format_capacity(min, DisplayUnit::Btc),
+        min_capacity_sats: min,
+        median_capacity: formatters::format_capacity(median, DisplayUnit::Btc),
+        median_capacity_sats: median,
+        approximate,
+    }
+}
+
+/// Handler for GET /stats.
+///
+/// Totals for the whole table, so clients don't have to fetch every node to
+/// add them up. Cached like /nodes, with its own `CACHE_TTL_STATS`.
+///
+/// The average normally comes from SQLite's AVG(), which adds up in floating
+/// point and can be off by a few sats on huge totals. `?exact=true` divides the
+/// exact total instead, and adds the unrounded result as `avg_capacity_sats_exact`.
+///
+/// `?min_capacity=&max_capacity=` narrow everything to a capacity band (in sats,
+/// inclusive), cached per band.
+///
+/// `?budget_ms=` caps how long the exact figures may take. Past it, the query
+/// is stopped and the total, average and median are estimated from a sample
+/// instead, with `approximate: true`. Estimates aren't cached.
+#[get("/stats")]
+async fn get_stats(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    cache: web::Data<Cache<String, StatsResponse>>,
+    stats: web::Data<CacheStats>,
+    query: web::Query<StatsQuery>,
+) -> impl Responder {
+    if let Err(msg) = check_filters(None, query.min_capacity, query.max_capacity) {
+        return HttpResponse::BadRequest().body(msg);
+    }
+    let listing = NodeListing {
+        min_capacity: query.min_capacity,
+        max_capacity: query.max_capacity,
+        ..NodeListing::default()
+    };
+    let exact = query.exact.unwrap_or(false);
+    let precision = config.stats_avg_precision;
+    let cache_key = cache::scoped_key(&req, config.cache_per_client, format!("stats:exact={}:{}", exact, listing.filter_key()));
+
+    let cached = cache.get(&cache_key).await;
+    stats.record(&cache_key, cached.is_some());
+    if let Some(response) = cached {
+        return HttpResponse::Ok().json(response);
+    }
+
+    let budget = query.budget_ms.map(std::time::Duration::from_millis);
+    let result = web::block(move || -> Result<StatsResponse, DbError> {
+        let conn = pool.get()?;
+        let Some(budget) = budget else {
+            return Ok(exact_stats(&conn, &listing, exact, precision)?);
+        };
+        // SQLite calls this every 1,000 steps, and stops the query once it says so.
+        let deadline = std::time::Instant::now() + budget;
+        conn.progress_handler(1_000, Some(move || std::time::Instant::now() >= deadline));
+        let stats = exact_stats(&conn, &listing, exact, precision);
+        // The connection goes back to the pool, so the handler can't stay on it.
+        conn.progress_handler(0, None::<fn() -> bool>);
+        match stats {
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::OperationInterrupted => {
+                info!("[API] /stats went past its {}ms budget, sampling instead.", budget.as_millis());
+                Ok(sampled_stats(&conn, &listing)?)
+            }
+            stats => Ok(stats?),
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(response)) => {
+            // Estimates aren't cached, so they don't stand in for the real
+            // figures; a cached exact response is still served to budgeted requests.
+            if !response.approximate {
+                cache.insert(cache_key, response.clone()).await;
+            }
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error fetching stats from database")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /entities.
+///
+/// Best-effort operator detection: nodes whose aliases share a prefix before
+/// `separator` (e.g. "ACME-01", "ACME-02") are grouped together, with per-group
+/// node counts and total capacity. It's only a naming heuristic, so unrelated
+/// nodes with similar names can end up in the same group.
+///
+/// `separator` and `depth` default to `ENTITY_SEPARATOR` and `ENTITY_PREFIX_DEPTH`.
+/// Groups smaller than `min_nodes` (default 2) are left out. Paginated with
+/// `limit` (default 50, max 500) and `offset`.
+#[get("/entities")]
+async fn get_entities(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cache: web::Data<Cache<String, Vec<EntityGroup>>>,
+    stats: web::Data<CacheStats>,
+    config: web::Data<Config>,
+    query: web::Query<EntitiesQuery>,
+) -> impl Responder {
+    let separator = query.separator.clone().unwrap_or_else(|| config.entity_separator.clone());
+    let depth = query.depth.unwrap_or(config.entity_prefix_depth);
+    let min_nodes = query.min_nodes.unwrap_or(2);
+    let limit = query.limit.unwrap_or(50).min(500);
+    let offset = query.offset.unwrap_or(0);
+
+    if separator.is_empty() {
+        return HttpResponse::BadRequest().body("separator must not be empty");
+    }
+    if depth == 0 {
+        return HttpResponse::BadRequest().body("depth must be at least 1");
+    }
+
+    // The full grouping is cached; pages are sliced out of it.
+    let cache_key = cache::scoped_key(&req, config.cache_per_client, format!("entities:{}:{}:{}", separator, depth, min_nodes));
+    let cached = cache.get(&cache_key).await;
+    stats.record(&cache_key, cached.is_some());
+    let groups = match cached {
+        Some(groups) => groups,
+        None => {
+            let result = web::block(move || -> Result<Vec<EntityGroup>, DbError> {
+                let conn = pool.get()?;
+                let mut stmt = conn.prepare("SELECT alias, capacity FROM nodes")?;
+                let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+
+                // Totals are i128s, like on /stats, so a big group can't overflow.
+                let mut totals: std::collections::HashMap<String, (u64, i128)> = std::collections::HashMap::new();
+                for row in rows {
+                    let (alias, capacity) = row?;
+                    let prefix = entity_prefix(&alias, &separator, depth);
+                    if prefix.is_empty() {
+                        continue; // Nodes without an alias can't be grouped.
+                    }
+                    let entry = totals.entry(prefix).or_insert((0, 0));
+                    entry.0 += 1;
+                    entry.1 += i128::from(capacity);
+                }
+
+                let mut groups: Vec<EntityGroup> = totals
+                    .into_iter()
+                    .filter(|(_, (count, _))| *count >= min_nodes)
+                    .map(|(entity, (node_count, sats))| EntityGroup {
+                        entity,
+                        node_count,
+                        total_capacity: formatters::format_capacity(sats, DisplayUnit::Btc),
+                        total_capacity_sats: sats,
+                    })
+                    .collect();
+                groups.sort_by(|a, b| {
+                    b.node_count
+                        .cmp(&a.node_count)
+                        .then(b.total_capacity_sats.cmp(&a.total_capacity_sats))
+                        .then_with(|| a.entity.cmp(&b.entity))
+                });
+                Ok(groups)
+            })
+            .await;
+
+            match result {
+                Ok(Ok(groups)) => {
+                    cache.insert(cache_key, groups.clone()).await;
+                    groups
+                }
+                Ok(Err(e)) => {
+                    error!("DB error: {}", e);
+                    return HttpResponse::InternalServerError().body("Error fetching nodes from database");
+                }
+                Err(e) => {
+                    error!("Task error: {}", e);
+                    return HttpResponse::InternalServerError().body("Internal server error");
+                }
+            }
+        }
+    };
+
+    let page: Vec<&EntityGroup> = groups.iter().skip(offset).take(limit).collect();
+    HttpResponse::Ok().json(page)
+}
+
+/// Handler for GET /.
+///
+/// A small discovery document listing the available endpoints.
+#[get("/")]
+async fn index() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "service": "lightningnetworkrust",
+        "version": env!("CARGO_PKG_VERSION"),
+        "endpoints": ENDPOINTS,
+    }))
+}
+
+/// Handler for GET /healthz.
+///
+/// For orchestrators: 200 if a pooled connection can run `SELECT 1` and the
+/// worker's loop is running, 503 if not. A worker that's restarting after a
+/// panic, or stopped (its cron schedule ran out, say), leaves the data going
+/// stale, so it counts as unhealthy. Also says whether the worker has completed
+/// a fetch yet, which doesn't affect the status (an empty DB on first start is
+/// still healthy).
+#[get("/healthz")]
+async fn health_check(pool: web::Data<DbPool>, worker_status: web::Data<WorkerStatus>) -> impl Responder {
+    let result = web::block(move || -> Result<i64, DbError> {
+        let conn = pool.get()?;
+        Ok(conn.query_row("SELECT 1", [], |row| row.get(0))?)
+    })
+    .await;
+
+    let worker_state = worker_status.state();
+    let body = |status: &str| {
+        serde_json::json!({
+            "status": status,
+            "worker_has_fetched": worker_status.has_fetched(),
+            "worker_state": worker_state,
+            "worker_panics": worker_status.panics(),
+        })
+    };
+    match result {
+        Ok(Ok(_)) if worker_state == WorkerState::Running => HttpResponse::Ok().json(body("ok")),
+        Ok(Ok(_)) => {
+            warn!("[Health] Worker isn't running: {:?}", worker_state);
+            HttpResponse::ServiceUnavailable().json(body("degraded"))
+        }
+        Ok(Err(e)) => {
+            warn!("[Health] Database check failed: {}", e);
+            HttpResponse::ServiceUnavailable().json(body("degraded"))
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::ServiceUnavailable().json(body("degraded"))
+        }
+    }
+}
+
+/// Handler for GET /worker/status.
+///
+/// What the background worker has been up to, for alerting on a stuck or
+/// failing worker without digging through the logs.
+#[get("/worker/status")]
+async fn get_worker_status(worker_status: web::Data<WorkerStatus>) -> impl Responder {
+    HttpResponse::Ok().json(WorkerStatusResponse {
+        state: worker_status.state(),
+        panics: worker_status.panics(),
+        has_fetched: worker_status.has_fetched(),
+        last_success_unix: worker_status.last_success_unix(),
+        last_error: worker_status.last_error(),
+        consecutive_failures: worker_status.consecutive_failures(),
+        last_fetch_duration_ms: worker_status.last_fetch_duration_ms(),
+    })
+}
 
-    // Try to get the response from the cache.
-    if let Some(cached_nodes) = cache.get(&cache_key).await {
-        info!("[API] Cache hit for /nodes");
-        return HttpResponse::Ok().json(cached_nodes);
+/// Handler for GET /metrics.
+///
+/// Prometheus text format. The node count gauge is refreshed here,
+/// the other counters are bumped as things happen.
+#[get("/metrics")]
+async fn get_metrics(pool: web::Data<DbPool>, metrics: web::Data<Metrics>) -> impl Responder {
+    let result = web::block(move || -> Result<i64, DbError> {
+        let conn = pool.get()?;
+        Ok(conn.query_row("SELECT COUNT(*) FROM nodes", [], |row| row.get(0))?)
+    })
+    .await;
+    match result {
+        Ok(Ok(count)) => metrics.node_count.set(count),
+        // Still worth serving the rest, the gauge just keeps its last value.
+        Ok(Err(e)) => warn!("[Metrics] Couldn't count nodes: {}", e),
+        Err(e) => error!("Task error: {}", e),
     }
-    info!("[API] Cache miss for /nodes");
 
-    // If cache is empty, query the database.
-    // We run this in a blocking thread to avoid holding up the server.
-    let result = web::block(move || -> Result<Vec<NodeResponse>, rusqlite::Error> {
-        let conn = Connection::open(&db_path)?;
-        let mut stmt = conn.prepare("SELECT public_key, alias, capacity, first_seen FROM nodes ORDER BY capacity DESC")?;
-        
-        let node_iter = stmt.query_map([], |row| {
-            Ok(NodeFromDb {
-                public_key: row.get(0)?,
-                alias: row.get(1)?,
-                capacity: row.get(2)?,
-                first_seen: row.get(3)?,
-            })
-        })?;
+    match metrics.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for POST /refresh.
+///
+/// Has the worker fetch and store right away instead of waiting for the next
+/// tick, and answers with what changed. The fetch runs in the worker, so it
+/// never overlaps with a scheduled one.
+///
+/// Calls that come in while a cycle (asked for or scheduled) is already
+/// running don't queue another fetch. They get a 202 saying so, or with
+/// `?wait=true`, wait for that cycle and get its outcome.
+#[post("/refresh")]
+async fn refresh(req: HttpRequest, refresher: web::Data<Refresher>, config: web::Data<Config>, query: web::Query<RefreshQuery>) -> impl Responder {
+    if let Err(resp) = auth::require_admin_if_set(&req, config.admin_token.as_deref()) {
+        return *resp;
+    }
+
+    let pending = match refresher.start() {
+        Some(RefreshStart::Started(pending)) => pending,
+        Some(RefreshStart::AlreadyRunning(pending)) if query.wait.unwrap_or(false) => pending,
+        Some(RefreshStart::AlreadyRunning(_)) => {
+            return HttpResponse::Accepted().json(serde_json::json!({ "status": "already_running" }));
+        }
+        None => return HttpResponse::ServiceUnavailable().json(serde_json::json!({ "error": "The worker is shutting down" })),
+    };
+    match pending.outcome().await {
+        Some(CycleOutcome::Stored { inserted, updated }) => {
+            HttpResponse::Ok().json(serde_json::json!({ "status": "updated", "inserted": inserted, "updated": updated }))
+        }
+        Some(CycleOutcome::NotModified) => {
+            HttpResponse::Ok().json(serde_json::json!({ "status": "not_modified", "inserted": 0, "updated": 0 }))
+        }
+        Some(CycleOutcome::Failed) => {
+            HttpResponse::BadGateway().json(serde_json::json!({ "error": "Fetching from the upstream failed, see the server log" }))
+        }
+        Some(CycleOutcome::Cancelled) | None => {
+            HttpResponse::ServiceUnavailable().json(serde_json::json!({ "error": "The worker is shutting down" }))
+        }
+    }
+}
+
+/// Builds the cache report shared by /cache/stats and /admin/cache/stats.
+#[allow(clippy::too_many_arguments)]
+async fn cache_stats_report(
+    nodes_cache: &Cache<String, Vec<NodeResponse>>,
+    entities_cache: &Cache<String, Vec<EntityGroup>>,
+    stats_cache: &Cache<String, StatsResponse>,
+    count_cache: &Cache<String, NodeCountResponse>,
+    countries_cache: &Cache<String, Vec<CountryStat>>,
+    ranked_cache: &Cache<String, Vec<RankedNode>>,
+    stats: &CacheStats,
+    expiry: &cache::EndpointExpiry,
+) -> CacheStatsReport {
+    // moka updates its counts lazily, so flush pending work first to get current numbers.
+    nodes_cache.run_pending_tasks().await;
+    entities_cache.run_pending_tasks().await;
+    stats_cache.run_pending_tasks().await;
+    count_cache.run_pending_tasks().await;
+    countries_cache.run_pending_tasks().await;
+    ranked_cache.run_pending_tasks().await;
+
+    let entry = |name: &'static str, entry_count: u64, weighted_size: u64| {
+        let (hits, misses) = stats.get(name);
+        let lookups = hits + misses;
+        CacheStatsEntry {
+            name,
+            entry_count,
+            weighted_size,
+            hits,
+            misses,
+            hit_rate: (lookups > 0).then(|| hits as f64 / lookups as f64),
+            ttl_seconds: expiry.ttl_for(name).as_secs(),
+        }
+    };
+    CacheStatsReport {
+        caches: vec![
+            entry("nodes", nodes_cache.entry_count(), nodes_cache.weighted_size()),
+            entry("entities", entities_cache.entry_count(), entities_cache.weighted_size()),
+            entry("stats", stats_cache.entry_count(), stats_cache.weighted_size()),
+            entry("count", count_cache.entry_count(), count_cache.weighted_size()),
+            entry("countries", countries_cache.entry_count(), countries_cache.weighted_size()),
+            entry("ranked", ranked_cache.entry_count(), ranked_cache.weighted_size()),
+        ],
+    }
+}
+
+/// Handler for GET /admin/cache/stats.
+///
+/// Shows how each response cache is doing (size, hits, misses, hit rate and TTL),
+/// to help tune `CACHE_TTL_*`. Hit and miss counts are since startup.
+#[get("/admin/cache/stats")]
+#[allow(clippy::too_many_arguments)]
+async fn get_cache_stats(
+    req: HttpRequest,
+    nodes_cache: web::Data<Cache<String, Vec<NodeResponse>>>,
+    entities_cache: web::Data<Cache<String, Vec<EntityGroup>>>,
+    stats_cache: web::Data<Cache<String, StatsResponse>>,
+    count_cache: web::Data<Cache<String, NodeCountResponse>>,
+    countries_cache: web::Data<Cache<String, Vec<CountryStat>>>,
+    ranked_cache: web::Data<Cache<String, Vec<RankedNode>>>,
+    stats: web::Data<CacheStats>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(resp) = auth::require_admin(&req, config.admin_token.as_deref()) {
+        return *resp;
+    }
+    HttpResponse::Ok().json(
+        cache_stats_report(&nodes_cache, &entities_cache, &stats_cache, &count_cache, &countries_cache, &ranked_cache, &stats, &config.cache_expiry).await,
+    )
+}
+
+/// Handler for GET /cache/stats.
+///
+/// Same report as /admin/cache/stats, but only needs the admin token when one
+/// is set, so it can be checked on a private deployment without one.
+#[get("/cache/stats")]
+#[allow(clippy::too_many_arguments)]
+async fn get_public_cache_stats(
+    req: HttpRequest,
+    nodes_cache: web::Data<Cache<String, Vec<NodeResponse>>>,
+    entities_cache: web::Data<Cache<String, Vec<EntityGroup>>>,
+    stats_cache: web::Data<Cache<String, StatsResponse>>,
+    count_cache: web::Data<Cache<String, NodeCountResponse>>,
+    countries_cache: web::Data<Cache<String, Vec<CountryStat>>>,
+    ranked_cache: web::Data<Cache<String, Vec<RankedNode>>>,
+    stats: web::Data<CacheStats>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Err(resp) = auth::require_admin_if_set(&req, config.admin_token.as_deref()) {
+        return *resp;
+    }
+    HttpResponse::Ok().json(
+        cache_stats_report(&nodes_cache, &entities_cache, &stats_cache, &count_cache, &countries_cache, &ranked_cache, &stats, &config.cache_expiry).await,
+    )
+}
+
+/// Snapshot names end up in URLs, so keep them simple.
+fn is_valid_snapshot_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Handler for POST /admin/snapshot/{name}.
+///
+/// Copies the current `nodes` table into `snapshot_nodes` under `name`, so it can
+/// later be compared with GET /admin/diff. Names can't be reused (409).
+#[post("/admin/snapshot/{name}")]
+async fn create_snapshot(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<&'static dyn Clock>,
+    name: web::Path<String>,
+) -> impl Responder {
+    if let Err(resp) = auth::require_admin(&req, config.admin_token.as_deref()) {
+        return *resp;
+    }
+    let name = name.into_inner();
+    if !is_valid_snapshot_name(&name) {
+        return HttpResponse::BadRequest().body("Snapshot name must be 1-64 letters, digits, '-' or '_'");
+    }
 
-        let mut nodes = Vec::new();
-        for node_result in node_iter {
-            let node_db = node_result?;
-            nodes.push(NodeResponse {
-                public_key: node_db.public_key,
-                alias: node_db.alias,
-                capacity: formatters::format_capacity(node_db.capacity),
-                first_seen: formatters::format_timestamp(node_db.first_seen),
-            });
+    let created_at = clock.now().timestamp();
+    let result = web::block(move || -> Result<Option<SnapshotInfo>, DbError> {
+        let mut conn = pool.get()?;
+        let tx = conn.transaction()?;
+        let created = tx.execute("INSERT OR IGNORE INTO snapshots (name, created_at) VALUES (?1, ?2)", rusqlite::params![name, created_at])?;
+        if created == 0 {
+            return Ok(None); // Already taken.
         }
-        Ok(nodes)
+        let node_count = tx.execute(
+            "INSERT INTO snapshot_nodes (snapshot, public_key, alias, capacity, first_seen, channels, country, country_code)
+             SELECT ?1, public_key, alias, capacity, first_seen, channels, country, country_code FROM nodes",
+            [&name],
+        )?;
+        tx.commit()?;
+        Ok(Some(SnapshotInfo { name, created_at: formatters::format_timestamp(created_at), node_count }))
     })
     .await;
 
     match result {
-        Ok(Ok(nodes)) => {
-            // Put the result in the cache for next time.
-            cache.insert(cache_key.clone(), nodes.clone()).await;
-            HttpResponse::Ok().json(nodes)
+        Ok(Ok(Some(info))) => {
+            info!("[Admin] Saved snapshot '{}' with {} nodes.", info.name, info.node_count);
+            HttpResponse::Created().json(info)
         }
+        Ok(Ok(None)) => HttpResponse::Conflict().body("A snapshot with that name already exists"),
         Ok(Err(e)) => {
             error!("DB error: {}", e);
-            HttpResponse::InternalServerError().body("Error fetching nodes from database")
+            HttpResponse::InternalServerError().body("Error saving snapshot")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /admin/diff?from=a&to=b.
+///
+/// Lists the nodes that are in `to` but not `from` (added), in `from` but not
+/// `to` (removed), and in both with a different alias or capacity (changed).
+#[get("/admin/diff")]
+async fn diff_snapshots(req: HttpRequest, pool: web::Data<DbPool>, config: web::Data<Config>, query: web::Query<DiffQuery>) -> impl Responder {
+    if let Err(resp) = auth::require_admin(&req, config.admin_token.as_deref()) {
+        return *resp;
+    }
+    let DiffQuery { from, to } = query.into_inner();
+
+    let result = web::block(move || -> Result<Option<SnapshotDiff>, DbError> {
+        let conn = pool.get()?;
+        let found: i64 = conn.query_row("SELECT COUNT(*) FROM snapshots WHERE name IN (?1, ?2)", [&from, &to], |row| row.get(0))?;
+        let expected = if from == to { 1 } else { 2 };
+        if found < expected {
+            return Ok(None);
+        }
+
+        let format = NodeFormat::default();
+        // Nodes in snapshot ?1 that aren't in snapshot ?2.
+        let missing_from = "SELECT public_key, alias, capacity, first_seen, channels, country, country_code FROM snapshot_nodes a
+             WHERE a.snapshot = ?1
+               AND NOT EXISTS (SELECT 1 FROM snapshot_nodes b WHERE b.snapshot = ?2 AND b.public_key = a.public_key)
+             ORDER BY capacity DESC, public_key";
+        let added = read_nodes(&conn, missing_from, [&to, &from], &format)?;
+        let removed = read_nodes(&conn, missing_from, [&from, &to], &format)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT a.public_key, a.alias, a.capacity, a.first_seen, a.channels, a.country, a.country_code,
+                    b.alias, b.capacity, b.first_seen, b.channels, b.country, b.country_code
+             FROM snapshot_nodes a JOIN snapshot_nodes b ON b.public_key = a.public_key
+             WHERE a.snapshot = ?1 AND b.snapshot = ?2 AND (a.alias != b.alias OR a.capacity != b.capacity)
+             ORDER BY a.public_key",
+        )?;
+        let changed = stmt
+            .query_map([&from, &to], |row| {
+                let public_key: String = row.get(0)?;
+                let side = |offset: usize| -> rusqlite::Result<NodeFromDb> {
+                    Ok(NodeFromDb {
+                        public_key: public_key.clone(),
+                        alias: row.get(offset)?,
+                        capacity: row.get(offset + 1)?,
+                        first_seen: row.get(offset + 2)?,
+                        channels: row.get(offset + 3)?,
+                        country: row.get(offset + 4)?,
+                        country_code: row.get(offset + 5)?,
+                        rank: None,
+                        stale: None,
+                    })
+                };
+                Ok(NodeChange {
+                    before: to_response(side(1)?, &format),
+                    after: to_response(side(7)?, &format),
+                    public_key,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(SnapshotDiff { from, to, added, removed, changed }))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some(diff))) => HttpResponse::Ok().json(diff),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Snapshot not found"),
+        Ok(Err(e)) => {
+            error!("DB error: {}", e);
+            HttpResponse::InternalServerError().body("Error reading snapshots")
+        }
+        Err(e) => {
+            error!("Task error: {}", e);
+            HttpResponse::InternalServerError().body("Internal server error")
+        }
+    }
+}
+
+/// Handler for GET /admin/integrity.
+///
+/// Runs `PRAGMA integrity_check` and `PRAGMA foreign_key_check` and returns
+/// what they found, so suspected corruption can be looked at without shelling
+/// into the host. It reads the whole file, so it can take a while on a big DB.
+/// It uses its own read-only connection, so it doesn't hold a write lock.
+#[get("/admin/integrity")]
+async fn check_integrity(req: HttpRequest, config: web::Data<Config>) -> impl Responder {
+    if let Err(resp) = auth::require_admin(&req, config.admin_token.as_deref()) {
+        return *resp;
+    }
+    let db_path = config.database_path.clone();
+    let busy_timeout = config.db_busy_timeout;
+    info!("[Admin] Running database integrity check...");
+
+    let result = web::block(move || -> Result<IntegrityReport, rusqlite::Error> {
+        let started = std::time::Instant::now();
+        let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        conn.busy_timeout(busy_timeout)?;
+
+        let integrity_check = conn
+            .prepare("PRAGMA integrity_check")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let foreign_key_violations = conn
+            .prepare("PRAGMA foreign_key_check")?
+            .query_map([], |row| {
+                Ok(ForeignKeyViolation {
+                    table: row.get(0)?,
+                    rowid: row.get(1)?,
+                    parent: row.get(2)?,
+                    fkid: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(IntegrityReport {
+            ok: integrity_check == ["ok"] && foreign_key_violations.is_empty(),
+            integrity_check,
+            foreign_key_violations,
+            duration_ms: started.elapsed().as_millis(),
+        })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(report)) => {
+            info!("[Admin] Integrity check finished in {}ms, ok: {}", report.duration_ms, report.ok);
+            HttpResponse::Ok().json(report)
+        }
+        Ok(Err(e)) => {
+            error!("Integrity check failed: {}", e);
+            HttpResponse::InternalServerError().body("Integrity check failed")
         }
         Err(e) => {
             error!("Task error: {}", e);
@@ -74,6 +2273,9 @@ async fn get_nodes(cache: web::Data<Cache<String, Vec<NodeResponse>>>) -> impl R
     }
 }
 
+/// Longest wait between database setup retries at startup.
+const DB_INIT_MAX_BACKOFF_SECS: u64 = 30;
+
 /// This is where the app starts.
 ///
 /// It sets up everything: .env, logger, database, the background worker,
@@ -83,36 +2285,1074 @@ async fn main() -> std::io::Result<()> {
     // Create a default .env file if needed, then load it.
     env_setup::setup_env()?;
     dotenv().ok();
-    env_logger::init();
+    // Before the logger, so the file can set `RUST_LOG` and `LOG_FORMAT` too.
+    let file_loaded = config::load_file();
+    logging::init();
+
+    // Check all the settings up front, so a typo stops the app here with a
+    // clear message instead of quietly running on a default.
+    let config = match file_loaded.and_then(|()| Config::from_env()) {
+        Ok(config) => web::Data::new(config),
+        Err(e) => {
+            error!("[Main] Invalid configuration: {}", e);
+            return Err(std::io::Error::other(format!("Invalid configuration: {}", e)));
+        }
+    };
 
     // Set up the database. The app won't start if this fails.
-    let db_path = env::var("DATABASE_PATH").unwrap_or("nodes.db".to_string());
-    if let Err(e) = db::initialize_database(&db_path) {
+    // If another process is holding the lock (e.g. during a rolling restart),
+    // we retry a few times with a growing delay before giving up. Any other
+    // error won't go away by waiting, so it stops startup right away.
+    let db_path = &config.database_path;
+    let policy = RetryPolicy {
+        max_attempts: config.db_init_retries.saturating_add(1),
+        base_backoff_secs: 1,
+        max_backoff_secs: DB_INIT_MAX_BACKOFF_SECS,
+    };
+    let init = retry::retry_with_backoff("Main", policy, || async {
+        match db::initialize_database(db_path, config.db_busy_timeout, &config.db_journal_mode) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_busy() => {
+                warn!("[Main] Database is locked ({}).", e);
+                Err(Failure::Retry(e))
+            }
+            Err(e) => Err(Failure::Stop(e)),
+        }
+    })
+    .await;
+    if let Err(e) = init {
         error!("Failed to start database: {}", e);
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Database initialization failed"));
+        return Err(std::io::Error::other("Database initialization failed"));
     }
     info!("[Main] Database is ready.");
+    formatters::configure(config.format);
+    info!("[Main] Showing dates in {}.", config.format.timezone);
+
+    // One pool of connections for the API and the worker.
+    let pool = match db::build_pool(db_path, config.db_pool_size, config.db_busy_timeout, &config.db_journal_mode) {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("Failed to open the database connection pool: {}", e);
+            return Err(std::io::Error::other("Database initialization failed"));
+        }
+    };
 
     // Start the background worker.
-    worker::spawn_worker();
-    info!("[Main] Background worker started.");
+    let worker_status = Arc::new(WorkerStatus::default());
+    let metrics = Arc::new(Metrics::new());
+    // Where "now" comes from, for the worker and the handlers that stamp times.
+    let clock: &'static dyn Clock = &SystemClock;
+    let worker = worker::spawn_worker(config.worker.clone(), pool.clone(), worker_status.clone(), metrics.clone(), clock);
+    info!("[Main] Background worker started, fetching from {}.", worker.config().api_urls.join(", "));
+    metrics::spawn_data_quality_task(pool.clone(), metrics.clone(), std::time::Duration::from_secs(config.data_quality_interval_secs));
+    if let Some(url) = &config.pushgateway_url {
+        metrics::spawn_pusher(url.clone(), metrics.clone(), std::time::Duration::from_secs(config.push_interval_secs));
+    }
+    let refresher = web::Data::new(worker.refresher());
 
-    // Set up the cache. TTL is configurable via .env.
-    let port: u16 = env::var("SERVER_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(8080);
-    let ttl_secs: u64 = env::var("CACHE_TTL_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(10);
+    // Set up the cache. TTLs are configurable per endpoint via .env.
+    let port = config.server_port;
+    let compression = config.enable_compression;
+    let rate_limiter = web::Data::new(ratelimit::RateLimiter::new(config.rate_limit_per_minute, config.rate_limit_behind_proxy));
+    // Every cache below is keyed by client-chosen parameters (paging, filters,
+    // separators, weights...), and by client with `CACHE_PER_CLIENT`, so each
+    // one is bounded like `LastGoodNodes`.
     let cache: Cache<String, Vec<NodeResponse>> = Cache::builder()
-        .time_to_live(std::time::Duration::from_secs(ttl_secs))
+        .max_capacity(1_000)
+        .expire_after(config.cache_expiry.clone())
         .build();
+    let last_good = LastGoodNodes::new();
+    let entities_cache: Cache<String, Vec<EntityGroup>> = Cache::builder()
+        .max_capacity(1_000)
+        .expire_after(config.cache_expiry.clone())
+        .build();
+    let stats_cache: Cache<String, StatsResponse> = Cache::builder()
+        .max_capacity(1_000)
+        .expire_after(config.cache_expiry.clone())
+        .build();
+    let count_cache: Cache<String, NodeCountResponse> = Cache::builder()
+        .max_capacity(1_000)
+        .expire_after(config.cache_expiry.clone())
+        .build();
+    let countries_cache: Cache<String, Vec<CountryStat>> = Cache::builder()
+        .max_capacity(1_000)
+        .expire_after(config.cache_expiry.clone())
+        .build();
+    let ranked_cache: Cache<String, Vec<RankedNode>> = Cache::builder()
+        .max_capacity(1_000)
+        .expire_after(config.cache_expiry.clone())
+        .build();
+    // Shared by every worker thread, so it's wrapped once out here.
+    let cache_stats = web::Data::new(CacheStats::new(&["nodes", "entities", "stats", "count", "countries", "ranked"]));
 
     // Start the HTTP server and share the cache with all threads.
     info!("Starting server on http://0.0.0.0:{}", port);
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
+            // Innermost, so the deadline only covers the handler itself.
+            .wrap(middleware::from_fn(timeout::limit_time))
+            // Between the handler and compression, to count bytes before they're compressed.
+            .wrap(middleware::from_fn(traffic::count_uncompressed))
+            // gzip/brotli/zstd, whichever the client's Accept-Encoding prefers.
+            .wrap(middleware::Condition::new(compression, middleware::Compress::default()))
+            // Inside CORS, so preflights don't use up tokens and 429s still get CORS headers.
+            .wrap(middleware::from_fn(ratelimit::limit))
+            .wrap(cors::middleware(&config.cors_allowed_origins))
+            // Counts response bytes. Keep this the outermost middleware (the last
+            // `wrap`) so it sees the final, possibly compressed, body.
+            .wrap(middleware::from_fn(traffic::count_bytes))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(config.clone())
+            .app_data(web::Data::from(worker_status.clone()))
+            .app_data(web::Data::new(clock))
             .app_data(web::Data::new(cache.clone()))
+            .app_data(web::Data::new(last_good.clone()))
+            .app_data(web::Data::new(entities_cache.clone()))
+            .app_data(web::Data::new(stats_cache.clone()))
+            .app_data(web::Data::new(count_cache.clone()))
+            .app_data(web::Data::new(countries_cache.clone()))
+            .app_data(web::Data::new(ranked_cache.clone()))
+            .app_data(cache_stats.clone())
+            .app_data(web::Data::from(metrics.clone()))
+            .app_data(refresher.clone())
+            .app_data(rate_limiter.clone())
+            // Room for a big pubkey list or bloom filter on POST /nodes/unknown.
+            .app_data(web::JsonConfig::default().limit(4 * 1024 * 1024))
+            .service(index)
+            .service(health_check)
+            .service(get_worker_status)
+            .service(get_metrics)
+            .service(refresh)
             .service(get_nodes)
+            .service(get_top_nodes)
+            .service(get_node_count)
+            .service(get_nodes_by_country)
+            .service(get_node_changes)
+            .service(get_departed_nodes)
+            .service(get_ranked_nodes)
+            .service(search_nodes_by_pubkey)
+            .service(search_nodes)
+            // After the more specific /nodes/... routes, so it doesn't shadow them.
+            .service(get_node_by_pubkey)
+            .service(get_node_history)
+            .service(get_node_timeline)
+            .service(get_node_sparkline)
+            .service(get_node_forecast)
+            .service(get_unknown_nodes)
+            .service(get_stats)
+            .service(get_entities)
+            .service(check_integrity)
+            .service(get_cache_stats)
+            .service(get_public_cache_stats)
+            .service(create_snapshot)
+            .service(diff_snapshots)
     })
     .bind(("0.0.0.0", port))?
-    .run()
-    .await
+    .run();
+
+    // `run` finishes once actix has handled SIGINT/SIGTERM and drained its
+    // connections. Then stop the worker and wait for it, so a store in
+    // progress isn't cut off when the process exits.
+    let result = server.await;
+    info!("[Main] Server stopped. Waiting for the background worker...");
+    worker.shutdown();
+    if let Err(e) = worker.join().await {
+        error!("[Main] Background worker ended abnormally: {}", e);
+    }
+    result
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Only the `test` module: the attribute of the same name would shadow `#[test]`.
+    use actix_web::{http::StatusCode, test::{self}};
+    use db::testing::TempDb;
+
+    /// The /nodes app with everything `serve_nodes` needs, on `pool`, and
+    /// optionally a worker status and a clock. Gives back the cache stats so
+    /// tests can see hits and misses.
+    macro_rules! nodes_app {
+        ($pool:expr) => {
+            nodes_app!($pool, web::Data::new(WorkerStatus::default()))
+        };
+        ($pool:expr, $status:expr) => {
+            nodes_app!($pool, $status, &SystemClock)
+        };
+        ($pool:expr, $status:expr, $clock:expr) => {{
+            let stats = web::Data::new(CacheStats::new(&["nodes"]));
+            let cache: Cache<String, Vec<NodeResponse>> = Cache::builder().expire_after(cache::EndpointExpiry::from_env().unwrap()).build();
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new($pool))
+                    .app_data(web::Data::new(cache))
+                    .app_data(stats.clone())
+                    .app_data(web::Data::new(LastGoodNodes::new()))
+                    .app_data(web::Data::new(Metrics::new()))
+                    .app_data($status)
+                    .app_data(web::Data::new(Config::from_env().unwrap()))
+                    .app_data(web::Data::<&'static dyn Clock>::new($clock))
+                    .service(get_nodes),
+            )
+            .await;
+            (app, stats)
+        }};
+    }
+
+    /// A database with three nodes, stored out of capacity order.
+    fn seeded_db(name: &str) -> (TempDb, DbPool) {
+        let db = TempDb::new(name);
+        let pool = db.pool();
+        pool.get()
+            .unwrap()
+            .execute_batch(&format!(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen, channels, country, country_code) VALUES
+                 ('02{a}', 'Small', 1000, 1600000000, 3, NULL, NULL),
+                 ('02{b}', 'Big', 250000000, 1500000000, 40, 'France', 'FR'),
+                 ('02{c}', 'Mid', 5000000, 1700000000, 12, NULL, NULL);",
+                a = "a".repeat(64),
+                b = "b".repeat(64),
+                c = "c".repeat(64),
+            ))
+            .unwrap();
+        (db, pool)
+    }
+
+    #[actix_web::test]
+    async fn nodes_shape_and_order() {
+        let (_db, pool) = seeded_db("api-shape");
+        let (app, _) = nodes_app!(pool);
+
+        let nodes: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes").to_request()).await;
+        let nodes = nodes.as_array().unwrap();
+        assert_eq!(nodes.len(), 3);
+
+        let aliases: Vec<&str> = nodes.iter().map(|n| n["alias"].as_str().unwrap()).collect();
+        assert_eq!(aliases, ["Big", "Mid", "Small"], "biggest capacity first");
+
+        let big = nodes[0].as_object().unwrap();
+        let keys: Vec<&str> = big.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["public_key", "alias", "capacity", "first_seen", "channels", "country", "country_code"]);
+        assert_eq!(big["public_key"], format!("02{}", "b".repeat(64)));
+        assert_eq!(big["capacity"], "2.50000000");
+        assert_eq!(big["first_seen"], "2017-07-14T02:40:00Z");
+        assert_eq!(big["channels"], 40);
+        assert_eq!(big["country"], "France");
+        assert_eq!(big["country_code"], "FR");
+        assert_eq!(nodes[2]["country"], serde_json::Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn nodes_cache_miss_then_hit() {
+        let (_db, pool) = seeded_db("api-cache");
+        let (app, stats) = nodes_app!(pool.clone());
+
+        let first = test::call_and_read_body(&app, test::TestRequest::get().uri("/nodes").to_request()).await;
+        assert_eq!(stats.get("nodes"), (0, 1));
+
+        // Changed behind the cache's back, so a hit still shows the old data.
+        pool.get().unwrap().execute("DELETE FROM nodes", []).unwrap();
+        let second = test::call_and_read_body(&app, test::TestRequest::get().uri("/nodes").to_request()).await;
+        assert_eq!(stats.get("nodes"), (1, 1));
+        assert_eq!(first, second);
+
+        // A different variant is its own entry, so it misses and reads the DB.
+        let other: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes?unit=sats").to_request()).await;
+        assert_eq!(stats.get("nodes"), (1, 2));
+        assert_eq!(other, serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn nodes_default_page_and_no_limit() {
+        let db = TempDb::new("api-page-size");
+        let pool = db.pool();
+        {
+            let mut conn = pool.get().unwrap();
+            let tx = conn.transaction().unwrap();
+            for i in 0..60 {
+                tx.execute(
+                    "INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES (?1, 'n', ?2, 0)",
+                    params![format!("02{:064x}", i), i],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        let (app, _) = nodes_app!(pool);
+
+        let count = |body: serde_json::Value| body.as_array().unwrap().len();
+        for (uri, expected) in [
+            ("/nodes", 50),
+            ("/nodes?limit=10", 10),
+            ("/nodes?limit=0", 60),
+            ("/nodes?all=true", 60),
+            ("/nodes?all=true&limit=0", 60),
+            ("/nodes?limit=0&offset=55", 5),
+            ("/nodes?all=false", 50),
+        ] {
+            let body = test::call_and_read_body_json(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(count(body), expected, "{}", uri);
+        }
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes?all=true&limit=5").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn departed_nodes_newest_first_with_absence() {
+        use chrono::{TimeZone, Utc};
+
+        let db = TempDb::new("api-departed");
+        let pool = db.pool();
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen, last_seen) VALUES
+                 ('a', 'Present', 10, 0, 1000),
+                 ('b', 'LeftRecently', 20, 0, 900),
+                 ('c', 'LeftLongAgo', 30, 0, 100),
+                 ('d', 'AlsoRecent', 40, 0, 900),
+                 ('e', 'AlsoPresent', 50, 0, 1000);",
+            )
+            .unwrap();
+        let clock: &'static dyn Clock = Box::leak(Box::new(Utc.timestamp_opt(1100, 0).unwrap()));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(Config::from_env().unwrap()))
+                .app_data(web::Data::new(clock))
+                .service(get_departed_nodes),
+        )
+        .await;
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/departed").to_request()).await;
+        assert_eq!(body["last_fetch"], 1000);
+        assert_eq!(body["total"], 3);
+        let nodes = body["nodes"].as_array().unwrap();
+        let departed: Vec<(&str, i64, i64)> = nodes
+            .iter()
+            .map(|n| (n["public_key"].as_str().unwrap(), n["last_seen"].as_i64().unwrap(), n["absent_secs"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(departed, [("b", 900, 200), ("d", 900, 200), ("c", 100, 1000)]);
+        assert_eq!(nodes[0]["alias"], "LeftRecently");
+
+        let page: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/departed?limit=1&offset=1&case=camel").to_request()).await;
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["lastFetch"], 1000);
+        assert_eq!(page["nodes"][0]["publicKey"], "d");
+        assert_eq!(page["nodes"][0]["absentSecs"], 200);
+        assert_eq!(page["nodes"].as_array().unwrap().len(), 1);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes/departed?offset=-1").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn departed_nodes_on_an_empty_table() {
+        let db = TempDb::new("api-departed-empty");
+        let clock: &'static dyn Clock = &SystemClock;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(db.pool()))
+                .app_data(web::Data::new(Config::from_env().unwrap()))
+                .app_data(web::Data::new(clock))
+                .service(get_departed_nodes),
+        )
+        .await;
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/departed").to_request()).await;
+        assert_eq!(body, serde_json::json!({ "last_fetch": null, "total": 0, "nodes": [] }));
+    }
+
+    /// The /nodes/ranked app on `pool`. Gives back the cache stats too.
+    macro_rules! ranked_app {
+        ($pool:expr) => {{
+            let stats = web::Data::new(CacheStats::new(&["ranked"]));
+            let cache: Cache<String, Vec<RankedNode>> = Cache::builder().expire_after(cache::EndpointExpiry::from_env().unwrap()).build();
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new($pool))
+                    .app_data(web::Data::new(cache))
+                    .app_data(stats.clone())
+                    .app_data(web::Data::new(Config::from_env().unwrap()))
+                    .service(get_ranked_nodes),
+            )
+            .await;
+            (app, stats)
+        }};
+    }
+
+    #[actix_web::test]
+    async fn ranked_nodes_by_weighted_score() {
+        let (_db, pool) = seeded_db("api-ranked");
+        let (app, stats) = ranked_app!(pool);
+        let ranking = |body: &serde_json::Value| -> Vec<(String, f64)> {
+            body["nodes"].as_array().unwrap().iter().map(|n| (n["alias"].as_str().unwrap().to_string(), n["score"].as_f64().unwrap())).collect()
+        };
+
+        // Capacity and channels count half each by default. Big has the most of
+        // both, Small the least; Mid has 2% of the capacity range and 24% of the channels one.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/ranked").to_request()).await;
+        assert_eq!(body["weights"], serde_json::json!({ "capacity": 0.5, "channels": 0.5, "age": 0.0 }));
+        assert_eq!(body["total"], 3);
+        assert_eq!(ranking(&body), [("Big".to_string(), 1.0), ("Mid".to_string(), 0.13162), ("Small".to_string(), 0.0)]);
+        assert_eq!(body["nodes"][0]["public_key"], format!("02{}", "b".repeat(64)));
+
+        // Weights are scaled to add up to 1, so these two share a cache entry.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/ranked?w_capacity=7&w_channels=3").to_request()).await;
+        assert_eq!(body["weights"]["capacity"], 0.7);
+        let same: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/ranked?w_capacity=0.7&w_channels=0.3").to_request()).await;
+        assert_eq!(same["nodes"], body["nodes"]);
+        assert_eq!(stats.get("ranked"), (1, 2), "one hit, for the second weight pair");
+
+        // Only the weights given count.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/ranked?w_channels=1&limit=1&offset=1&case=camel").to_request()).await;
+        assert_eq!(body["total"], 3);
+        assert_eq!(ranking(&body), [("Mid".to_string(), 0.243243)]);
+        assert_eq!(body["nodes"][0]["publicKey"], format!("02{}", "c".repeat(64)));
+
+        for bad in ["w_capacity=-1", "w_capacity=0&w_channels=0", "w_age=NaN", "w_channels=inf", "w_capacity=x", "limit=-1"] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/nodes/ranked?{}", bad)).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", bad);
+        }
+    }
+
+    #[actix_web::test]
+    async fn ranked_nodes_by_age_skip_unknown_first_seen() {
+        let (_db, pool) = seeded_db("api-ranked-age");
+        pool.get()
+            .unwrap()
+            .execute("INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES ('02dd', 'Unknown', 1000, 0)", [])
+            .unwrap();
+        let (app, _) = ranked_app!(pool);
+
+        // Oldest first: Big (2017), Small (2020), Mid (2023). The placeholder
+        // first_seen = 0 scores 0 rather than the oldest, and ties go to capacity.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes/ranked?w_age=1").to_request()).await;
+        let ranking: Vec<(&str, f64)> = body["nodes"].as_array().unwrap().iter().map(|n| (n["alias"].as_str().unwrap(), n["score"].as_f64().unwrap())).collect();
+        assert_eq!(ranking, [("Big", 1.0), ("Small", 0.5), ("Mid", 0.0), ("Unknown", 0.0)]);
+    }
+
+    #[actix_web::test]
+    async fn bytes_served_before_and_after_compression() {
+        use actix_web::http::header::ACCEPT_ENCODING;
+
+        let metrics = web::Data::new(Metrics::new());
+        let app = test::init_service(
+            App::new()
+                .wrap(middleware::from_fn(traffic::count_uncompressed))
+                .wrap(middleware::Compress::default())
+                .wrap(middleware::from_fn(traffic::count_bytes))
+                .app_data(metrics.clone())
+                .service(index),
+        )
+        .await;
+
+        let plain = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(metrics.response_bytes.with_label_values(&["identity"]).get(), plain.len() as u64);
+        assert_eq!(metrics.response_uncompressed_bytes.get(), plain.len() as u64);
+
+        let gzipped = test::call_and_read_body(&app, test::TestRequest::get().uri("/").insert_header((ACCEPT_ENCODING, "gzip")).to_request()).await;
+        assert!(gzipped.len() < plain.len());
+        assert_eq!(metrics.response_bytes.with_label_values(&["gzip"]).get(), gzipped.len() as u64);
+        assert_eq!(metrics.response_uncompressed_bytes.get(), 2 * plain.len() as u64);
+        assert!(metrics.render().unwrap().contains(&format!("http_response_bytes_total{{encoding=\"gzip\"}} {}", gzipped.len())));
+    }
+
+    #[actix_web::test]
+    async fn healthz_needs_a_running_worker() {
+        let db = TempDb::new("api-healthz");
+        let pool = db.pool();
+        for (state, expected, label) in [
+            (WorkerState::Running, StatusCode::OK, "running"),
+            (WorkerState::Restarting, StatusCode::SERVICE_UNAVAILABLE, "restarting"),
+            (WorkerState::Stopped, StatusCode::SERVICE_UNAVAILABLE, "stopped"),
+        ] {
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(pool.clone()))
+                    .app_data(web::Data::new(WorkerStatus::in_state(state)))
+                    .service(health_check)
+                    .service(get_worker_status),
+            )
+            .await;
+            let resp = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+            assert_eq!(resp.status(), expected, "{}", label);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["worker_state"], label);
+            assert_eq!(body["worker_panics"], 0);
+
+            let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/worker/status").to_request()).await;
+            assert_eq!(body["state"], label);
+        }
+    }
+
+    #[actix_web::test]
+    async fn nocache_refused_unless_allowed() {
+        let (_db, pool) = seeded_db("api-nocache");
+        let (app, _) = nodes_app!(pool);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes?nocache=true").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn nodes_relative_times_from_the_clock() {
+        use chrono::{TimeZone, Utc};
+
+        let (_db, pool) = seeded_db("api-relative");
+        // Small was first seen at 1600000000, Big 100M seconds before, Mid 100M after.
+        let clock: &'static dyn Clock = Box::leak(Box::new(Utc.timestamp_opt(1_700_000_000 + 5 * 3600, 0).unwrap()));
+        let (app, _) = nodes_app!(pool, web::Data::new(WorkerStatus::default()), clock);
+
+        let nodes: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes?date_format=relative").to_request()).await;
+        let first_seen: Vec<&str> = nodes.as_array().unwrap().iter().map(|n| n["first_seen"].as_str().unwrap()).collect();
+        assert_eq!(first_seen, ["6 years ago", "5 hours ago", "3 years ago"]);
+    }
+
+    #[actix_web::test]
+    async fn nodes_stale_flag_from_the_last_fetch() {
+        use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+        use chrono::{TimeZone, Utc};
+
+        let db = TempDb::new("api-stale-flag");
+        let pool = db.pool();
+        // The last fetch is at 100000 and the default threshold is a day, so the cutoff is 13600.
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen, updated_at) VALUES
+                 ('a', 'Old', 30, 0, 13599),
+                 ('b', 'Borderline', 20, 0, 13600),
+                 ('c', 'Fresh', 10, 0, 90000);",
+            )
+            .unwrap();
+        let status = web::Data::new(WorkerStatus::fetched_at(&Utc.timestamp_opt(100_000, 0).unwrap()));
+        let (app, _) = nodes_app!(pool.clone(), status.clone());
+
+        let nodes: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes?include_stale_flag=true").to_request()).await;
+        let flags: Vec<(&str, bool)> = nodes.as_array().unwrap().iter().map(|n| (n["alias"].as_str().unwrap(), n["stale"].as_bool().unwrap())).collect();
+        assert_eq!(flags, [("Old", true), ("Borderline", false), ("Fresh", false)]);
+
+        // Streams carry it too, and it's left out unless asked for.
+        let streamed: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes?include_stale_flag=true&stream=true").to_request()).await;
+        assert_eq!(streamed, nodes);
+        let plain: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes").to_request()).await;
+        assert!(plain[0].get("stale").is_none());
+
+        // A later fetch that changed nothing still moves the cutoff, so the old tag no longer matches.
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes?include_stale_flag=true").to_request()).await;
+        let etag = resp.headers().get(ETAG).unwrap().clone();
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes?include_stale_flag=true").insert_header((IF_NONE_MATCH, etag.clone())).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        status.fetched_again_at(&Utc.timestamp_opt(200_000, 0).unwrap());
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes?include_stale_flag=true").insert_header((IF_NONE_MATCH, etag)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let nodes: serde_json::Value = test::read_body_json(resp).await;
+        assert!(nodes.as_array().unwrap().iter().all(|n| n["stale"] == true), "{}", nodes);
+
+        // Before the worker's first success, nothing counts as stale.
+        let (app, _) = nodes_app!(pool);
+        let nodes: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/nodes?include_stale_flag=true").to_request()).await;
+        assert!(nodes.as_array().unwrap().iter().all(|n| n["stale"] == false), "{}", nodes);
+    }
+
+    #[test]
+    fn downsample_evenly() {
+        let values: Vec<i64> = (0..10).collect();
+        assert_eq!(downsample(&values, 20), values);
+        assert_eq!(downsample(&values, 10), values);
+        assert_eq!(downsample(&values, 4), [0, 3, 6, 9]);
+        assert_eq!(downsample(&values, 3), [0, 5, 9]);
+        assert_eq!(downsample(&values, 2), [0, 9]);
+        assert_eq!(downsample(&values, 1), [9]);
+        assert_eq!(downsample::<i64>(&[], 5), Vec::<i64>::new());
+
+        let long: Vec<usize> = (0..1001).collect();
+        let picked = downsample(&long, 11);
+        assert_eq!(picked, [0, 100, 200, 300, 400, 500, 600, 700, 800, 900, 1000]);
+    }
+
+    #[actix_web::test]
+    async fn sparkline_from_history() {
+        let (_db, pool) = seeded_db("api-sparkline");
+        let big = format!("02{}", "b".repeat(64));
+        {
+            let conn = pool.get().unwrap();
+            for (i, capacity) in (100..200).enumerate() {
+                conn.execute(
+                    "INSERT INTO node_history (public_key, capacity, channels, recorded_at) VALUES (?1, ?2, 1, ?3)",
+                    params![big, capacity, 1_000 + i as i64],
+                )
+                .unwrap();
+            }
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(Config::from_env().unwrap()))
+                .service(get_node_sparkline),
+        )
+        .await;
+
+        let uri = |rest: &str| format!("/nodes/{}/sparkline{}", big, rest);
+        let values: Vec<i64> = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&uri("?points=5")).to_request()).await;
+        assert_eq!(values, [100, 125, 150, 174, 199]);
+        // SPARKLINE_POINTS defaults to 30.
+        let values: Vec<i64> = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&uri("")).to_request()).await;
+        assert_eq!(values.len(), 30);
+        assert_eq!((values[0], values[29]), (100, 199));
+
+        // Known, but no history yet.
+        let small = format!("/nodes/02{}/sparkline", "a".repeat(64));
+        let body = test::call_and_read_body(&app, test::TestRequest::get().uri(&small).to_request()).await;
+        assert_eq!(body, "[]");
+
+        let unknown = format!("/nodes/02{}/sparkline", "f".repeat(64));
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&unknown).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        for bad in ["?points=0", "?points=501"] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(&uri(bad)).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", bad);
+        }
+    }
+
+    #[actix_web::test]
+    async fn timeline_from_history_and_last_seen() {
+        let (_db, pool) = seeded_db("api-timeline");
+        let big = format!("02{}", "b".repeat(64));
+        {
+            let conn = pool.get().unwrap();
+            conn.execute("UPDATE nodes SET last_seen = CASE WHEN public_key = ?1 THEN 500 ELSE 900 END", [&big]).unwrap();
+            for (capacity, at) in [(1, 100), (2, 200), (3, 500)] {
+                conn.execute(
+                    "INSERT INTO node_history (public_key, capacity, channels, recorded_at) VALUES (?1, ?2, 1, ?3)",
+                    params![big, capacity, at],
+                )
+                .unwrap();
+            }
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(Config::from_env().unwrap()))
+                .service(get_node_timeline),
+        )
+        .await;
+        let uri = |rest: &str| format!("/nodes/{}/timeline{}", big, rest);
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&uri("?limit=2")).to_request()).await;
+        assert_eq!(
+            body["events"],
+            serde_json::json!([
+                { "type": "appeared", "at": 100, "capacity_sats": 1, "channels": 1 },
+                { "type": "capacity_change", "at": 200, "from_sats": 1, "to_sats": 2, "channels": 1 },
+            ])
+        );
+        assert_eq!(body["next_after"], 200);
+
+        // The last change and the departure share a time, so they stay on one page.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&uri("?after=200&limit=1")).to_request()).await;
+        assert_eq!(
+            body["events"],
+            serde_json::json!([
+                { "type": "capacity_change", "at": 500, "from_sats": 2, "to_sats": 3, "channels": 1 },
+                { "type": "departed", "at": 500 },
+            ])
+        );
+        assert_eq!(body["next_after"], serde_json::Value::Null);
+
+        // Nothing recorded, and not departed.
+        let small = format!("/nodes/02{}/timeline", "a".repeat(64));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&small).to_request()).await;
+        assert_eq!(body["events"], serde_json::json!([]));
+
+        // Not hex, an uncompressed-key prefix, and a bad limit.
+        for bad in [format!("/nodes/{}/timeline", "zz"), format!("/nodes/04{}/timeline", "b".repeat(64)), uri("?limit=0")] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(&bad).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn linear_fit_lines() {
+        let line = linear_fit(&[(0.0, 5.0), (1.0, 7.0), (2.0, 9.0), (3.0, 11.0)]).unwrap();
+        assert_eq!(line, LinearFit { slope: 2.0, intercept: 5.0, r_squared: 1.0 });
+
+        let flat = linear_fit(&[(0.0, 4.0), (10.0, 4.0), (20.0, 4.0)]).unwrap();
+        assert_eq!(flat, LinearFit { slope: 0.0, intercept: 4.0, r_squared: 1.0 });
+
+        // Noise around y = x: same slope, imperfect fit.
+        let noisy = linear_fit(&[(0.0, 1.0), (1.0, 0.0), (2.0, 3.0), (3.0, 2.0)]).unwrap();
+        assert!((noisy.slope - 0.6).abs() < 1e-9, "{:?}", noisy);
+        assert!(noisy.r_squared > 0.0 && noisy.r_squared < 1.0, "{:?}", noisy);
+
+        assert_eq!(linear_fit(&[(5.0, 1.0), (5.0, 2.0)]), None);
+    }
+
+    #[actix_web::test]
+    async fn forecast_extends_the_trend() {
+        use chrono::{TimeZone, Utc};
+        const DAY: i64 = 86_400;
+
+        let (_db, pool) = seeded_db("api-forecast");
+        let big = format!("02{}", "b".repeat(64));
+        let mid = format!("02{}", "c".repeat(64));
+        {
+            let conn = pool.get().unwrap();
+            let insert = |key: &str, day: i64, capacity: i64| {
+                conn.execute(
+                    "INSERT INTO node_history (public_key, capacity, channels, recorded_at) VALUES (?1, ?2, 1, ?3)",
+                    params![key, capacity, day * DAY],
+                )
+                .unwrap();
+            };
+            // Grows 1000 sats a day.
+            for day in 0..5 {
+                insert(&big, day, 100_000 + day * 1000);
+            }
+            // Only two entries.
+            insert(&mid, 0, 5_000_000);
+            insert(&mid, 1, 4_000_000);
+        }
+        let clock: &'static dyn Clock = Box::leak(Box::new(Utc.timestamp_opt(4 * DAY, 0).unwrap()));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Config::from_env().unwrap()))
+                .app_data(web::Data::new(clock))
+                .service(get_node_forecast),
+        )
+        .await;
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/nodes/{}/forecast?horizon_days=3", big)).to_request()).await;
+        assert_eq!(body["points_used"], 5);
+        assert_eq!(body["slope_sats_per_day"], 1000.0);
+        assert_eq!(body["r_squared"], 1.0);
+        assert!(body["caveat"].as_str().unwrap().contains("5 recorded capacity changes"));
+        let projection = body["projection"].as_array().unwrap();
+        let sats: Vec<i64> = projection.iter().map(|p| p["capacity_sats"].as_i64().unwrap()).collect();
+        assert_eq!(sats, [105_000, 106_000, 107_000]);
+        assert_eq!(projection[0]["date"], "1970-01-06T00:00:00Z");
+        assert_eq!(projection[0]["capacity"], "0.00105000");
+
+        // FORECAST_HORIZON_DAYS defaults to 30.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/nodes/{}/forecast", big)).to_request()).await;
+        assert_eq!(body["projection"].as_array().unwrap().len(), 30);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/nodes/{}/forecast", mid)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["points"], 2);
+
+        // A third entry makes it fit, and a steep decline bottoms out at zero.
+        pool.get()
+            .unwrap()
+            .execute("INSERT INTO node_history (public_key, capacity, channels, recorded_at) VALUES (?1, 3000000, 1, ?2)", params![mid, 2 * DAY])
+            .unwrap();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/nodes/{}/forecast?horizon_days=10", mid)).to_request()).await;
+        assert_eq!(body["slope_sats_per_day"], -1_000_000.0);
+        let sats: Vec<i64> = body["projection"].as_array().unwrap().iter().map(|p| p["capacity_sats"].as_i64().unwrap()).collect();
+        assert_eq!(sats[..3], [0, 0, 0]);
+
+        let unknown = format!("/nodes/02{}/forecast", "f".repeat(64));
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&unknown).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/nodes/{}/forecast?horizon_days=0", big)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn single_node_conditional_get() {
+        use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+
+        let (_db, pool) = seeded_db("api-node-etag");
+        let app = test::init_service(App::new().app_data(web::Data::new(pool.clone())).service(get_node_by_pubkey)).await;
+        let uri = format!("/nodes/02{}", "b".repeat(64));
+        let get = |tag: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&uri);
+            if let Some(tag) = tag {
+                req = req.insert_header((IF_NONE_MATCH, tag.to_string()));
+            }
+            req.to_request()
+        };
+
+        let resp = test::call_service(&app, get(None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers().get(ETAG).unwrap().to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+
+        let resp = test::call_service(&app, get(Some(&etag))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(ETAG).unwrap(), etag.as_str());
+        assert!(test::read_body(resp).await.is_empty());
+        assert_eq!(test::call_service(&app, get(Some("*"))).await.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(test::call_service(&app, get(Some("W/\"other\""))).await.status(), StatusCode::OK);
+
+        // A new capacity, as the worker stores it, changes the tag.
+        pool.get().unwrap().execute("UPDATE nodes SET capacity = capacity + 1, updated_at = 1700000000 WHERE alias = 'Big'", []).unwrap();
+        let resp = test::call_service(&app, get(Some(&etag))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let newer = resp.headers().get(ETAG).unwrap().to_str().unwrap().to_string();
+        assert_ne!(newer, etag);
+
+        // So do channels, which don't move `updated_at`.
+        pool.get().unwrap().execute("UPDATE nodes SET channels = channels + 1 WHERE alias = 'Big'", []).unwrap();
+        let resp = test::call_service(&app, get(Some(&newer))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn compressed_export_matches_plain() {
+        use std::io::Read;
+
+        let db = TempDb::new("api-export-compress");
+        let pool = db.pool();
+        {
+            // Enough rows for the stream to send several chunks.
+            let mut conn = pool.get().unwrap();
+            let tx = conn.transaction().unwrap();
+            for i in 0..2_000 {
+                tx.execute(
+                    "INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES (?1, ?2, ?3, 1600000000)",
+                    params![format!("02{:064x}", i), format!("node, \"{}\"", i), i * 1000],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        let (app, _) = nodes_app!(pool);
+
+        for format in ["json", "csv"] {
+            let plain = test::call_service(&app, test::TestRequest::get().uri(&format!("/nodes?stream=true&format={}", format)).to_request()).await;
+            assert!(plain.headers().get(CONTENT_ENCODING).is_none());
+            let plain = test::read_body(plain).await;
+            assert!(plain.len() > 100_000, "only {} bytes", plain.len());
+
+            for (compress, extension) in [("gzip", "gz"), ("zstd", "zst")] {
+                // No stream=true: asking for compression is enough.
+                let uri = format!("/nodes?format={}&compress={}", format, compress);
+                let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), compress);
+                let disposition = resp.headers().get(CONTENT_DISPOSITION).unwrap().to_str().unwrap().to_string();
+                assert_eq!(disposition, format!("attachment; filename=\"nodes.{}.{}\"", format, extension));
+
+                let body = test::read_body(resp).await;
+                assert!(body.len() < plain.len() / 3, "{} of {} bytes", body.len(), plain.len());
+                let decoded = match compress {
+                    "gzip" => {
+                        let mut decoded = Vec::new();
+                        flate2::read::GzDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+                        decoded
+                    }
+                    _ => zstd::decode_all(&body[..]).unwrap(),
+                };
+                assert!(decoded == plain, "{} {} differs from the plain export", format, compress);
+            }
+        }
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes?compress=br").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn nodes_500_when_the_database_file_is_missing() {
+        let path = std::env::temp_dir().join("lnrust-missing-dir").join("nodes.db");
+        let manager = r2d2_sqlite::SqliteConnectionManager::file(&path).with_flags(OpenFlags::SQLITE_OPEN_READ_WRITE);
+        let pool = r2d2::Pool::builder()
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(manager);
+        let (app, _) = nodes_app!(pool);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(test::read_body(resp).await, "Error fetching nodes from database");
+    }
+
+    /// The /stats app on `pool`.
+    macro_rules! stats_app {
+        ($pool:expr) => {{
+            let cache: Cache<String, StatsResponse> = Cache::builder().build();
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($pool))
+                    .app_data(web::Data::new(Config::from_env().unwrap()))
+                    .app_data(web::Data::new(cache))
+                    .app_data(web::Data::new(CacheStats::new(&["stats"])))
+                    .service(get_stats),
+            )
+            .await
+        }};
+    }
+
+    #[actix_web::test]
+    async fn stats_total_past_i64_max() {
+        let db = TempDb::new("api-stats-overflow");
+        let pool = db.pool();
+        pool.get()
+            .unwrap()
+            .execute(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES ('a', 'A', ?1, 0), ('b', 'B', 10, 0)",
+                [i64::MAX],
+            )
+            .unwrap();
+        let app = stats_app!(pool);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/stats").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let expected = i128::from(i64::MAX) + 10;
+        assert!(body.contains(&format!("\"total_capacity_sats\":{},", expected)), "{}", body);
+        assert!(body.contains(&format!("\"max_capacity_sats\":{},", i64::MAX)), "{}", body);
+        assert!(body.contains("\"node_count\":2,"), "{}", body);
+    }
+
+    #[actix_web::test]
+    async fn country_and_entity_totals_past_i64_max() {
+        let db = TempDb::new("api-group-overflow");
+        let pool = db.pool();
+        pool.get()
+            .unwrap()
+            .execute(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen, country, country_code) VALUES
+                 ('a', 'ACME-1', ?1, 0, 'France', 'FR'), ('b', 'ACME-2', 10, 0, 'France', 'FR'), ('c', 'Other', 5, 0, NULL, NULL)",
+                [i64::MAX],
+            )
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(Cache::<String, Vec<CountryStat>>::new(10)))
+                .app_data(web::Data::new(Cache::<String, Vec<EntityGroup>>::new(10)))
+                .app_data(web::Data::new(CacheStats::new(&["countries", "entities"])))
+                .app_data(web::Data::new(Config::from_env().unwrap()))
+                .service(get_nodes_by_country)
+                .service(get_entities),
+        )
+        .await;
+        let expected = i128::from(i64::MAX) + 10;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/nodes/by-country").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.starts_with("[{\"country\":\"FR\",\"country_name\":\"France\",\"node_count\":2,"), "{}", body);
+        assert!(body.contains(&format!("\"total_capacity_sats\":{}}}", expected)), "{}", body);
+        assert!(body.contains("{\"country\":\"unknown\",\"country_name\":null,\"node_count\":1,"), "{}", body);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/entities").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("\"entity\":\"ACME\",\"node_count\":2,"), "{}", body);
+        assert!(body.contains(&format!("\"total_capacity_sats\":{}}}", expected)), "{}", body);
+    }
+
+    #[test]
+    fn exact_average_digits() {
+        assert_eq!(exact_average(0, 0, 8), "0.00000000");
+        assert_eq!(exact_average(10, 4, 2), "2.50");
+        assert_eq!(exact_average(10, 3, 4), "3.3333");
+        assert_eq!(exact_average(20, 3, 4), "6.6667");
+        assert_eq!(exact_average(20, 3, 0), "7");
+        // Rounding the last place up carries into the whole part.
+        assert_eq!(exact_average(1999, 1000, 2), "2.00");
+        assert_eq!(exact_average(i128::from(i64::MAX) * 3, 3, 18), format!("{}.{}", i64::MAX, "0".repeat(18)));
+        assert_eq!(exact_average(i128::from(i64::MAX) * 2 + 1, 2, 1), format!("{}.5", i64::MAX));
+    }
+
+    #[test]
+    fn rounded_average_halves_up() {
+        assert_eq!(rounded_average(0, 0), 0);
+        assert_eq!(rounded_average(5, 2), 3);
+        assert_eq!(rounded_average(7, 3), 2);
+        assert_eq!(rounded_average(8, 3), 3);
+    }
+
+    #[actix_web::test]
+    async fn stats_exact_average_beats_float() {
+        let db = TempDb::new("api-stats-exact");
+        let pool = db.pool();
+        // Past i64::MAX, SQLite's AVG() falls back to adding in floating point.
+        pool.get()
+            .unwrap()
+            .execute(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES ('a', 'A', ?1, 0), ('b', 'B', ?1, 0), ('c', 'C', 1, 0)",
+                [i64::MAX],
+            )
+            .unwrap();
+        let app = stats_app!(pool);
+
+        let float: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats").to_request()).await;
+        let exact: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?exact=true").to_request()).await;
+
+        // (2 * i64::MAX + 1) / 3, exactly.
+        let expected: i64 = 6_148_914_691_236_517_205;
+        assert_eq!(exact["avg_capacity_sats"], expected);
+        assert_eq!(exact["avg_capacity_sats_exact"], format!("{}.00000000", expected));
+        assert!(float.get("avg_capacity_sats_exact").is_none());
+
+        let float_avg = float["avg_capacity_sats"].as_i64().unwrap();
+        assert_ne!(float_avg, expected, "the float average should be off here");
+        assert!((float_avg - expected).abs() < 10_000, "but only by a little: {}", float_avg);
+    }
+
+    #[actix_web::test]
+    async fn stats_scoped_to_a_capacity_range() {
+        let (_db, pool) = seeded_db("api-stats-range");
+        let app = stats_app!(pool.clone());
+
+        let band: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?min_capacity=5000000&max_capacity=250000000").to_request()).await;
+        assert_eq!(band["node_count"], 2);
+        assert_eq!(band["total_capacity_sats"], 255_000_000);
+        assert_eq!(band["avg_capacity_sats"], 127_500_000);
+        assert_eq!(band["min_capacity_sats"], 5_000_000);
+
+        let open_ended: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?max_capacity=4999999").to_request()).await;
+        assert_eq!(open_ended["node_count"], 1);
+        assert_eq!(open_ended["max_capacity_sats"], 1000);
+
+        // Each band has its own cache entry: the same band is a hit, a new one reads the DB.
+        pool.get().unwrap().execute("DELETE FROM nodes", []).unwrap();
+        let again: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?min_capacity=5000000&max_capacity=250000000").to_request()).await;
+        assert_eq!(again, band);
+        let other: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?min_capacity=1").to_request()).await;
+        assert_eq!(other["node_count"], 0);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/stats?min_capacity=10&max_capacity=9").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn stats_sampled_past_the_budget() {
+        let db = TempDb::new("api-stats-budget");
+        let pool = db.pool();
+        // Capacities 1..=5000, enough rows for the exact figures to take a few
+        // thousand steps.
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000)
+                 INSERT INTO nodes (public_key, alias, capacity, first_seen) SELECT printf('k%d', i), 'N', i, 0 FROM n;",
+            )
+            .unwrap();
+        let app = stats_app!(pool);
+
+        let exact: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?min_capacity=1&budget_ms=60000").to_request()).await;
+        assert_eq!(exact["approximate"], false);
+        assert_eq!(exact["median_capacity_sats"], 2500);
+        assert_eq!(exact["total_capacity_sats"], 12_502_500);
+
+        // No time at all: the first check stops the query.
+        let sampled: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats?budget_ms=0").to_request()).await;
+        assert_eq!(sampled["approximate"], true);
+        assert_eq!((&sampled["node_count"], &sampled["min_capacity_sats"], &sampled["max_capacity_sats"]), (&5000.into(), &1.into(), &5000.into()));
+        let median = sampled["median_capacity_sats"].as_i64().unwrap();
+        assert!((1500..=3500).contains(&median), "{}", sampled);
+        assert!(sampled.get("avg_capacity_sats_exact").is_none());
+
+        // Estimates aren't cached, so without a budget the exact figures come back.
+        let unbudgeted: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/stats").to_request()).await;
+        assert_eq!(unbudgeted["approximate"], false);
+        assert_eq!(unbudgeted["avg_capacity_sats"], 2501);
+    }
+}
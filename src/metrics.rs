@@ -0,0 +1,294 @@
+use log::{info, warn};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use reqwest::Client;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::db::{DbError, DbPool};
+use crate::models::INVALID_PUBKEY_SQL;
+
+// Prometheus metrics, served as text at GET /metrics.
+// Everything is registered once at startup and shared via `web::Data`.
+// Where scraping isn't possible, they can also be pushed to a Pushgateway.
+
+/// The `job` the Pushgateway files our metrics under.
+const PUSH_JOB: &str = "lightningnetwork-rust";
+
+/// The `issue` label values of `nodes_data_quality_issues`.
+pub const DATA_QUALITY_ISSUES: [&str; 4] = ["empty_alias", "zero_capacity", "unknown_first_seen", "invalid_pubkey"];
+
+pub struct Metrics {
+    registry: Registry,
+    pub nodes_requests: IntCounter,
+    pub nodes_cache_hits: IntCounter,
+    pub nodes_cache_misses: IntCounter,
+    pub fetch_successes: IntCounter,
+    pub fetch_failures: IntCounter,
+    /// Response body bytes sent, by `encoding` (the `Content-Encoding`, or "identity").
+    pub response_bytes: IntCounterVec,
+    /// Response body bytes before compression.
+    pub response_uncompressed_bytes: IntCounter,
+    /// Refreshed from the DB on every scrape, and with the data-quality gauges.
+    pub node_count: IntGauge,
+    /// Nodes with each kind of suspicious data, by `issue`. Refreshed every
+    /// `DATA_QUALITY_INTERVAL_SECONDS`.
+    pub data_quality: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        // The names are fixed, so registering can only fail on a typo. Fail loudly.
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).expect("invalid counter");
+            registry.register(Box::new(counter.clone())).expect("duplicate metric");
+            counter
+        };
+
+        let nodes_requests = counter("nodes_requests_total", "Requests to GET /nodes");
+        let nodes_cache_hits = counter("nodes_cache_hits_total", "GET /nodes requests served from the cache");
+        let nodes_cache_misses = counter("nodes_cache_misses_total", "GET /nodes requests that had to query the database");
+        let fetch_successes = counter("worker_fetch_success_total", "Worker fetches that stored data (or found it unchanged)");
+        let fetch_failures = counter("worker_fetch_failure_total", "Failed worker fetch or store attempts, retries included");
+        let response_uncompressed_bytes = counter("http_response_uncompressed_bytes_total", "Response body bytes before compression");
+
+        let response_bytes = IntCounterVec::new(
+            Opts::new("http_response_bytes_total", "Response body bytes sent, by Content-Encoding"),
+            &["encoding"],
+        )
+        .expect("invalid counter");
+        registry.register(Box::new(response_bytes.clone())).expect("duplicate metric");
+
+        let node_count = IntGauge::new("nodes_count", "Nodes currently in the database").expect("invalid gauge");
+        registry.register(Box::new(node_count.clone())).expect("duplicate metric");
+
+        let data_quality = IntGaugeVec::new(
+            Opts::new("nodes_data_quality_issues", "Nodes in the database with suspicious data, by kind of issue"),
+            &["issue"],
+        )
+        .expect("invalid gauge");
+        registry.register(Box::new(data_quality.clone())).expect("duplicate metric");
+        // Every series exists from the start, so a 0 isn't mistaken for a missing one.
+        for issue in DATA_QUALITY_ISSUES {
+            data_quality.with_label_values(&[issue]).set(0);
+        }
+
+        Metrics {
+            registry,
+            nodes_requests,
+            nodes_cache_hits,
+            nodes_cache_misses,
+            fetch_successes,
+            fetch_failures,
+            response_bytes,
+            response_uncompressed_bytes,
+            node_count,
+            data_quality,
+        }
+    }
+
+    /// Counts the nodes with each data-quality issue, plus all nodes, and sets the gauges.
+    ///
+    /// - `empty_alias`: blank alias.
+    /// - `zero_capacity`: capacity of 0 (or less).
+    /// - `unknown_first_seen`: the `first_seen = 0` sentinel the old-schema migration leaves.
+    /// - `invalid_pubkey`: fails `models::is_valid_pubkey`.
+    pub fn update_data_quality(&self, pool: &DbPool) -> Result<(), DbError> {
+        let conn = pool.get()?;
+        // SUM() over no rows is NULL, hence the TOTAL()s.
+        let counts: [i64; 5] = conn.query_row(
+            &format!(
+                "SELECT COUNT(*),
+                        CAST(TOTAL(TRIM(alias) = '') AS INTEGER),
+                        CAST(TOTAL(capacity <= 0) AS INTEGER),
+                        CAST(TOTAL(first_seen = 0) AS INTEGER),
+                        CAST(TOTAL({}) AS INTEGER)
+                 FROM nodes",
+                INVALID_PUBKEY_SQL
+            ),
+            [],
+            |row| Ok([row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?]),
+        )?;
+        self.node_count.set(counts[0]);
+        for (issue, count) in DATA_QUALITY_ISSUES.iter().zip(&counts[1..]) {
+            self.data_quality.with_label_values(&[issue]).set(*count);
+        }
+        Ok(())
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+}
+
+/// Refreshes the data-quality gauges every `interval`, starting right away.
+/// Failures are logged, and the gauges keep their last values.
+pub fn spawn_data_quality_task(pool: DbPool, metrics: Arc<Metrics>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let (pool, metrics) = (pool.clone(), metrics.clone());
+            match tokio::task::spawn_blocking(move || metrics.update_data_quality(&pool)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("[Metrics] Couldn't check data quality: {}", e),
+                Err(e) => warn!("[Metrics] Data quality task failed: {}", e),
+            }
+        }
+    })
+}
+
+/// POSTs everything to the Pushgateway at `base_url`, under our job.
+pub async fn push(client: &Client, base_url: &str, metrics: &Metrics) -> Result<(), String> {
+    let body = metrics.render().map_err(|e| format!("couldn't encode metrics: {}", e))?;
+    let url = format!("{}/metrics/job/{}", base_url.trim_end_matches('/'), PUSH_JOB);
+    let response = client
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| format!("couldn't reach {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", url, response.status()));
+    }
+    Ok(())
+}
+
+/// Pushes to the Pushgateway every `interval`. A failed push is logged and
+/// the next one tries again; nothing else is affected.
+pub fn spawn_pusher(base_url: String, metrics: Arc<Metrics>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    info!("[Metrics] Pushing to {} every {}s.", base_url, interval.as_secs());
+    tokio::spawn(async move {
+        let client = Client::builder()
+            .timeout(interval.min(Duration::from_secs(30)))
+            .build()
+            .expect("Failed to build reqwest client");
+        let mut ticker = tokio::time::interval(interval);
+        let mut failing = false;
+        loop {
+            ticker.tick().await;
+            match push(&client, &base_url, &metrics).await {
+                Ok(()) if failing => {
+                    info!("[Metrics] Pushing works again.");
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    warn!("[Metrics] Push failed: {}", e);
+                    failing = true;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing::TempDb;
+
+    fn issues(metrics: &Metrics) -> Vec<i64> {
+        DATA_QUALITY_ISSUES.iter().map(|issue| metrics.data_quality.with_label_values(&[issue]).get()).collect()
+    }
+
+    #[test]
+    fn counts_data_quality_issues() {
+        let db = TempDb::new("metrics-quality");
+        let pool = db.pool();
+        let metrics = Metrics::new();
+        assert_eq!(issues(&metrics), [0, 0, 0, 0]);
+
+        metrics.update_data_quality(&pool).unwrap();
+        assert_eq!(issues(&metrics), [0, 0, 0, 0], "an empty table has no issues");
+
+        pool.get()
+            .unwrap()
+            .execute_batch(&format!(
+                "INSERT INTO nodes (public_key, alias, capacity, first_seen) VALUES
+                 ('02{good}', 'Fine', 1000, 1600000000),
+                 ('02{blank}', '  ', 0, 1600000000),
+                 ('02{old}', 'Old', 5000, 0),
+                 ('02{upper}', 'Upper', 5000, 1600000000),
+                 ('02zz{short}', '', 5000, 1600000000),
+                 ('04{good}', 'Uncompressed', 5000, 1600000000),
+                 ('not-a-key', 'Bad', 5000, 0);",
+                good = "a".repeat(64),
+                blank = "b".repeat(64),
+                old = "c".repeat(64),
+                upper = "D".repeat(64),
+                short = "e".repeat(10),
+            ))
+            .unwrap();
+        metrics.update_data_quality(&pool).unwrap();
+        // empty_alias, zero_capacity, unknown_first_seen, invalid_pubkey
+        // Uppercase hex and an 04 prefix count as invalid, like in the worker and the API.
+        assert_eq!(issues(&metrics), [2, 1, 2, 4]);
+        assert_eq!(metrics.node_count.get(), 7);
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains("nodes_data_quality_issues{issue=\"empty_alias\"} 2"), "{}", text);
+        assert!(text.contains("nodes_data_quality_issues{issue=\"invalid_pubkey\"} 4"), "{}", text);
+    }
+
+    /// Accepts one request on a local port and answers it with `status`.
+    /// Gives back the base URL and the raw request as it arrived.
+    async fn one_shot_server(status: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Headers, then as much body as Content-Length says.
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length: usize = text[..end]
+                        .lines()
+                        .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length || n == 0 {
+                        break;
+                    }
+                }
+            }
+            socket.write_all(format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status).as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn pushes_the_rendered_metrics() {
+        let metrics = Metrics::new();
+        metrics.nodes_requests.inc_by(7);
+        let (url, server) = one_shot_server("200 OK").await;
+
+        push(&Client::new(), &url, &metrics).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /metrics/job/lightningnetwork-rust HTTP/1.1"), "{}", request);
+        assert!(request.to_lowercase().contains("content-type: text/plain; version=0.0.4"), "{}", request);
+        assert!(request.contains("nodes_requests_total 7"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn failed_pushes_are_errors() {
+        let metrics = Metrics::new();
+        let (url, server) = one_shot_server("500 Internal Server Error").await;
+        let err = push(&Client::new(), &url, &metrics).await.unwrap_err();
+        assert!(err.contains("500"), "{}", err);
+        server.await.unwrap();
+
+        // Nothing listening at all.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(push(&Client::new(), &url, &metrics).await.is_err());
+    }
+}
@@ -1,14 +1,44 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use crate::worker::WorkerState;
 
 // Just a home for the data structures we use in the app.
 
+/// Whether `key` is a compressed public key as we store them: 66 lowercase hex
+/// characters (33 bytes), starting with 02 or 03. The worker drops upstream
+/// nodes that fail this, and the API turns such keys away with a 400 (after
+/// lowercasing them).
+pub fn is_valid_pubkey(key: &str) -> bool {
+    key.len() == 66
+        && (key.starts_with("02") || key.starts_with("03"))
+        && key.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The SQL version of `is_valid_pubkey`, true for rows whose `public_key` fails it.
+pub const INVALID_PUBKEY_SQL: &str =
+    "(length(public_key) != 66 OR substr(public_key, 1, 2) NOT IN ('02', '03') OR public_key GLOB '*[^0-9a-f]*')";
+
 /// How a node is represented in our API response (GET /nodes).
 #[derive(Serialize, Clone)]
 pub struct NodeResponse {
     pub public_key: String,
     pub alias: String,
     pub capacity: String,
-    pub first_seen: String,
+    /// A string, or a number with `?date_format=unix`.
+    pub first_seen: serde_json::Value,
+    pub channels: i64,
+    /// Country name in English, `null` when the upstream doesn't know it (e.g. Tor-only nodes).
+    pub country: Option<String>,
+    /// ISO 3166-1 alpha-2 code, like "FR".
+    pub country_code: Option<String>,
+    /// Position by capacity (1 = largest). Only set when `?include_rank=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<i64>,
+    /// "whale", "large", "medium" or "small". Only set when `?include_tier=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity_tier: Option<String>,
+    /// Whether the node's data hasn't changed in a while. Only set when `?include_stale_flag=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<bool>,
 }
 
 /// How a node is represented when we read it from the database,
@@ -18,4 +48,423 @@ pub struct NodeFromDb {
     pub alias: String,
     pub capacity: i64,
     pub first_seen: i64,
-} 
\ No newline at end of file
+    pub channels: i64,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
+    pub rank: Option<i64>,
+    pub stale: Option<bool>,
+}
+
+/// Query parameters accepted by GET /nodes.
+#[derive(Deserialize)]
+pub struct NodesQuery {
+    /// `time_format` is the old name, still accepted.
+    #[serde(alias = "time_format")]
+    pub date_format: Option<String>,
+    pub unit: Option<String>,
+    pub include_rank: Option<bool>,
+    pub include_tier: Option<bool>,
+    pub include_stale_flag: Option<bool>,
+    pub case: Option<String>,
+    pub format: Option<String>,
+    pub stream: Option<bool>,
+    pub compress: Option<String>,
+    pub nocache: Option<bool>,
+    pub fields: Option<String>,
+    pub min_share: Option<f64>,
+    pub min_capacity: Option<i64>,
+    pub max_capacity: Option<i64>,
+    pub limit: Option<i64>,
+    pub all: Option<bool>,
+    pub offset: Option<i64>,
+    pub sort_by: Option<String>,
+    pub order: Option<String>,
+}
+
+/// Query parameters for GET /nodes/count: the same filters as /nodes.
+#[derive(Deserialize)]
+pub struct NodeCountQuery {
+    pub min_share: Option<f64>,
+    pub min_capacity: Option<i64>,
+    pub max_capacity: Option<i64>,
+}
+
+/// Result of GET /nodes/count.
+#[derive(Serialize, Clone)]
+pub struct NodeCountResponse {
+    pub count: i64,
+}
+
+/// Query parameters for GET /nodes/search/pubkey.
+#[derive(Deserialize)]
+pub struct PubkeyPrefixQuery {
+    pub prefix: String,
+    pub case: Option<String>,
+}
+
+/// Result of GET /nodes/{public_key}/history, oldest entry first.
+#[derive(Serialize)]
+pub struct NodeHistory {
+    pub public_key: String,
+    pub history: Vec<HistoryEntry>,
+}
+
+/// A node's capacity and channel count as recorded at one point in time.
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    pub capacity: String,
+    pub capacity_sats: i64,
+    pub channels: i64,
+    pub recorded_at: String,
+}
+
+/// Query parameters for GET /nodes/{public_key}/timeline.
+#[derive(Deserialize)]
+pub struct TimelineQuery {
+    /// Only events after this Unix time. Pass the previous page's `next_after`.
+    pub after: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// One thing that happened to a node. `at` is Unix seconds.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    /// Its first history entry.
+    Appeared { at: i64, capacity_sats: i64, channels: i64 },
+    CapacityChange { at: i64, from_sats: i64, to_sats: i64, channels: i64 },
+    /// It wasn't in the last successful fetch. `at` is when it was last seen.
+    Departed { at: i64 },
+}
+
+impl TimelineEvent {
+    pub fn at(&self) -> i64 {
+        match self {
+            TimelineEvent::Appeared { at, .. } | TimelineEvent::CapacityChange { at, .. } | TimelineEvent::Departed { at } => *at,
+        }
+    }
+}
+
+/// Result of GET /nodes/{public_key}/timeline, oldest event first.
+#[derive(Serialize)]
+pub struct NodeTimeline {
+    pub public_key: String,
+    pub events: Vec<TimelineEvent>,
+    /// Where the next page starts (`?after=`), `null` on the last page.
+    pub next_after: Option<i64>,
+}
+
+/// Query parameters for GET /nodes/{public_key}/sparkline.
+#[derive(Deserialize)]
+pub struct SparklineQuery {
+    /// Most values to return. Defaults to `SPARKLINE_POINTS`.
+    pub points: Option<usize>,
+}
+
+/// Query parameters for GET /nodes/{public_key}/forecast.
+#[derive(Deserialize)]
+pub struct ForecastQuery {
+    /// Days to project. Defaults to `FORECAST_HORIZON_DAYS`.
+    pub horizon_days: Option<u32>,
+}
+
+/// One projected day of a forecast.
+#[derive(Serialize)]
+pub struct ForecastPoint {
+    pub date: String,
+    pub capacity: String,
+    pub capacity_sats: i64,
+}
+
+/// Result of GET /nodes/{public_key}/forecast.
+#[derive(Serialize)]
+pub struct NodeForecast {
+    pub public_key: String,
+    /// History entries the line was fitted to.
+    pub points_used: usize,
+    /// Fitted change in capacity per day. Negative when the node is shrinking.
+    pub slope_sats_per_day: f64,
+    /// How well the line fits the history, from 0 (not at all) to 1 (exactly).
+    pub r_squared: f64,
+    /// One value per day, starting the day after now.
+    pub projection: Vec<ForecastPoint>,
+    pub caveat: String,
+}
+
+/// Query parameters for GET /nodes/search.
+#[derive(Deserialize)]
+pub struct AliasSearchQuery {
+    pub q: String,
+    pub case: Option<String>,
+}
+
+/// Query parameters for POST /refresh.
+#[derive(Deserialize)]
+pub struct RefreshQuery {
+    /// When a refresh is already running, wait for it instead of answering 202.
+    pub wait: Option<bool>,
+}
+
+/// Query parameters for GET /nodes/changes.
+#[derive(Deserialize)]
+pub struct NodeChangesQuery {
+    /// Unix time. Nodes that changed at or after it are returned.
+    pub since: i64,
+    pub case: Option<String>,
+}
+
+/// Result of GET /nodes/changes. Pass `until` as the next `since`.
+#[derive(Serialize)]
+pub struct NodeChangesResponse {
+    pub since: i64,
+    pub until: i64,
+    pub nodes: Vec<NodeResponse>,
+}
+
+/// Query parameters for GET /nodes/departed.
+#[derive(Deserialize)]
+pub struct DepartedNodesQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub case: Option<String>,
+}
+
+/// A node that's in the database but wasn't in the last successful fetch.
+#[derive(Serialize)]
+pub struct DepartedNode {
+    #[serde(flatten)]
+    pub node: NodeResponse,
+    /// Unix time of the last fetch that had this node.
+    pub last_seen: i64,
+    /// Seconds since `last_seen`.
+    pub absent_secs: i64,
+}
+
+/// Result of GET /nodes/departed.
+#[derive(Serialize)]
+pub struct DepartedNodesResponse {
+    /// Unix time of the last successful fetch, `null` before there's been one.
+    pub last_fetch: Option<i64>,
+    /// Departed nodes in all, across every page.
+    pub total: i64,
+    pub nodes: Vec<DepartedNode>,
+}
+
+/// Query parameters for GET /nodes/ranked.
+#[derive(Deserialize)]
+pub struct RankedNodesQuery {
+    pub w_capacity: Option<f64>,
+    pub w_channels: Option<f64>,
+    pub w_age: Option<f64>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub case: Option<String>,
+}
+
+/// How much each component counts towards a node's score, scaled to add up to 1.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct RankingWeights {
+    pub capacity: f64,
+    pub channels: f64,
+    pub age: f64,
+}
+
+/// A node with its composite score (GET /nodes/ranked).
+#[derive(Serialize, Clone)]
+pub struct RankedNode {
+    #[serde(flatten)]
+    pub node: NodeResponse,
+    /// From 0 to 1: the weighted average of the node's normalized components.
+    pub score: f64,
+}
+
+/// Result of GET /nodes/ranked.
+#[derive(Serialize)]
+pub struct RankedNodesResponse {
+    pub weights: RankingWeights,
+    /// Ranked nodes in all, across every page.
+    pub total: usize,
+    pub nodes: Vec<RankedNode>,
+}
+
+/// One group of nodes that look like they belong to the same operator,
+/// based on a shared alias prefix (GET /entities).
+#[derive(Serialize, Clone)]
+pub struct EntityGroup {
+    pub entity: String,
+    pub node_count: u64,
+    pub total_capacity: String,
+    /// Can go past i64::MAX, like on /stats.
+    pub total_capacity_sats: i128,
+}
+
+/// Query parameters for GET /entities.
+#[derive(Deserialize)]
+pub struct EntitiesQuery {
+    pub separator: Option<String>,
+    pub depth: Option<usize>,
+    pub min_nodes: Option<u64>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+/// Query parameters for GET /stats.
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// Work the average out from the exact total instead of SQLite's float AVG().
+    pub exact: Option<bool>,
+    /// Capacity band in sats, both ends inclusive, like on /nodes.
+    pub min_capacity: Option<i64>,
+    pub max_capacity: Option<i64>,
+    /// Milliseconds the exact figures may take before sampled estimates are
+    /// returned instead.
+    pub budget_ms: Option<u64>,
+}
+
+/// Result of GET /stats. Capacities come formatted in BTC plus the raw sats.
+/// Everything is zero when the table is empty.
+#[derive(Serialize, Clone)]
+pub struct StatsResponse {
+    pub node_count: i64,
+    pub total_capacity: String,
+    /// Can go past i64::MAX, so clients should parse it as a big integer.
+    pub total_capacity_sats: i128,
+    pub avg_capacity: String,
+    pub avg_capacity_sats: i64,
+    /// The average in sats as a decimal string, to `STATS_AVG_PRECISION` places.
+    /// Only set with `?exact=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_capacity_sats_exact: Option<String>,
+    pub max_capacity: String,
+    pub max_capacity_sats: i64,
+    pub min_capacity: String,
+    pub min_capacity_sats: i64,
+    /// The middle capacity (the lower of the two middle ones for an even count).
+    pub median_capacity: String,
+    pub median_capacity_sats: i64,
+    /// Set when `?budget_ms=` ran out and the total, average and median are
+    /// estimated from a sample of nodes. The count, max and min are always exact.
+    pub approximate: bool,
+}
+
+/// Result of GET /worker/status. Times are Unix seconds; fields the worker
+/// hasn't got to yet are `null`.
+#[derive(Serialize)]
+pub struct WorkerStatusResponse {
+    pub state: WorkerState,
+    /// Times the worker's loop has panicked (and been restarted) since startup.
+    pub panics: u32,
+    pub has_fetched: bool,
+    pub last_success_unix: Option<i64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub last_fetch_duration_ms: Option<u64>,
+}
+
+/// One row of GET /nodes/by-country.
+#[derive(Serialize, Clone)]
+pub struct CountryStat {
+    /// ISO 3166-1 alpha-2 code, or "unknown" for nodes without a location.
+    pub country: String,
+    /// English name, when the upstream gave one.
+    pub country_name: Option<String>,
+    pub node_count: i64,
+    pub total_capacity: String,
+    /// Can go past i64::MAX, like on /stats.
+    pub total_capacity_sats: i128,
+}
+
+/// One entry in the API index served at GET /.
+#[derive(Serialize)]
+pub struct EndpointInfo {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub description: &'static str,
+}
+
+/// Request body for POST /nodes/unknown. Send either a list of pubkeys
+/// or a bloom filter of them (see `bloom.rs` for the filter layout).
+#[derive(Deserialize)]
+pub struct KnownNodesRequest {
+    pub pubkeys: Option<Vec<String>>,
+    pub bloom: Option<BloomFilterSpec>,
+}
+
+/// A client-built bloom filter: the bit array as hex, plus the number of hashes.
+#[derive(Deserialize)]
+pub struct BloomFilterSpec {
+    pub bits: String,
+    pub hashes: u32,
+}
+
+/// Result of GET /admin/integrity.
+#[derive(Serialize)]
+pub struct IntegrityReport {
+    pub ok: bool,
+    /// Output of `PRAGMA integrity_check`. Just `["ok"]` when all is well.
+    pub integrity_check: Vec<String>,
+    pub foreign_key_violations: Vec<ForeignKeyViolation>,
+    pub duration_ms: u128,
+}
+
+/// One row of `PRAGMA foreign_key_check`.
+#[derive(Serialize)]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub rowid: Option<i64>,
+    pub parent: String,
+    pub fkid: i64,
+}
+
+/// Result of GET /admin/cache/stats.
+#[derive(Serialize)]
+pub struct CacheStatsReport {
+    pub caches: Vec<CacheStatsEntry>,
+}
+
+/// Numbers for one endpoint's cache.
+#[derive(Serialize)]
+pub struct CacheStatsEntry {
+    pub name: &'static str,
+    pub entry_count: u64,
+    /// moka's size estimate. Without a weigher this is the same as `entry_count`.
+    pub weighted_size: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Hits over lookups, from 0 to 1. `null` before the first lookup.
+    pub hit_rate: Option<f64>,
+    pub ttl_seconds: u64,
+}
+
+/// Result of POST /admin/snapshot/{name}.
+#[derive(Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created_at: String,
+    pub node_count: usize,
+}
+
+/// Query parameters accepted by GET /admin/diff.
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    pub from: String,
+    pub to: String,
+}
+
+/// Result of GET /admin/diff: how the `to` snapshot differs from `from`.
+#[derive(Serialize)]
+pub struct SnapshotDiff {
+    pub from: String,
+    pub to: String,
+    pub added: Vec<NodeResponse>,
+    pub removed: Vec<NodeResponse>,
+    pub changed: Vec<NodeChange>,
+}
+
+/// A node whose alias or capacity differs between two snapshots.
+#[derive(Serialize)]
+pub struct NodeChange {
+    pub public_key: String,
+    pub before: NodeResponse,
+    pub after: NodeResponse,
+}
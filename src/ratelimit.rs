@@ -0,0 +1,105 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use log::warn;
+use moka::future::Cache;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Per-IP rate limiting, so one scraper can't hog the API.
+
+/// Paths that skip the limit: health probes and metrics scrapes poll on a
+/// schedule and shouldn't get locked out by (or count against) API traffic.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/metrics"];
+
+/// A token bucket: holds up to `per_minute` tokens, refills continuously at
+/// `per_minute` per minute, and each request takes one.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Shared limiter state, one bucket per client IP.
+pub struct RateLimiter {
+    per_minute: u32,
+    behind_proxy: bool,
+    buckets: Cache<String, Arc<Mutex<Bucket>>>,
+}
+
+impl RateLimiter {
+    /// `per_minute` of 0 turns limiting off. `behind_proxy` keys on `X-Forwarded-For`.
+    pub fn new(per_minute: u32, behind_proxy: bool) -> Self {
+        RateLimiter {
+            per_minute,
+            behind_proxy,
+            // An idle bucket is full again after a minute, which is the same as
+            // having no bucket, so it can go.
+            buckets: Cache::builder().max_capacity(100_000).time_to_idle(Duration::from_secs(60)).build(),
+        }
+    }
+
+    /// The client's IP. Only trusts `X-Forwarded-For` when we're told we're
+    /// behind a proxy, since anyone can send that header.
+    fn client_ip(&self, req: &ServiceRequest) -> Option<String> {
+        if self.behind_proxy {
+            let forwarded = req
+                .headers()
+                .get("X-Forwarded-For")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.split(',').next())
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty());
+            if let Some(ip) = forwarded {
+                return Some(ip.to_string());
+            }
+        }
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    }
+
+    /// Takes a token for `ip`. On failure, returns how long until one is free.
+    async fn acquire(&self, ip: String) -> Result<(), Duration> {
+        let capacity = self.per_minute as f64;
+        let bucket = self
+            .buckets
+            .get_with(ip, async { Arc::new(Mutex::new(Bucket { tokens: capacity, updated: Instant::now() })) })
+            .await;
+
+        let per_sec = capacity / 60.0;
+        let mut bucket = bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// Middleware that answers 429 with a `Retry-After` header once a client IP
+/// goes over `RATE_LIMIT_PER_MINUTE`. Needs a `web::Data<RateLimiter>`.
+pub async fn limit<B: MessageBody + 'static>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+    let ip = match &limiter {
+        Some(limiter) if limiter.per_minute > 0 && !EXEMPT_PATHS.contains(&req.path()) => limiter.client_ip(&req),
+        _ => None,
+    };
+
+    if let (Some(limiter), Some(ip)) = (limiter, ip)
+        && let Err(wait) = limiter.acquire(ip.clone()).await
+    {
+        // Round up, so a client that waits exactly this long gets through.
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        warn!("[RateLimit] {} is over the limit, retry in {}s.", ip, retry_after);
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, retry_after.to_string()))
+            .json(serde_json::json!({ "error": "Too many requests" }));
+        return Ok(req.into_response(response));
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
@@ -0,0 +1,131 @@
+use log::{info, warn};
+use std::future::Future;
+use std::time::Duration;
+
+// Retrying with a growing, jittered delay. Used by the worker's fetches and
+// the webhook.
+
+/// How often to try and how long to wait in between.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Tries in total, the first one included.
+    pub max_attempts: u32,
+    /// Wait after the first failed try. Doubles after each one.
+    pub base_backoff_secs: u64,
+    /// Upper bound on any single wait, jitter included.
+    pub max_backoff_secs: u64,
+}
+
+/// Why an attempt failed, as far as retrying goes.
+pub enum Failure<E> {
+    /// Might clear up on its own (a timeout, a 503...), so try again.
+    Retry(E),
+    /// Won't get better by retrying (a 404, bad credentials...), so give up now.
+    Stop(E),
+}
+
+/// Runs `op` until it succeeds, at most `policy.max_attempts` times, sleeping
+/// a jittered and doubling backoff between tries. Gives back the last error if
+/// every try failed or one said to stop. `name` prefixes the log lines.
+///
+/// Dropping the returned future (on shutdown, say) cancels a wait in progress.
+pub async fn retry_with_backoff<T, E, F, Fut>(name: &str, policy: RetryPolicy, mut op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Failure<E>>>,
+{
+    let mut attempts = 0;
+    let mut backoff = policy.base_backoff_secs;
+
+    loop {
+        let err = match op().await {
+            Ok(value) => return Ok(value),
+            Err(Failure::Stop(err)) => return Err(err),
+            Err(Failure::Retry(err)) => err,
+        };
+
+        attempts += 1;
+        if attempts >= policy.max_attempts {
+            warn!("[{}] Max retries reached.", name);
+            return Err(err);
+        }
+
+        let delay = jittered(backoff, policy.max_backoff_secs);
+        info!("[{}] Retrying in {:.1}s...", name, delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+        backoff = backoff.saturating_mul(2); // Double the wait time for next retry.
+    }
+}
+
+/// Scales a backoff by a random factor between 0.5 and 1.5, so instances that
+/// failed together don't all retry at the same moment. Capped at `max_secs`.
+fn jittered(backoff_secs: u64, max_secs: u64) -> Duration {
+    let secs = backoff_secs as f64 * rand::random_range(0.5..1.5);
+    Duration::from_secs_f64(secs.min(max_secs as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use tokio::time::Instant;
+
+    const POLICY: RetryPolicy = RetryPolicy { max_attempts: 4, base_backoff_secs: 1, max_backoff_secs: 60 };
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_up_to_max_attempts() {
+        let attempts = Cell::new(0);
+        let result: Result<(), &str> = retry_with_backoff("Test", POLICY, || async {
+            attempts.set(attempts.get() + 1);
+            Err(Failure::Retry("down"))
+        })
+        .await;
+        assert_eq!(result, Err("down"));
+        assert_eq!(attempts.get(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_after_one_attempt() {
+        let attempts = Cell::new(0);
+        let started = Instant::now();
+        let result: Result<(), &str> = retry_with_backoff("Test", POLICY, || async {
+            attempts.set(attempts.get() + 1);
+            Err(Failure::Stop("not found"))
+        })
+        .await;
+        assert_eq!(result, Err("not found"));
+        assert_eq!(attempts.get(), 1);
+        assert_eq!(started.elapsed(), Duration::ZERO, "a stop shouldn't wait");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn returns_the_first_success() {
+        let attempts = Cell::new(0);
+        let result: Result<u32, &str> = retry_with_backoff("Test", POLICY, || async {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 { Err(Failure::Retry("busy")) } else { Ok(attempts.get()) }
+        })
+        .await;
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_are_capped_at_max_backoff() {
+        let policy = RetryPolicy { max_attempts: 6, base_backoff_secs: 10, max_backoff_secs: 15 };
+        let times = RefCell::new(Vec::new());
+        let _: Result<(), ()> = retry_with_backoff("Test", policy, || async {
+            times.borrow_mut().push(Instant::now());
+            Err(Failure::Retry(()))
+        })
+        .await;
+
+        let times = times.into_inner();
+        assert_eq!(times.len(), 6);
+        let waits: Vec<Duration> = times.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        let cap = Duration::from_secs(15);
+        // The first wait is 5-15s after jitter, the rest would be over the cap without it.
+        assert!(waits[0] >= Duration::from_secs(5) && waits[0] <= cap, "first wait was {:?}", waits[0]);
+        assert!(waits.iter().all(|wait| *wait <= cap), "waits were {:?}", waits);
+        assert!(waits[2..].iter().all(|wait| *wait == cap), "waits were {:?}", waits);
+    }
+}
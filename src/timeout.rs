@@ -0,0 +1,37 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use log::warn;
+use std::time::Duration;
+use crate::config::Config;
+
+// Server-side deadline for handling a request, so a slow query on a cache
+// miss can't keep a client waiting forever.
+
+/// Middleware that answers 503 if the handler hasn't produced a response
+/// within `REQUEST_TIMEOUT_SECONDS`. Needs a `web::Data<Config>`.
+///
+/// Dropping the handler stops it at its next `.await`. A query already running
+/// inside `web::block` can't be stopped from here: it finishes on the blocking
+/// pool and its result is thrown away, but the client has its answer by then.
+/// Only the time to the response head counts, so long streamed bodies are fine.
+pub async fn limit_time<B: MessageBody + 'static>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let secs = req.app_data::<web::Data<Config>>().map(|c| c.request_timeout_secs).unwrap_or(0);
+    if secs == 0 {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    // Routing needs the only handle to the request, so keep a label instead of a clone.
+    let label = format!("{} {}", req.method(), req.path());
+    match tokio::time::timeout(Duration::from_secs(secs), next.call(req)).await {
+        Ok(res) => Ok(res?.map_into_boxed_body()),
+        Err(_) => {
+            warn!("[Timeout] {} took longer than {}s, answered 503.", label, secs);
+            // Sent as an error response, which the outer middleware (CORS) still decorates.
+            let response = HttpResponse::ServiceUnavailable().json(serde_json::json!({ "error": "Request timed out" }));
+            Err(InternalError::from_response("Request timed out", response).into())
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_ENCODING;
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpMessage;
+use log::info;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use crate::metrics::Metrics;
+
+// Counts how many bytes we actually send for each response, and how many they
+// were before compression. Totals go to /metrics.
+
+/// A response's body size before compression, filled in by `count_uncompressed`
+/// and read by `count_bytes` once the response is done.
+#[derive(Clone, Default)]
+struct UncompressedBytes(Arc<AtomicU64>);
+
+/// Middleware that counts the bytes of every response body as it is sent.
+///
+/// It counts what goes on the wire, so it has to wrap any compression
+/// middleware (i.e. be registered after it with `.wrap()`) to see compressed sizes.
+pub async fn count_bytes<B: MessageBody + 'static>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let label = format!("{} {}", req.method(), req.path());
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    let uncompressed = UncompressedBytes::default();
+    req.extensions_mut().insert(uncompressed.clone());
+    let res = next.call(req).await?;
+    let encoding = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("identity")
+        .to_string();
+
+    Ok(res.map_body(|_, body| {
+        CountingBody::new(body.boxed(), move |bytes| {
+            let before = uncompressed.0.load(Ordering::Relaxed);
+            if let Some(metrics) = &metrics {
+                metrics.response_bytes.with_label_values(&[&encoding]).inc_by(bytes);
+            }
+            if encoding == "identity" {
+                info!("[Traffic] {} sent {} bytes.", label, bytes);
+            } else {
+                info!("[Traffic] {} sent {} bytes ({}, {} before compression).", label, bytes, encoding, before);
+            }
+        })
+        .boxed()
+    }))
+}
+
+/// Middleware that counts the bytes of every response body before compression.
+///
+/// It has to sit inside the compression middleware (registered before it with
+/// `.wrap()`), and inside `count_bytes`, which logs what it counted. Bodies the
+/// handler compresses itself (`?compress=` exports) are counted as they are.
+pub async fn count_uncompressed<B: MessageBody + 'static>(req: ServiceRequest, next: Next<B>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    let shared = req.extensions().get::<UncompressedBytes>().cloned();
+    let res = next.call(req).await?;
+
+    Ok(res.map_body(|_, body| {
+        CountingBody::new(body.boxed(), move |bytes| {
+            if let Some(shared) = &shared {
+                shared.0.store(bytes, Ordering::Relaxed);
+            }
+            if let Some(metrics) = &metrics {
+                metrics.response_uncompressed_bytes.inc_by(bytes);
+            }
+        })
+        .boxed()
+    }))
+}
+
+/// Wraps a response body and adds up the size of each chunk sent.
+/// Reports the total once the body is done (or dropped, if the client left early).
+struct CountingBody {
+    inner: BoxBody,
+    bytes: u64,
+    report: Option<Box<dyn FnOnce(u64)>>,
+}
+
+impl CountingBody {
+    fn new(inner: BoxBody, report: impl FnOnce(u64) + 'static) -> Self {
+        CountingBody { inner, bytes: 0, report: Some(Box::new(report)) }
+    }
+}
+
+impl MessageBody for CountingBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        // Drop the wrapped body first, so a counting layer inside it has
+        // reported by the time this one does.
+        drop(std::mem::replace(&mut self.inner, BoxBody::new(())));
+        if let Some(report) = self.report.take() {
+            report(self.bytes);
+        }
+    }
+}
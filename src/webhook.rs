@@ -0,0 +1,69 @@
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::sync::Arc;
+use crate::alerts::CapacityAlert;
+use crate::retry::{self, Failure};
+use crate::worker::{self, Node, WorkerConfig};
+
+// Tells an outside service (a chat bot, say) about nodes we haven't seen before,
+// and about watched nodes whose capacity moved past their alert threshold.
+
+/// The JSON body POSTed to `WEBHOOK_URL`. `capacity` is in sats.
+#[derive(Serialize)]
+struct NewNodesEvent<'a> {
+    event: &'static str,
+    count: usize,
+    nodes: &'a [Node],
+}
+
+/// The JSON body POSTed to `WEBHOOK_URL` when watched nodes cross their
+/// `ALERT_THRESHOLDS_PATH` thresholds.
+#[derive(Serialize)]
+struct CapacityAlertsEvent<'a> {
+    event: &'static str,
+    count: usize,
+    alerts: &'a [CapacityAlert],
+}
+
+/// POSTs the new nodes to `url`, retrying like a fetch does: up to
+/// `FETCH_MAX_ATTEMPTS` tries with the same jittered, doubling backoff.
+/// Gives up on statuses that won't change on a retry (404, 401, ...).
+pub async fn send_new_nodes(client: Client, config: Arc<WorkerConfig>, url: String, nodes: Vec<Node>) {
+    let event = NewNodesEvent { event: "new_nodes", count: nodes.len(), nodes: &nodes };
+    match post(&client, &config, &url, &event).await {
+        Ok(()) => info!("[Webhook] Sent {} new nodes.", nodes.len()),
+        Err(()) => warn!("[Webhook] Giving up, {} new nodes weren't sent.", nodes.len()),
+    }
+}
+
+/// POSTs capacity alerts to `url`, retrying like `send_new_nodes`.
+pub async fn send_capacity_alerts(client: Client, config: Arc<WorkerConfig>, url: String, alerts: Vec<CapacityAlert>) {
+    let event = CapacityAlertsEvent { event: "capacity_alerts", count: alerts.len(), alerts: &alerts };
+    match post(&client, &config, &url, &event).await {
+        Ok(()) => info!("[Webhook] Sent {} capacity alerts.", alerts.len()),
+        Err(()) => warn!("[Webhook] Giving up, {} capacity alerts weren't sent.", alerts.len()),
+    }
+}
+
+/// POSTs `event` as JSON, with retries.
+async fn post(client: &Client, config: &WorkerConfig, url: &str, event: &impl Serialize) -> Result<(), ()> {
+    retry::retry_with_backoff("Webhook", config.retry_policy(), || async move {
+        match client.post(url).json(event).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                error!("[Webhook] {} answered {}.", url, response.status());
+                if worker::is_retryable_status(response.status()) {
+                    Err(Failure::Retry(()))
+                } else {
+                    Err(Failure::Stop(()))
+                }
+            }
+            Err(e) => {
+                error!("[Webhook] Failed to reach {}: {}", url, e);
+                Err(Failure::Retry(()))
+            }
+        }
+    })
+    .await
+}
@@ -1,78 +1,1216 @@
-use serde::Deserialize;
-use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use rusqlite::params;
 use std::time::Duration;
-use std::env;
+use std::fmt;
 use log::{error, info, warn};
-use reqwest::Client;
+use reqwest::{Client, Response, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use chrono::Utc;
+use cron::Schedule;
+use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use futures_util::future::join_all;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU8, AtomicU32, AtomicU64, Ordering};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use crate::alerts::{self, CapacityAlert, ThresholdsFile};
+use crate::clock::Clock;
+use crate::config::{self, ConfigError};
+use crate::db::{DbError, DbPool};
+use crate::dns::CachingResolver;
+use crate::metrics::Metrics;
+use crate::models::is_valid_pubkey;
+use crate::retry::{self, Failure, RetryPolicy};
+use crate::webhook;
 
 // This module is the background worker. It's job is to fetch node data
 // from the API and save it to our local database on a timer.
 
-/// The node data we get from the Mempool API.
+/// A node as any upstream reports it, before unit conversion.
+/// `capacity` is kept as a raw JSON number because its unit depends on the source.
+#[derive(Debug)]
+struct RawNode {
+    public_key: String,
+    alias: String,
+    capacity: serde_json::Number,
+    first_seen: i64,
+    channels: i64,
+    location: Location,
+}
+
+/// Where an upstream geolocates a node. Every field is optional: mempool.space
+/// leaves them `null` for Tor-only nodes, and other sources may not send them.
+#[derive(Deserialize, Debug, Clone, Default)]
+struct Location {
+    #[serde(default)]
+    city: Option<LocalizedName>,
+    #[serde(default)]
+    country: Option<LocalizedName>,
+    /// ISO 3166-1 alpha-2, e.g. "FR". mempool.space sends this one in snake_case
+    /// even though its other fields are camelCase.
+    #[serde(default, alias = "isoCode")]
+    iso_code: Option<String>,
+}
+
+/// A place name in several languages (`{"en": "Paris", "de": "Paris", ...}`).
+/// We only keep the English one.
+#[derive(Deserialize, Debug, Clone, Default)]
+struct LocalizedName {
+    #[serde(default)]
+    en: Option<String>,
+}
+
+// Each upstream names its fields its own way, so every naming style gets a small
+// adapter struct with its own serde attributes that converts into `RawNode`.
+
+/// The node data as it comes from the Mempool API (camelCase fields).
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+struct CamelCaseNode {
+    public_key: String,
+    alias: String,
+    capacity: serde_json::Number,
+    first_seen: i64,
+    /// Not every source reports it.
+    #[serde(default)]
+    channels: i64,
+    #[serde(flatten)]
+    location: Location,
+}
+
+impl From<CamelCaseNode> for RawNode {
+    fn from(n: CamelCaseNode) -> Self {
+        RawNode { public_key: n.public_key, alias: n.alias, capacity: n.capacity, first_seen: n.first_seen, channels: n.channels, location: n.location }
+    }
+}
+
+/// The same data from sources that use snake_case fields.
+#[derive(Deserialize, Debug)]
+struct SnakeCaseNode {
+    public_key: String,
+    alias: String,
+    capacity: serde_json::Number,
+    first_seen: i64,
+    #[serde(default)]
+    channels: i64,
+    #[serde(flatten)]
+    location: Location,
+}
+
+impl From<SnakeCaseNode> for RawNode {
+    fn from(n: SnakeCaseNode) -> Self {
+        RawNode { public_key: n.public_key, alias: n.alias, capacity: n.capacity, first_seen: n.first_seen, channels: n.channels, location: n.location }
+    }
+}
+
+/// How the upstream names its JSON fields, i.e. which adapter to parse with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldCase {
+    Camel,
+    Snake,
+}
+
+impl FieldCase {
+    /// Reads `API_FIELD_CASE` (`camel` or `snake`). Defaults to camel, like mempool.space.
+    fn from_env() -> Result<Self, ConfigError> {
+        match config::raw("API_FIELD_CASE").unwrap_or_default().to_lowercase().as_str() {
+            "snake" => Ok(FieldCase::Snake),
+            "" | "camel" => Ok(FieldCase::Camel),
+            other => Err(ConfigError::new("API_FIELD_CASE", format!("unknown case '{}'. Use 'camel' or 'snake'", other))),
+        }
+    }
+}
+
+/// Whether the worker's loop is running, as its supervisor sees it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    Running,
+    /// It panicked and is waiting to be started again.
+    Restarting,
+    /// It ended: on shutdown, or when a cron schedule ran out of upcoming times.
+    Stopped,
+}
+
+impl WorkerState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => WorkerState::Running,
+            1 => WorkerState::Restarting,
+            _ => WorkerState::Stopped,
+        }
+    }
+}
+
+/// What the rest of the app can see about the worker. Shared with the API.
+pub struct WorkerStatus {
+    /// A `WorkerState`. Starts as running, since the worker is spawned right
+    /// after its status is made.
+    state: AtomicU8,
+    /// Times the loop has panicked since startup.
+    panics: AtomicU32,
+    /// Set after the first fetch that got data into the DB (or found it unchanged).
+    has_fetched: AtomicBool,
+    /// Goes up whenever the worker changes the `nodes` table. Used for ETags.
+    data_version: AtomicU64,
+    /// Unix time of the last successful fetch. 0 until there is one.
+    last_success_unix: AtomicI64,
+    /// What went wrong on the last failed try. Cleared by a success.
+    last_error: std::sync::Mutex<Option<String>>,
+    /// Failed tries in a row, across cycles.
+    consecutive_failures: AtomicU32,
+    /// How long the last upstream fetch took, in ms. 0 until there is one.
+    last_fetch_duration_ms: AtomicU64,
+}
+
+impl Default for WorkerStatus {
+    fn default() -> Self {
+        WorkerStatus {
+            state: AtomicU8::new(WorkerState::Running as u8),
+            panics: AtomicU32::new(0),
+            has_fetched: AtomicBool::new(false),
+            // Starts at the startup time, so versions from an earlier run never come back.
+            data_version: AtomicU64::new(Utc::now().timestamp_millis() as u64),
+            last_success_unix: AtomicI64::new(0),
+            last_error: std::sync::Mutex::new(None),
+            consecutive_failures: AtomicU32::new(0),
+            last_fetch_duration_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl WorkerStatus {
+    pub fn state(&self) -> WorkerState {
+        WorkerState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    fn set_state(&self, state: WorkerState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn panics(&self) -> u32 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    pub fn has_fetched(&self) -> bool {
+        self.has_fetched.load(Ordering::Relaxed)
+    }
+
+    pub fn data_version(&self) -> u64 {
+        self.data_version.load(Ordering::Acquire)
+    }
+
+    fn data_changed(&self) {
+        self.data_version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn last_success_unix(&self) -> Option<i64> {
+        Some(self.last_success_unix.load(Ordering::Relaxed)).filter(|&t| t > 0)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn last_fetch_duration_ms(&self) -> Option<u64> {
+        Some(self.last_fetch_duration_ms.load(Ordering::Relaxed)).filter(|&ms| ms > 0)
+    }
+
+    fn fetch_succeeded(&self, clock: &dyn Clock) {
+        self.has_fetched.store(true, Ordering::Relaxed);
+        self.last_success_unix.store(clock.now().timestamp(), Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    fn fetch_failed(&self, error: String) {
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error);
+    }
+
+    /// A status whose last successful fetch was at the clock's time.
+    #[cfg(test)]
+    pub fn fetched_at(clock: &dyn Clock) -> Self {
+        let status = WorkerStatus::default();
+        status.fetch_succeeded(clock);
+        status
+    }
+
+    /// A status as the supervisor would leave it in `state`.
+    #[cfg(test)]
+    pub fn in_state(state: WorkerState) -> Self {
+        let status = WorkerStatus::default();
+        status.set_state(state);
+        status
+    }
+
+    /// Records another successful fetch, one that didn't change the data.
+    #[cfg(test)]
+    pub fn fetched_again_at(&self, clock: &dyn Clock) {
+        self.fetch_succeeded(clock);
+    }
+}
+
+/// The worker's settings, read from the environment once at startup.
+#[derive(Clone, Debug)]
+pub struct WorkerConfig {
+    /// `API_URL`: where to fetch nodes from. A comma-separated list of URLs
+    /// fetches from all of them and merges the results.
+    pub api_urls: Vec<String>,
+    /// `FETCH_INTERVAL_SECONDS`: time between fetches, when there's no cron schedule.
+    pub interval_secs: u64,
+    /// `FETCH_CRON`: fetch at these times instead of on the interval.
+    pub schedule: Option<Schedule>,
+    /// `FETCH_TIMEOUT_SECONDS`: timeout for a whole upstream request.
+    pub timeout_secs: u64,
+    /// `FETCH_MAX_ATTEMPTS`: tries per cycle before giving up until the next one.
+    pub max_attempts: u32,
+    /// `FETCH_BASE_BACKOFF_SECONDS`: wait after the first failed try. Doubles after each one.
+    pub base_backoff_secs: u64,
+    /// `FETCH_MAX_BACKOFF_SECONDS`: upper bound on any single wait, jitter included.
+    pub max_backoff_secs: u64,
+    /// `DNS_CACHE_TTL_SECONDS`: 0 means resolve on every new connection.
+    pub dns_cache_ttl_secs: u64,
+    /// `API_FIELD_CASE`: how the upstream names its fields.
+    pub field_case: FieldCase,
+    /// `API_CAPACITY_UNIT`: what unit the upstream reports capacity in.
+    pub capacity_unit: CapacityUnit,
+    /// `STALE_NODE_TTL_SECONDS`: delete nodes the upstream hasn't reported for this long.
+    /// `None` unless `PRUNE_STALE_NODES=true`, so by default nodes are kept forever.
+    pub stale_node_ttl_secs: Option<u64>,
+    /// `WEBHOOK_URL`: where to POST newly seen nodes. `None` sends nothing.
+    pub webhook_url: Option<String>,
+    /// `MAX_RESPONSE_BYTES`: biggest upstream body we'll read.
+    pub max_response_bytes: u64,
+    /// `SEED_FROM_BUNDLE`: fill an empty DB from the bundled snapshot when a fetch fails.
+    pub seed_from_bundle: bool,
+    /// `ALERT_THRESHOLDS_PATH`: per-node capacity-change alert thresholds, as
+    /// loaded at startup. `None` means no alerts.
+    pub alert_thresholds: Option<ThresholdsFile>,
+}
+
+/// Where nodes come from when `API_URL` isn't set. Also written to the default .env.
+pub const DEFAULT_API_URL: &str = "https://mempool.space/api/v1/lightning/nodes/rankings/connectivity";
+
+/// Reads `API_URL`, splitting it on commas. Falls back to `DEFAULT_API_URL`.
+fn api_urls() -> Result<Vec<String>, ConfigError> {
+    let urls = config::list("API_URL");
+    if urls.is_empty() {
+        return Ok(vec![DEFAULT_API_URL.to_string()]);
+    }
+    for url in &urls {
+        config::check_api_url("API_URL", url)?;
+    }
+    Ok(urls)
+}
+
+/// Loads the optional `ALERT_THRESHOLDS_PATH` file, so a bad one stops startup.
+fn alert_thresholds() -> Result<Option<ThresholdsFile>, ConfigError> {
+    config::raw("ALERT_THRESHOLDS_PATH")
+        .map(|path| ThresholdsFile::load(&path).map_err(|e| ConfigError::new("ALERT_THRESHOLDS_PATH", e)))
+        .transpose()
+}
+
+/// Reads the optional `WEBHOOK_URL`. Same https rule as `API_URL`.
+fn webhook_url() -> Result<Option<String>, ConfigError> {
+    let url = config::raw("WEBHOOK_URL");
+    if let Some(url) = &url {
+        config::check_api_url("WEBHOOK_URL", url)?;
+    }
+    Ok(url)
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let config = WorkerConfig {
+            api_urls: api_urls()?,
+            interval_secs: config::parse("FETCH_INTERVAL_SECONDS", 10)?,
+            schedule: cron_schedule()?,
+            timeout_secs: config::parse("FETCH_TIMEOUT_SECONDS", 30)?,
+            max_attempts: config::parse("FETCH_MAX_ATTEMPTS", 3)?,
+            base_backoff_secs: config::parse("FETCH_BASE_BACKOFF_SECONDS", 1)?,
+            max_backoff_secs: config::parse("FETCH_MAX_BACKOFF_SECONDS", 60)?,
+            dns_cache_ttl_secs: config::parse("DNS_CACHE_TTL_SECONDS", 0)?,
+            field_case: FieldCase::from_env()?,
+            capacity_unit: CapacityUnit::from_env()?,
+            stale_node_ttl_secs: if config::flag("PRUNE_STALE_NODES", false)? {
+                Some(config::parse("STALE_NODE_TTL_SECONDS", 7 * 24 * 3600)?)
+            } else {
+                None
+            },
+            webhook_url: webhook_url()?,
+            max_response_bytes: config::parse("MAX_RESPONSE_BYTES", 50 * 1024 * 1024)?,
+            seed_from_bundle: config::flag("SEED_FROM_BUNDLE", false)?,
+            alert_thresholds: alert_thresholds()?,
+        };
+        // With a cron schedule the interval isn't used, so it can be anything.
+        if config.schedule.is_none() && config.interval_secs == 0 {
+            return Err(ConfigError::new("FETCH_INTERVAL_SECONDS", "must be at least 1"));
+        }
+        if config.timeout_secs == 0 {
+            return Err(ConfigError::new("FETCH_TIMEOUT_SECONDS", "must be at least 1"));
+        }
+        if config.max_response_bytes == 0 {
+            return Err(ConfigError::new("MAX_RESPONSE_BYTES", "must be at least 1"));
+        }
+        if config.max_attempts == 0 {
+            return Err(ConfigError::new("FETCH_MAX_ATTEMPTS", "must be at least 1"));
+        }
+        if config.base_backoff_secs > config.max_backoff_secs {
+            return Err(ConfigError::new("FETCH_BASE_BACKOFF_SECONDS", "can't be greater than FETCH_MAX_BACKOFF_SECONDS"));
+        }
+        if config.stale_node_ttl_secs == Some(0) {
+            return Err(ConfigError::new("STALE_NODE_TTL_SECONDS", "must be at least 1"));
+        }
+        Ok(config)
+    }
+
+    /// The `FETCH_*` retry settings. The webhook retries the same way.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_backoff_secs: self.base_backoff_secs,
+            max_backoff_secs: self.max_backoff_secs,
+        }
+    }
+}
+
+/// Everything a run of the worker loop needs. Cloned into each (re)spawned loop.
+#[derive(Clone)]
+struct WorkerContext {
+    config: Arc<WorkerConfig>,
+    client: Client,
+    pool: DbPool,
+    status: Arc<WorkerStatus>,
+    metrics: Arc<Metrics>,
+    clock: &'static dyn Clock,
+    refresh: Arc<Mutex<mpsc::Receiver<RefreshRequest>>>,
+    in_flight: InFlight,
+    /// The `ALERT_THRESHOLDS_PATH` file, reloaded when it changes.
+    alerts: Option<Arc<std::sync::Mutex<ThresholdsFile>>>,
+}
+
+/// Where a cycle's outcome goes once it's done. Everyone waiting on the same
+/// cycle watches the same channel.
+type RefreshRequest = watch::Sender<Option<CycleOutcome>>;
+
+/// The cycle that's running or queued right now, if any, for refreshes to join.
+type InFlight = Arc<std::sync::Mutex<Option<watch::Receiver<Option<CycleOutcome>>>>>;
+
+/// Asks the worker for an immediate fetch-and-store cycle. Cheap to clone.
+#[derive(Clone)]
+pub struct Refresher {
+    tx: mpsc::Sender<RefreshRequest>,
+    in_flight: InFlight,
+}
+
+/// A cycle a refresh started or joined.
+pub struct PendingRefresh(watch::Receiver<Option<CycleOutcome>>);
+
+impl PendingRefresh {
+    /// Waits for the cycle to finish. `None` if the worker stopped first.
+    pub async fn outcome(mut self) -> Option<CycleOutcome> {
+        self.0.wait_for(Option::is_some).await.ok().and_then(|outcome| *outcome)
+    }
+}
+
+/// What asking for a refresh did.
+pub enum RefreshStart {
+    /// Nothing was running, so a cycle was queued.
+    Started(PendingRefresh),
+    /// A cycle (asked for or scheduled) was already running or queued, so
+    /// nothing new was queued. Its outcome is shared.
+    AlreadyRunning(PendingRefresh),
+}
+
+impl Refresher {
+    /// Queues a cycle, unless one is already running or queued: then the
+    /// caller joins that one, so a burst of refreshes fetches only once.
+    /// Returns `None` if the worker isn't running anymore.
+    pub fn start(&self) -> Option<RefreshStart> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        // The sender is gone if the loop panicked mid-cycle, so that one isn't running anymore.
+        if let Some(outcome) = in_flight.as_ref().filter(|outcome| outcome.has_changed().is_ok()) {
+            return Some(RefreshStart::AlreadyRunning(PendingRefresh(outcome.clone())));
+        }
+        let (reply, outcome) = watch::channel(None);
+        self.tx.try_send(reply).ok()?;
+        *in_flight = Some(outcome.clone());
+        Some(RefreshStart::Started(PendingRefresh(outcome)))
+    }
+}
+
+/// A node ready to be stored. `capacity` is always in sats.
+/// Serialized as-is for the new-node webhook.
+#[derive(Debug, Clone, Serialize)]
 pub struct Node {
     public_key: String,
     alias: String,
     capacity: i64,
     first_seen: i64,
+    channels: i64,
+    city: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+}
+
+const SATS_PER_BTC: i64 = 100_000_000;
+const MSATS_PER_SAT: i64 = 1_000;
+
+/// The unit an upstream reports capacity in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapacityUnit {
+    Sats,
+    Msats,
+    Btc,
+}
+
+impl CapacityUnit {
+    /// Reads `API_CAPACITY_UNIT` (`sats`, `msats` or `btc`). Defaults to sats,
+    /// which is what mempool.space reports.
+    fn from_env() -> Result<Self, ConfigError> {
+        match config::raw("API_CAPACITY_UNIT").unwrap_or_default().to_lowercase().as_str() {
+            "msats" | "msat" => Ok(CapacityUnit::Msats),
+            "btc" => Ok(CapacityUnit::Btc),
+            "" | "sats" | "sat" => Ok(CapacityUnit::Sats),
+            other => Err(ConfigError::new("API_CAPACITY_UNIT", format!("unknown unit '{}'. Use 'sats', 'msats' or 'btc'", other))),
+        }
+    }
+
+    /// Converts a capacity in this unit to whole sats.
+    ///
+    /// msats are rounded down (a channel can't hold a fraction of a sat on-chain),
+    /// BTC is rounded to the nearest sat. Returns `None` for negative values or
+    /// anything that doesn't fit in an i64.
+    pub fn to_sats(self, value: &serde_json::Number) -> Option<i64> {
+        let sats = match (self, value.as_i64()) {
+            (CapacityUnit::Sats, Some(v)) => Some(v),
+            (CapacityUnit::Msats, Some(v)) => Some(v / MSATS_PER_SAT),
+            (CapacityUnit::Btc, Some(v)) => v.checked_mul(SATS_PER_BTC),
+            // Not an integer (or too big for one), so go through f64.
+            (unit, None) => {
+                let v = value.as_f64()?;
+                let sats = match unit {
+                    CapacityUnit::Sats => v.round(),
+                    CapacityUnit::Msats => (v / MSATS_PER_SAT as f64).floor(),
+                    CapacityUnit::Btc => (v * SATS_PER_BTC as f64).round(),
+                };
+                (sats.is_finite() && sats >= 0.0 && sats < i64::MAX as f64).then_some(sats as i64)
+            }
+        }?;
+        (sats >= 0).then_some(sats)
+    }
+}
+
+/// Validators from the last upstream response we stored. Sent back on the next
+/// fetch so the upstream can answer `304 Not Modified` if nothing changed.
+#[derive(Default, Debug, Clone)]
+struct UpstreamValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl UpstreamValidators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(String::from);
+        UpstreamValidators { etag: get(ETAG), last_modified: get(LAST_MODIFIED) }
+    }
+}
+
+/// One upstream and what we remember about it between fetches.
+struct Source {
+    url: String,
+    validators: UpstreamValidators,
+    /// What it sent last time, for when it answers 304 but another source changed.
+    nodes: Option<Vec<Node>>,
+}
+
+impl Source {
+    fn new(url: &str) -> Self {
+        Source { url: url.to_string(), validators: UpstreamValidators::default(), nodes: None }
+    }
+}
+
+enum FetchOutcome {
+    /// The upstream says the data hasn't changed since the last fetch.
+    NotModified,
+    Fresh(Vec<Node>, UpstreamValidators),
+}
+
+/// Why a fetch from one upstream failed.
+#[derive(Debug)]
+enum FetchError {
+    Http(reqwest::Error),
+    /// The body went over `MAX_RESPONSE_BYTES` (the limit).
+    TooLarge(u64),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Http(e) => e.fmt(f),
+            FetchError::TooLarge(limit) => write!(f, "response is larger than MAX_RESPONSE_BYTES ({} bytes)", limit),
+            FetchError::Json(e) => write!(f, "invalid JSON: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for FetchError {
+    fn from(e: serde_json::Error) -> Self {
+        FetchError::Json(e)
+    }
+}
+
+/// Reads the whole body, but gives up as soon as it's clearly over `limit`
+/// bytes, so a huge (or endless) response can't use up our memory.
+async fn read_body(mut response: Response, limit: u64) -> Result<Vec<u8>, FetchError> {
+    if response.content_length().is_some_and(|len| len > limit) {
+        return Err(FetchError::TooLarge(limit));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(FetchError::TooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Grabs the latest node data from one upstream.
+async fn fetch_nodes(config: &WorkerConfig, client: &Client, source: &Source) -> Result<FetchOutcome, FetchError> {
+    info!("[Worker] Fetching nodes from {}...", source.url);
+    // Upstreams that don't support conditional requests just ignore these headers.
+    let mut request = client.get(&source.url);
+    if let Some(etag) = &source.validators.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &source.validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(FetchOutcome::NotModified);
+    }
+    // Turn 4xx/5xx into errors here, so the retry logic can see the status.
+    let response = response.error_for_status()?;
+
+    let new_validators = UpstreamValidators::from_headers(response.headers());
+    let body = read_body(response, config.max_response_bytes).await?;
+    let raw_nodes = parse_nodes(&body, config.field_case)?;
+
+    Ok(FetchOutcome::Fresh(normalize_nodes(raw_nodes, config.capacity_unit), new_validators))
+}
+
+/// Parses an upstream body with the adapter for its field case.
+fn parse_nodes(body: &[u8], case: FieldCase) -> Result<Vec<RawNode>, serde_json::Error> {
+    Ok(match case {
+        FieldCase::Camel => serde_json::from_slice::<Vec<CamelCaseNode>>(body)?.into_iter().map(RawNode::from).collect(),
+        FieldCase::Snake => serde_json::from_slice::<Vec<SnakeCaseNode>>(body)?.into_iter().map(RawNode::from).collect(),
+    })
+}
+
+/// Combines the node lists from several sources into one, without duplicates.
+/// When sources disagree about a node, the one reporting the higher capacity wins.
+fn merge_nodes<'a>(lists: impl Iterator<Item = &'a [Node]>) -> Vec<Node> {
+    let mut merged: HashMap<&str, &Node> = HashMap::new();
+    for node in lists.flatten() {
+        merged
+            .entry(&node.public_key)
+            .and_modify(|kept| {
+                if node.capacity > kept.capacity {
+                    *kept = node;
+                }
+            })
+            .or_insert(node);
+    }
+    merged.into_values().cloned().collect()
+}
+
+/// Turns upstream nodes into nodes we can store.
+///
+/// Capacities are normalized to sats here, so the `capacity` column always
+/// means sats no matter what the source reports. Nodes with a capacity we
+/// can't convert are skipped, and so are nodes with a malformed public key,
+/// so garbage never makes it into the primary key.
+fn normalize_nodes(raw_nodes: Vec<RawNode>, unit: CapacityUnit) -> Vec<Node> {
+    let mut bad_keys = 0;
+    let mut bad_capacities = 0;
+    let nodes: Vec<Node> = raw_nodes
+        .into_iter()
+        .filter_map(|raw| {
+            if !is_valid_pubkey(&raw.public_key) {
+                bad_keys += 1;
+                return None;
+            }
+            let Some(capacity) = unit.to_sats(&raw.capacity) else {
+                bad_capacities += 1;
+                return None;
+            };
+            Some(Node {
+                public_key: raw.public_key,
+                alias: raw.alias,
+                capacity,
+                first_seen: raw.first_seen,
+                channels: raw.channels,
+                city: raw.location.city.and_then(|name| name.en),
+                country: raw.location.country.and_then(|name| name.en),
+                country_code: raw.location.iso_code.map(|code| code.to_uppercase()),
+            })
+        })
+        .collect();
+    if bad_keys > 0 {
+        warn!("[Worker] Skipped {} nodes with an invalid public key.", bad_keys);
+    }
+    if bad_capacities > 0 {
+        warn!("[Worker] Skipped {} nodes with an invalid capacity.", bad_capacities);
+    }
+    nodes
+}
+
+/// A snapshot of the mempool rankings, compiled into the binary.
+/// See the README for how to regenerate it.
+const BUNDLED_NODES: &[u8] = include_bytes!("bundled_nodes.json");
+
+/// Seeds the database from the bundled snapshot, if `SEED_FROM_BUNDLE=true`
+/// and the database has no nodes yet.
+///
+/// This is for offline demos: when the upstream can't be reached, the service
+/// still has something meaningful to show. It goes through the same
+/// `store_nodes` path as a normal fetch.
+fn seed_from_bundle(enabled: bool, pool: &DbPool, status: &WorkerStatus, clock: &dyn Clock) {
+    if !enabled {
+        return;
+    }
+
+    let is_empty = pool
+        .get()
+        .map_err(DbError::from)
+        .and_then(|conn| Ok(conn.query_row("SELECT NOT EXISTS (SELECT 1 FROM nodes)", [], |row| row.get::<_, bool>(0))?));
+    match is_empty {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            error!("[Worker] Couldn't check whether the DB is empty: {}", e);
+            return;
+        }
+    }
+
+    let raw_nodes = match parse_nodes(BUNDLED_NODES, FieldCase::Camel) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            error!("[Worker] Bundled snapshot is invalid: {}", e);
+            return;
+        }
+    };
+    // The bundle is a mempool.space snapshot, which reports sats.
+    let nodes = normalize_nodes(raw_nodes, CapacityUnit::Sats);
+    match store_nodes(pool, &nodes, clock, &HashMap::new()) {
+        Ok(stored) => {
+            info!("[Worker] Upstream unreachable and DB empty. Seeded {} nodes from the bundled snapshot.", stored.new_keys.len());
+            status.data_changed();
+        }
+        Err(e) => error!("[Worker] Failed to seed DB from the bundled snapshot: {}", e),
+    }
+}
+
+/// What a node looked like in the DB before a store.
+struct StoredNode {
+    alias: String,
+    capacity: i64,
+    channels: i64,
+    city: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+    /// The capacity last written to `node_history`.
+    history_capacity: Option<i64>,
 }
 
-/// Grabs the latest node data from the Mempool API.
-async fn fetch_nodes(api_url: &str, client: &Client) -> Result<Vec<Node>, reqwest::Error> {
-    info!("[Worker] Fetching nodes from API...");
-    let nodes = client
-        .get(api_url)
-        .send()
-        .await?
-        .json::<Vec<Node>>()
-        .await?;
-    Ok(nodes)
+/// What `store_nodes` did.
+struct Stored {
+    /// Public keys of the nodes that were new.
+    new_keys: Vec<String>,
+    /// Nodes whose `updated_at` moved, i.e. whose alias or capacity changed.
+    updated: usize,
+    /// Whether anything the API shows changed, channels and location included.
+    changed: bool,
+    alerts: Vec<CapacityAlert>,
 }
 
 /// Saves the list of nodes into the database.
 ///
-/// It does two things in one transaction:
-/// 1. `INSERT OR IGNORE`: Adds any new nodes.
-/// 2. `UPDATE`: Updates info for existing nodes if it changed.
+/// It does three things in one transaction:
+/// 1. Reads what's stored for every node, with its last recorded capacity, in
+///    one query. New, updated and unchanged nodes are told apart from that.
+/// 2. An upsert per node that adds new nodes and refreshes existing ones,
+///    `last_seen` included. `updated_at` is set on insert, and moves when the
+///    alias or capacity changes.
+/// 3. Appends to `node_history` for every node whose capacity differs from
+///    its last recorded one (so new nodes get their first entry).
 ///
 /// This is way more efficient than checking each node one by one.
-fn store_nodes(nodes: &[Node]) -> rusqlite::Result<(usize, usize)> {
-    let db_path = env::var("DATABASE_PATH").unwrap_or("nodes.db".to_string());
-    let conn = rusqlite::Connection::open(db_path)?;
+///
+/// Every timestamp written is the clock's current time.
+///
+/// Nodes with a capacity-change threshold in `thresholds` (percent, by public
+/// key) get an alert when their capacity moved by at least that much.
+fn store_nodes(
+    pool: &DbPool,
+    nodes: &[Node],
+    clock: &dyn Clock,
+    thresholds: &HashMap<String, f64>,
+) -> Result<Stored, DbError> {
+    let conn = pool.get()?;
     let tx = conn.unchecked_transaction()?;
+    let now = clock.now().timestamp();
 
-    let mut inserted_count = 0;
-    let mut updated_count = 0;
+    let existing: HashMap<String, StoredNode> = {
+        let mut stmt = tx.prepare_cached(
+            "SELECT n.public_key, n.alias, n.capacity, n.channels, n.city, n.country, n.country_code,
+                    (SELECT capacity FROM node_history h WHERE h.public_key = n.public_key ORDER BY recorded_at DESC, id DESC LIMIT 1)
+             FROM nodes n",
+        )?;
+        stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                StoredNode {
+                    alias: row.get(1)?,
+                    capacity: row.get(2)?,
+                    channels: row.get(3)?,
+                    city: row.get(4)?,
+                    country: row.get(5)?,
+                    country_code: row.get(6)?,
+                    history_capacity: row.get(7)?,
+                },
+            ))
+        })?
+        .collect::<Result<_, _>>()?
+    };
 
+    let mut stored = Stored { new_keys: Vec::new(), updated: 0, changed: false, alerts: Vec::new() };
     {
-        let mut stmt = tx.prepare_cached(
-            "INSERT OR IGNORE INTO nodes (public_key, alias, capacity, first_seen) VALUES (?1, ?2, ?3, ?4)",
+        let mut upsert = tx.prepare_cached(
+            "INSERT INTO nodes (public_key, alias, capacity, first_seen, channels, city, country, country_code, updated_at, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)
+             ON CONFLICT(public_key) DO UPDATE SET
+                 alias = excluded.alias, capacity = excluded.capacity, channels = excluded.channels,
+                 city = excluded.city, country = excluded.country, country_code = excluded.country_code,
+                 updated_at = CASE WHEN alias != excluded.alias OR capacity != excluded.capacity THEN excluded.updated_at ELSE updated_at END,
+                 last_seen = excluded.last_seen",
+        )?;
+        let mut history = tx.prepare_cached(
+            "INSERT INTO node_history (public_key, capacity, channels, recorded_at) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        // A new node may be one that was pruned and came back, so its history
+        // isn't known up front.
+        let mut new_history = tx.prepare_cached(
+            "INSERT INTO node_history (public_key, capacity, channels, recorded_at)
+             SELECT ?1, ?2, ?3, ?4
+             WHERE ?2 IS NOT (SELECT capacity FROM node_history WHERE public_key = ?1 ORDER BY recorded_at DESC, id DESC LIMIT 1)",
         )?;
         for node in nodes {
-            let changed = stmt.execute(params![
+            upsert.execute(params![
                 node.public_key,
                 node.alias,
                 node.capacity,
-                node.first_seen
+                node.first_seen,
+                node.channels,
+                node.city,
+                node.country,
+                node.country_code,
+                now
             ])?;
-            inserted_count += changed;
+            let history_params = params![node.public_key, node.capacity, node.channels, now];
+            let Some(old) = existing.get(&node.public_key) else {
+                stored.new_keys.push(node.public_key.clone());
+                stored.changed = true;
+                new_history.execute(history_params)?;
+                continue;
+            };
+            if old.alias != node.alias || old.capacity != node.capacity {
+                stored.updated += 1;
+                stored.changed = true;
+            } else if old.channels != node.channels || old.city != node.city || old.country != node.country || old.country_code != node.country_code {
+                stored.changed = true;
+            }
+            if old.history_capacity != Some(node.capacity) {
+                history.execute(history_params)?;
+            }
+            if let Some(&threshold) = thresholds.get(&node.public_key) {
+                stored.alerts.extend(alerts::check(&node.public_key, &node.alias, old.capacity, node.capacity, threshold));
+            }
         }
     }
 
-    {
-        let mut stmt = tx.prepare_cached(
-            "UPDATE nodes SET alias = ?2, capacity = ?3 WHERE public_key = ?1 AND (alias != ?2 OR capacity != ?3)",
-        )?;
-        for node in nodes {
-            let changed = stmt.execute(params![node.public_key, node.alias, node.capacity])?;
-            updated_count += changed;
+    // Commit the transaction to make the changes permanent.
+    tx.commit()?;
+    Ok(stored)
+}
+
+/// Deletes nodes whose `last_seen` is more than `ttl_secs` before the clock's
+/// time, i.e. that dropped off the upstream rankings. Their history is kept.
+fn prune_stale_nodes(pool: &DbPool, ttl_secs: u64, clock: &dyn Clock) -> Result<usize, DbError> {
+    let cutoff = clock.now().timestamp().saturating_sub(ttl_secs as i64);
+    let conn = pool.get()?;
+    Ok(conn.execute("DELETE FROM nodes WHERE last_seen < ?1", [cutoff])?)
+}
+
+/// Whether an upstream HTTP status is worth retrying.
+///
+/// 5xx, 408 (request timeout) and 429 (rate limited) can clear up on their own.
+/// Other 4xx (404, 401, ...) mean something is misconfigured, and retrying won't fix that.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS) || !status.is_client_error()
+}
+
+/// Whether a failed fetch is worth retrying. Timeouts and connection errors are
+/// treated as transient. A bad URL, a permanent status, an oversized body or a
+/// body we can't parse is not: the upstream would send the same thing again.
+fn is_retryable(err: &FetchError) -> bool {
+    match err {
+        FetchError::Http(e) if e.is_builder() => false,
+        FetchError::Http(e) => e.status().is_none_or(is_retryable_status),
+        FetchError::TooLarge(_) | FetchError::Json(_) => false,
+    }
+}
+
+/// Sleeps for `wait`, cut short if shutdown is requested.
+/// Returns `true` if the worker should stop.
+async fn sleep_or_shutdown(shutdown: &mut watch::Receiver<bool>, wait: Duration) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(wait) => false,
+        // An error means the sender is gone, which is as good as a shutdown.
+        _ = shutdown.wait_for(|stop| *stop) => true,
+    }
+}
+
+/// Sends the nodes that were just inserted to `WEBHOOK_URL`, in the background
+/// so a slow receiver doesn't hold up the worker.
+///
+/// When every node was new, the database was empty (first run, or the DB was
+/// reset). That isn't news, so nothing is sent.
+fn announce_new_nodes(ctx: &WorkerContext, url: &str, nodes: Vec<Node>, new_keys: Vec<String>) {
+    if new_keys.is_empty() {
+        return;
+    }
+    if new_keys.len() == nodes.len() {
+        info!("[Worker] Filled an empty DB, not sending {} nodes to the webhook.", new_keys.len());
+        return;
+    }
+    let new_keys: HashSet<String> = new_keys.into_iter().collect();
+    let new_nodes: Vec<Node> = nodes.into_iter().filter(|node| new_keys.contains(&node.public_key)).collect();
+    tokio::spawn(webhook::send_new_nodes(ctx.client.clone(), ctx.config.clone(), url.to_string(), new_nodes));
+}
+
+/// How a fetch-and-store cycle went.
+#[derive(Clone, Copy, Debug)]
+pub enum CycleOutcome {
+    /// New data was fetched and saved.
+    Stored { inserted: usize, updated: usize },
+    /// The upstream said nothing changed since the last fetch.
+    NotModified,
+    /// Every attempt failed. The errors are in the log.
+    Failed,
+    /// The worker was shut down before the cycle finished.
+    Cancelled,
+}
+
+/// Runs one fetch-and-store cycle, retrying a few times with a growing delay
+/// if the API or the database fails.
+///
+/// On shutdown, an in-flight download or retry wait is dropped, but a store
+/// that has started always runs to the end (it has no `.await` to stop at).
+async fn fetch_and_store(ctx: &WorkerContext, sources: &mut [Source], shutdown: &mut watch::Receiver<bool>) -> CycleOutcome {
+    let current: &[Source] = sources;
+    let attempts = retry::retry_with_backoff("Worker", ctx.config.retry_policy(), || try_fetch_and_store(ctx, current));
+    tokio::select! {
+        result = attempts => match result {
+            Ok((outcome, fresh)) => {
+                // Only remember the validators once the data is actually saved,
+                // otherwise a failed save would be skipped forever on 304s.
+                for (index, nodes, validators) in fresh {
+                    sources[index].validators = validators;
+                    sources[index].nodes = Some(nodes);
+                }
+                outcome
+            }
+            Err(()) => {
+                warn!("[Worker] Will try again later.");
+                seed_from_bundle(ctx.config.seed_from_bundle, &ctx.pool, &ctx.status, ctx.clock);
+                CycleOutcome::Failed
+            }
+        },
+        // An error means the sender is gone, which is as good as a shutdown.
+        _ = shutdown.wait_for(|stop| *stop) => {
+            info!("[Worker] Shutting down, dropping the fetch in progress.");
+            CycleOutcome::Cancelled
         }
     }
+}
 
-    // Commit the transaction to make the changes permanent.
-    tx.commit()?;
-    Ok((inserted_count, updated_count))
+/// What the sources that sent new data sent: index, nodes and validators.
+type FreshSources = Vec<(usize, Vec<Node>, UpstreamValidators)>;
+
+/// One attempt of a cycle. Returns what the sources sent once it's stored,
+/// for `fetch_and_store` to remember.
+///
+/// All sources are fetched at once. Whatever subset succeeds gets merged and
+/// stored; only if every source fails does the attempt count as failed. Sources
+/// that answer 304 contribute the nodes they sent last time.
+async fn try_fetch_and_store(ctx: &WorkerContext, sources: &[Source]) -> Result<(CycleOutcome, FreshSources), Failure<()>> {
+    let pool = &ctx.pool;
+    let started = std::time::Instant::now();
+    let results = join_all(sources.iter().map(|source| fetch_nodes(&ctx.config, &ctx.client, source))).await;
+    // At least 1, so a very fast fetch still shows up as done.
+    let elapsed_ms = (started.elapsed().as_millis() as u64).max(1);
+    ctx.status.last_fetch_duration_ms.store(elapsed_ms, Ordering::Relaxed);
+
+    let mut fresh = Vec::new();
+    let mut unchanged = Vec::new();
+    let mut errors = Vec::new();
+    let mut retryable = false;
+    for (index, (source, result)) in sources.iter().zip(results).enumerate() {
+        match result {
+            Ok(FetchOutcome::NotModified) => {
+                info!("[Worker] {}: not modified.", source.url);
+                unchanged.push(index);
+            }
+            Ok(FetchOutcome::Fresh(nodes, validators)) => {
+                info!("[Worker] {}: fetched {} nodes.", source.url, nodes.len());
+                fresh.push((index, nodes, validators));
+            }
+            Err(e) => {
+                error!("[Worker] Failed to fetch nodes from {}: {}", source.url, e);
+                retryable |= is_retryable(&e);
+                errors.push(format!("{}: {}", source.url, e));
+            }
+        }
+    }
+
+    if fresh.is_empty() && !unchanged.is_empty() {
+        info!("[Worker] Upstream data not modified. Skipping store.");
+        ctx.status.fetch_succeeded(ctx.clock);
+        ctx.metrics.fetch_successes.inc();
+        return Ok((CycleOutcome::NotModified, Vec::new()));
+    }
+
+    if !fresh.is_empty() {
+        // Got the nodes, now try to save them.
+        let lists = fresh
+            .iter()
+            .map(|(_, nodes, _)| nodes.as_slice())
+            .chain(unchanged.iter().filter_map(|&index| sources[index].nodes.as_deref()));
+        let merged = merge_nodes(lists);
+        let thresholds = match &ctx.alerts {
+            Some(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                file.reload_if_changed();
+                file.thresholds().clone()
+            }
+            None => HashMap::new(),
+        };
+        match store_nodes(pool, &merged, ctx.clock, &thresholds) {
+            Ok(Stored { new_keys, updated, changed, alerts }) => {
+                let inserted = new_keys.len();
+                if changed {
+                    info!("[Worker] DB updated. Inserted: {}, Updated: {}.", inserted, updated);
+                    ctx.status.data_changed();
+                }
+                ctx.status.fetch_succeeded(ctx.clock);
+                ctx.metrics.fetch_successes.inc();
+                // Only after fresh data, since a 304 doesn't refresh `last_seen`.
+                if let Some(ttl) = ctx.config.stale_node_ttl_secs {
+                    match prune_stale_nodes(pool, ttl, ctx.clock) {
+                        Ok(0) => {}
+                        Ok(pruned) => {
+                            info!("[Worker] Pruned {} nodes not seen in the last {}s.", pruned, ttl);
+                            ctx.status.data_changed();
+                        }
+                        Err(e) => error!("[Worker] Failed to prune stale nodes: {}", e),
+                    }
+                }
+                if let Some(url) = &ctx.config.webhook_url {
+                    announce_new_nodes(ctx, url, merged, new_keys);
+                    if !alerts.is_empty() {
+                        tokio::spawn(webhook::send_capacity_alerts(ctx.client.clone(), ctx.config.clone(), url.to_string(), alerts));
+                    }
+                }
+                return Ok((CycleOutcome::Stored { inserted, updated }, fresh));
+            }
+            Err(e) => {
+                error!("[Worker] Failed to save nodes to DB: {}", e);
+                errors.push(format!("saving to DB: {}", e));
+                // A DB error is always worth another try.
+                retryable = true;
+            }
+        }
+    }
+
+    ctx.metrics.fetch_failures.inc();
+    ctx.status.fetch_failed(errors.join("; "));
+    if retryable {
+        Err(Failure::Retry(()))
+    } else {
+        error!("[Worker] Not retrying, check API_URL and credentials.");
+        Err(Failure::Stop(()))
+    }
+}
+
+/// Reads the optional `FETCH_CRON` schedule.
+///
+/// The expression has a seconds field first, e.g. `0 0 * * * *` runs every hour
+/// on the hour.
+fn cron_schedule() -> Result<Option<Schedule>, ConfigError> {
+    let Some(expr) = config::raw("FETCH_CRON") else {
+        return Ok(None);
+    };
+    Schedule::from_str(&expr)
+        .map(Some)
+        .map_err(|e| ConfigError::new("FETCH_CRON", format!("invalid expression '{}': {}", expr, e)))
+}
+
+/// How long to sleep until the schedule's next run after the clock's current time.
+/// Returns `None` if the schedule never fires again.
+fn until_next_run(schedule: &Schedule, clock: &dyn Clock) -> Option<Duration> {
+    let now = clock.now();
+    let next = schedule.after(&now).next()?;
+    // `after` is strictly in the future, but clamp anyway in case of rounding.
+    Some((next - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Marks a cycle as running, so refreshes asked for meanwhile join it instead
+/// of queuing another. A refresh that's already queued is folded into it.
+fn begin_cycle(in_flight: &InFlight, refresh: &mut mpsc::Receiver<RefreshRequest>, reply: Option<RefreshRequest>) -> RefreshRequest {
+    let mut in_flight = in_flight.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    // A queued request already put itself in `in_flight`.
+    if let Some(reply) = reply.or_else(|| refresh.try_recv().ok()) {
+        return reply;
+    }
+    let (reply, outcome) = watch::channel(None);
+    *in_flight = Some(outcome);
+    reply
+}
+
+/// Runs a cycle and sends how it went to every refresh waiting on it.
+async fn run_cycle(
+    ctx: &WorkerContext,
+    sources: &mut [Source],
+    shutdown: &mut watch::Receiver<bool>,
+    refresh: &mut mpsc::Receiver<RefreshRequest>,
+    reply: Option<RefreshRequest>,
+) {
+    if reply.is_some() {
+        info!("[Worker] Refresh requested.");
+    }
+    let reply = begin_cycle(&ctx.in_flight, refresh, reply);
+    let outcome = fetch_and_store(ctx, sources, shutdown).await;
+    end_cycle(&ctx.in_flight, reply, outcome);
+}
+
+/// Sends a finished cycle's outcome to whoever joined it, and lets the next
+/// refresh start a new one.
+fn end_cycle(in_flight: &InFlight, reply: RefreshRequest, outcome: CycleOutcome) {
+    let mut in_flight = in_flight.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    *in_flight = None;
+    // Nobody may be waiting, or they gave up, that's fine.
+    let _ = reply.send(Some(outcome));
+}
+
+/// The worker's main loop: wait for the next tick (or cron time), then fetch and store.
+/// A refresh request runs a cycle right away and gets its outcome back.
+/// Refreshes asked for during a cycle share its outcome instead.
+/// Returns on shutdown, or if a cron schedule runs out of upcoming times.
+async fn run_worker_loop(ctx: WorkerContext, mut shutdown: watch::Receiver<bool>) {
+    let mut sources: Vec<Source> = ctx.config.api_urls.iter().map(|url| Source::new(url)).collect();
+    // Held for as long as this loop runs. If it panics, the guard is dropped
+    // and the restarted loop picks the receiver up again.
+    let mut refresh = ctx.refresh.lock().await;
+
+    if let Some(schedule) = &ctx.config.schedule {
+        info!("[Worker] Using cron schedule '{}'.", schedule);
+        loop {
+            let Some(wait) = until_next_run(schedule, ctx.clock) else {
+                warn!("[Worker] Cron schedule has no more upcoming runs. Stopping.");
+                return;
+            };
+            let reply = tokio::select! {
+                _ = tokio::time::sleep(wait) => None,
+                Some(reply) = refresh.recv() => Some(reply),
+                _ = shutdown.wait_for(|stop| *stop) => break,
+            };
+            run_cycle(&ctx, &mut sources, &mut shutdown, &mut refresh, reply).await;
+        }
+        info!("[Worker] Stopped.");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(ctx.config.interval_secs));
+    loop {
+        // Wait for the next tick or a refresh, unless we're told to stop first.
+        let reply = tokio::select! {
+            _ = interval.tick() => None,
+            Some(reply) = refresh.recv() => Some(reply),
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        run_cycle(&ctx, &mut sources, &mut shutdown, &mut refresh, reply).await;
+    }
+    info!("[Worker] Stopped.");
+}
+
+/// Pulls a readable message out of a panic payload.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// The running worker, as seen from `main`.
+pub struct WorkerHandle {
+    task: JoinHandle<()>,
+    shutdown: watch::Sender<bool>,
+    config: Arc<WorkerConfig>,
+    refresher: Refresher,
+}
+
+impl WorkerHandle {
+    /// The settings the worker was started with.
+    pub fn config(&self) -> &WorkerConfig {
+        &self.config
+    }
+
+    /// For triggering a fetch outside the schedule.
+    pub fn refresher(&self) -> Refresher {
+        self.refresher.clone()
+    }
+
+    /// Asks the worker to stop. It finishes any store in progress first,
+    /// so `join` to wait for it.
+    pub fn shutdown(&self) {
+        // Only fails if the worker is already gone, which is what we want anyway.
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Waits for the worker to finish. Errors if the supervisor itself panicked.
+    pub async fn join(self) -> Result<(), tokio::task::JoinError> {
+        self.task.await
+    }
 }
 
 /// Kicks off the background worker task.
@@ -80,55 +1218,426 @@ fn store_nodes(nodes: &[Node]) -> rusqlite::Result<(usize, usize)> {
 /// This function spawns a Tokio task that runs in a loop.
 /// It fetches data on a timer and will retry a few times with a delay
 /// if the API or database fails, so it's pretty resilient.
-pub fn spawn_worker() {
-    let interval_secs: u64 = env::var("FETCH_INTERVAL_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(10);
-    let api_url = env::var("API_URL").unwrap_or("https://mempool.space/api/v1/lightning/nodes/rankings/connectivity".to_string());
-    let timeout_secs: u64 = env::var("FETCH_TIMEOUT_SECONDS").ok().and_then(|s| s.parse().ok()).unwrap_or(30);
-    
-    let client = Client::builder()
-        .timeout(Duration::from_secs(timeout_secs))
-        .build()
-        .expect("Failed to build reqwest client");
-
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+///
+/// The timer is `config.interval_secs`, unless `config.schedule` is set,
+/// in which case fetches run at the times the cron expression says.
+///
+/// The loop runs inside a supervisor: if it panics (e.g. a bug in parsing),
+/// the panic is logged and the loop is restarted after a growing delay,
+/// instead of the worker dying quietly and the data going stale. Whether it's
+/// running, restarting or stopped is kept in `status`, for /healthz.
+///
+/// `clock` is where the worker gets "now" from, for the cron schedule and the
+/// times it stores.
+///
+/// Use the returned handle to stop the worker and wait for it.
+pub fn spawn_worker(
+    config: WorkerConfig,
+    pool: DbPool,
+    status: Arc<WorkerStatus>,
+    metrics: Arc<Metrics>,
+    clock: &'static dyn Clock,
+) -> WorkerHandle {
+    // If the loop ran at least this long before panicking, it was healthy,
+    // so the restart delay starts over.
+    const HEALTHY_RUN: Duration = Duration::from_secs(300);
+    const MAX_RESTART_DELAY: u64 = 60;
+
+    // One client for the worker's whole life, so connections are kept alive
+    // between fetches. The user agent lets upstreams tell who's asking.
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .user_agent(concat!("lightningnetwork-rust/", env!("CARGO_PKG_VERSION")));
+    if config.dns_cache_ttl_secs > 0 {
+        info!("[Worker] Caching DNS lookups for {}s.", config.dns_cache_ttl_secs);
+        builder = builder.dns_resolver(Arc::new(CachingResolver::new(Duration::from_secs(config.dns_cache_ttl_secs))));
+    }
+    let client = builder.build().expect("Failed to build reqwest client");
+    let config = Arc::new(config);
+    // Refreshes join the cycle in flight, so at most one is ever queued.
+    let (refresh_tx, refresh_rx) = mpsc::channel(1);
+    let in_flight = InFlight::default();
+    let ctx = WorkerContext {
+        config: config.clone(),
+        client,
+        pool,
+        status,
+        metrics,
+        clock,
+        refresh: Arc::new(Mutex::new(refresh_rx)),
+        in_flight: in_flight.clone(),
+        alerts: config.alert_thresholds.clone().map(|file| Arc::new(std::sync::Mutex::new(file))),
+    };
+    let (shutdown_tx, mut shutdown) = watch::channel(false);
+
+    let status = ctx.status.clone();
+    let task = tokio::spawn(async move {
+        let mut restart_delay = 1;
         loop {
-            // Wait for the next tick.
-            interval.tick().await;
-
-            // Simple retry loop.
-            let mut attempts = 0;
-            let max_attempts = 3;
-            let mut backoff = 1;
-
-            loop {
-                match fetch_nodes(&api_url, &client).await {
-                    Ok(nodes) => {
-                        // Got the nodes, now try to save them.
-                        match store_nodes(&nodes) {
-                            Ok((inserted, updated)) => {
-                                if inserted > 0 || updated > 0 {
-                                    info!("[Worker] DB updated. Inserted: {}, Updated: {}.", inserted, updated);
-                                }
-                                break; // All good, break the retry loop.
-                            }
-                            Err(e) => error!("[Worker] Failed to save nodes to DB: {}", e),
-                        }
+            let started = tokio::time::Instant::now();
+            status.set_state(WorkerState::Running);
+            let task = tokio::spawn(run_worker_loop(ctx.clone(), shutdown.clone()));
+
+            match task.await {
+                Ok(()) => break,
+                Err(e) if e.is_panic() => {
+                    status.panics.fetch_add(1, Ordering::Relaxed);
+                    status.set_state(WorkerState::Restarting);
+                    if started.elapsed() >= HEALTHY_RUN {
+                        restart_delay = 1;
+                    }
+                    let payload = e.into_panic();
+                    error!(
+                        "[Worker] Worker task panicked: {}. Restarting in {}s...",
+                        panic_message(payload.as_ref()),
+                        restart_delay
+                    );
+                    if sleep_or_shutdown(&mut shutdown, Duration::from_secs(restart_delay)).await {
+                        break;
                     }
-                    Err(e) => error!("[Worker] Failed to fetch nodes from API: {}", e),
+                    restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
                 }
-
-                // If we're here, something failed. Time to retry.
-                attempts += 1;
-                if attempts >= max_attempts {
-                    warn!("[Worker] Max retries reached. Will try again later.");
+                Err(e) => {
+                    error!("[Worker] Worker task was cancelled: {}", e);
                     break;
                 }
-                
-                info!("[Worker] Retrying in {}s...", backoff);
-                tokio::time::sleep(Duration::from_secs(backoff)).await;
-                backoff *= 2; // Double the wait time for next retry.
             }
         }
+        status.set_state(WorkerState::Stopped);
     });
-} 
\ No newline at end of file
+
+    WorkerHandle { task, shutdown: shutdown_tx, config, refresher: Refresher { tx: refresh_tx, in_flight } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing::TempDb;
+    use chrono::{DateTime, TimeZone};
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, h, m, s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn worker_stops_when_the_cron_schedule_runs_out() {
+        let db = TempDb::new("worker-cron-ends");
+        let mut config = WorkerConfig::from_env().unwrap();
+        config.schedule = Some(Schedule::from_str("0 0 0 1 1 * 2000").unwrap());
+        let status = Arc::new(WorkerStatus::default());
+        let clock: &'static dyn Clock = Box::leak(Box::new(at(0, 0, 0)));
+        assert_eq!(status.state(), WorkerState::Running);
+
+        let worker = spawn_worker(config, db.pool(), status.clone(), Arc::new(Metrics::new()), clock);
+        worker.join().await.unwrap();
+        assert_eq!(status.state(), WorkerState::Stopped);
+        assert_eq!(status.panics(), 0);
+        assert!(!status.has_fetched());
+    }
+
+    #[test]
+    fn cron_next_run_from_a_fixed_clock() {
+        let hourly = Schedule::from_str("0 0 * * * *").unwrap();
+        assert_eq!(until_next_run(&hourly, &at(0, 30, 0)), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(until_next_run(&hourly, &at(0, 59, 59)), Some(Duration::from_secs(1)));
+        // A run time that's exactly now is already past, so the next one is an hour away.
+        assert_eq!(until_next_run(&hourly, &at(1, 0, 0)), Some(Duration::from_secs(3600)));
+
+        let every_15s = Schedule::from_str("*/15 * * * * *").unwrap();
+        assert_eq!(until_next_run(&every_15s, &at(12, 0, 7)), Some(Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn cron_schedule_that_never_fires_again() {
+        let once = Schedule::from_str("0 0 0 1 1 * 2020").unwrap();
+        assert_eq!(until_next_run(&once, &at(0, 0, 0)), None);
+    }
+
+    /// A refresher wired to a queue the test plays the worker's side of.
+    fn refresher() -> (Refresher, mpsc::Receiver<RefreshRequest>) {
+        let (tx, rx) = mpsc::channel(1);
+        (Refresher { tx, in_flight: InFlight::default() }, rx)
+    }
+
+    fn outcome_of(start: Option<RefreshStart>) -> (bool, PendingRefresh) {
+        match start.unwrap() {
+            RefreshStart::Started(pending) => (true, pending),
+            RefreshStart::AlreadyRunning(pending) => (false, pending),
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_join_the_queued_cycle() {
+        let (refresher, mut rx) = refresher();
+        let (started, first) = outcome_of(refresher.start());
+        assert!(started);
+        let (started, second) = outcome_of(refresher.start());
+        assert!(!started, "a second refresh should join the first");
+        let (started, third) = outcome_of(refresher.start());
+        assert!(!started);
+
+        // The worker picks up a single request and runs one cycle for all three.
+        let reply = begin_cycle(&refresher.in_flight, &mut rx, None);
+        assert!(rx.try_recv().is_err(), "only one cycle should have been queued");
+        assert!(matches!(outcome_of(refresher.start()), (false, _)), "running counts too");
+        end_cycle(&refresher.in_flight, reply, CycleOutcome::Stored { inserted: 2, updated: 1 });
+
+        for pending in [first, second, third] {
+            assert!(matches!(pending.outcome().await, Some(CycleOutcome::Stored { inserted: 2, updated: 1 })));
+        }
+        // Once it's done, the next refresh starts a new cycle.
+        assert!(matches!(outcome_of(refresher.start()), (true, _)));
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn refreshes_join_a_scheduled_cycle() {
+        let (refresher, mut rx) = refresher();
+        let reply = begin_cycle(&refresher.in_flight, &mut rx, None);
+
+        let (started, pending) = outcome_of(refresher.start());
+        assert!(!started);
+        assert!(rx.try_recv().is_err());
+        end_cycle(&refresher.in_flight, reply, CycleOutcome::NotModified);
+        assert!(matches!(pending.outcome().await, Some(CycleOutcome::NotModified)));
+    }
+
+    #[tokio::test]
+    async fn refresh_after_a_cycle_died() {
+        let (refresher, mut rx) = refresher();
+        let (_, pending) = outcome_of(refresher.start());
+        // The loop panicked mid-cycle: the reply is dropped without an outcome.
+        drop(begin_cycle(&refresher.in_flight, &mut rx, None));
+
+        assert!(pending.outcome().await.is_none());
+        assert!(matches!(outcome_of(refresher.start()), (true, _)), "a dead cycle can't be joined");
+    }
+
+    #[test]
+    fn refresh_when_the_worker_is_gone() {
+        let (refresher, rx) = refresher();
+        drop(rx);
+        assert!(refresher.start().is_none());
+    }
+
+    fn number(json: &str) -> serde_json::Number {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn capacity_in_sats() {
+        assert_eq!(CapacityUnit::Sats.to_sats(&number("0")), Some(0));
+        assert_eq!(CapacityUnit::Sats.to_sats(&number("123456789")), Some(123_456_789));
+        assert_eq!(CapacityUnit::Sats.to_sats(&number("1.5")), Some(2));
+        assert_eq!(CapacityUnit::Sats.to_sats(&number("1.4")), Some(1));
+        assert_eq!(CapacityUnit::Sats.to_sats(&number("-1")), None);
+    }
+
+    #[test]
+    fn capacity_in_msats_rounds_down() {
+        assert_eq!(CapacityUnit::Msats.to_sats(&number("1000")), Some(1));
+        assert_eq!(CapacityUnit::Msats.to_sats(&number("1999")), Some(1));
+        assert_eq!(CapacityUnit::Msats.to_sats(&number("999")), Some(0));
+        assert_eq!(CapacityUnit::Msats.to_sats(&number("1999.9")), Some(1));
+        assert_eq!(CapacityUnit::Msats.to_sats(&number("-1000")), None);
+    }
+
+    #[test]
+    fn capacity_in_btc_rounds_to_nearest() {
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("1")), Some(100_000_000));
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("0.5")), Some(50_000_000));
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("0.123456784")), Some(12_345_678));
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("0.123456786")), Some(12_345_679));
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("0.00000001")), Some(1));
+        // Too big for an i64 once converted.
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("100000000000")), None);
+        assert_eq!(CapacityUnit::Btc.to_sats(&number("1e30")), None);
+    }
+
+    const CAMEL_PAYLOAD: &str = r#"[{
+        "publicKey": "02aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+        "alias": "ACME", "capacity": 1500, "firstSeen": 1600000000, "channels": 7,
+        "country": {"en": "France"}, "iso_code": "FR"
+    }]"#;
+
+    const SNAKE_PAYLOAD: &str = r#"[{
+        "public_key": "03bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+        "alias": "Zed", "capacity": 2.5, "first_seen": 1700000000,
+        "city": {"en": "Berlin"}, "iso_code": "DE"
+    }]"#;
+
+    #[test]
+    fn parses_camel_case_payload() {
+        let nodes = parse_nodes(CAMEL_PAYLOAD.as_bytes(), FieldCase::Camel).unwrap();
+        assert_eq!(nodes.len(), 1);
+        let node = &nodes[0];
+        assert!(node.public_key.starts_with("02aa"));
+        assert_eq!(node.alias, "ACME");
+        assert_eq!(node.capacity.as_i64(), Some(1500));
+        assert_eq!(node.first_seen, 1_600_000_000);
+        assert_eq!(node.channels, 7);
+        assert_eq!(node.location.country.as_ref().and_then(|c| c.en.as_deref()), Some("France"));
+        assert_eq!(node.location.iso_code.as_deref(), Some("FR"));
+    }
+
+    #[test]
+    fn parses_snake_case_payload() {
+        let nodes = parse_nodes(SNAKE_PAYLOAD.as_bytes(), FieldCase::Snake).unwrap();
+        assert_eq!(nodes.len(), 1);
+        let node = &nodes[0];
+        assert!(node.public_key.starts_with("03bb"));
+        assert_eq!(node.capacity.as_f64(), Some(2.5));
+        assert_eq!(node.first_seen, 1_700_000_000);
+        // Missing from the payload, so it takes the default.
+        assert_eq!(node.channels, 0);
+        assert_eq!(node.location.city.as_ref().and_then(|c| c.en.as_deref()), Some("Berlin"));
+    }
+
+    #[test]
+    fn rejects_payloads_in_the_wrong_shape() {
+        // Each adapter only accepts its own naming.
+        assert!(parse_nodes(CAMEL_PAYLOAD.as_bytes(), FieldCase::Snake).is_err());
+        assert!(parse_nodes(SNAKE_PAYLOAD.as_bytes(), FieldCase::Camel).is_err());
+
+        let neither = r#"[{"pubkey": "02aa", "name": "ACME", "cap": 1}]"#;
+        assert!(parse_nodes(neither.as_bytes(), FieldCase::Camel).is_err());
+        assert!(parse_nodes(neither.as_bytes(), FieldCase::Snake).is_err());
+        assert!(parse_nodes(br#"{"nodes": []}"#, FieldCase::Camel).is_err());
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        let table = [
+            (408, true),
+            (429, true),
+            (500, true),
+            (502, true),
+            (503, true),
+            (504, true),
+            (400, false),
+            (401, false),
+            (403, false),
+            (404, false),
+            (410, false),
+            (422, false),
+        ];
+        for (code, retryable) in table {
+            let status = StatusCode::from_u16(code).unwrap();
+            assert_eq!(is_retryable_status(status), retryable, "status {}", code);
+        }
+    }
+
+    #[test]
+    fn retryable_fetch_errors() {
+        assert!(!is_retryable(&FetchError::TooLarge(1)));
+        let json = serde_json::from_str::<Vec<CamelCaseNode>>("not json").unwrap_err();
+        assert!(!is_retryable(&FetchError::Json(json)));
+        // A URL that can't even be turned into a request.
+        let builder = Client::new().get("not a url").build().unwrap_err();
+        assert!(!is_retryable(&FetchError::Http(builder)));
+    }
+
+    fn node(key_byte: char, alias: &str, capacity: i64) -> Node {
+        Node {
+            public_key: format!("02{}", key_byte.to_string().repeat(64)),
+            alias: alias.to_string(),
+            capacity,
+            first_seen: 1_600_000_000,
+            channels: 1,
+            city: None,
+            country: None,
+            country_code: None,
+        }
+    }
+
+    /// `(updated_at, last_seen)` for every node, by public key.
+    fn times(pool: &DbPool) -> Vec<(i64, i64)> {
+        let conn = pool.get().unwrap();
+        let mut stmt = conn.prepare("SELECT updated_at, last_seen FROM nodes ORDER BY public_key").unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn store_nodes_stamps_the_clock_time() {
+        let db = TempDb::new("worker-store");
+        let pool = db.pool();
+        let t0 = at(0, 0, 0);
+        let t1 = at(1, 0, 0);
+        let (s0, s1) = (t0.timestamp(), t1.timestamp());
+
+        let stored = store_nodes(&pool, &[node('a', "A", 10), node('b', "B", 20)], &t0, &HashMap::new()).unwrap();
+        assert_eq!((stored.new_keys.len(), stored.updated, stored.changed), (2, 0, true));
+        assert_eq!(times(&pool), [(s0, s0), (s0, s0)]);
+
+        // An hour later `a` changed and `b` didn't: both were seen, only `a` was updated.
+        let stored = store_nodes(&pool, &[node('a', "A", 11), node('b', "B", 20)], &t1, &HashMap::new()).unwrap();
+        assert_eq!((stored.new_keys.len(), stored.updated, stored.changed), (0, 1, true));
+        assert_eq!(times(&pool), [(s1, s1), (s0, s1)]);
+
+        // Channels alone don't move `updated_at`, but the data did change.
+        let t2 = at(2, 0, 0);
+        let mut b = node('b', "B", 20);
+        b.channels = 5;
+        let stored = store_nodes(&pool, &[node('a', "A", 11), b], &t2, &HashMap::new()).unwrap();
+        assert_eq!((stored.new_keys.len(), stored.updated, stored.changed), (0, 0, true));
+        assert_eq!(times(&pool), [(s1, t2.timestamp()), (s0, t2.timestamp())]);
+
+        let stored = store_nodes(&pool, &[node('a', "A", 11)], &at(3, 0, 0), &HashMap::new()).unwrap();
+        assert_eq!((stored.updated, stored.changed), (0, false));
+    }
+
+    #[test]
+    fn store_nodes_records_capacity_changes_only() {
+        let db = TempDb::new("worker-history");
+        let pool = db.pool();
+        let history = |pool: &DbPool| -> Vec<i64> {
+            let conn = pool.get().unwrap();
+            let mut stmt = conn.prepare("SELECT capacity FROM node_history ORDER BY recorded_at, id").unwrap();
+            stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+        };
+
+        store_nodes(&pool, &[node('a', "A", 10)], &at(0, 0, 0), &HashMap::new()).unwrap();
+        store_nodes(&pool, &[node('a', "Renamed", 10)], &at(1, 0, 0), &HashMap::new()).unwrap();
+        store_nodes(&pool, &[node('a', "Renamed", 12)], &at(2, 0, 0), &HashMap::new()).unwrap();
+        assert_eq!(history(&pool), [10, 12]);
+
+        // Pruned, then back with the capacity it left with: nothing new to record.
+        prune_stale_nodes(&pool, 60, &at(3, 0, 0)).unwrap();
+        let stored = store_nodes(&pool, &[node('a', "Renamed", 12)], &at(4, 0, 0), &HashMap::new()).unwrap();
+        assert_eq!(stored.new_keys.len(), 1);
+        assert_eq!(history(&pool), [10, 12]);
+    }
+
+    #[test]
+    fn store_nodes_alerts_on_watched_nodes_only() {
+        let db = TempDb::new("worker-alerts");
+        let pool = db.pool();
+        let (a, b) = (node('a', "A", 1000), node('b', "B", 1000));
+        let thresholds = HashMap::from([(a.public_key.clone(), 5.0)]);
+
+        // New nodes have nothing to compare with.
+        let alerts = store_nodes(&pool, &[a, b], &at(0, 0, 0), &thresholds).unwrap().alerts;
+        assert!(alerts.is_empty());
+
+        // `a` moved 4%, `b` 50% but isn't watched.
+        let alerts = store_nodes(&pool, &[node('a', "A", 1040), node('b', "B", 1500)], &at(1, 0, 0), &thresholds).unwrap().alerts;
+        assert!(alerts.is_empty());
+
+        let alerts = store_nodes(&pool, &[node('a', "A", 980), node('b', "B", 1500)], &at(2, 0, 0), &thresholds).unwrap().alerts;
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].from_sats, alerts[0].to_sats), (1040, 980));
+    }
+
+    #[test]
+    fn prune_uses_the_clock_time() {
+        let db = TempDb::new("worker-prune");
+        let pool = db.pool();
+        store_nodes(&pool, &[node('a', "A", 10)], &at(0, 0, 0), &HashMap::new()).unwrap();
+        store_nodes(&pool, &[node('b', "B", 20)], &at(1, 0, 0), &HashMap::new()).unwrap();
+
+        // At 01:30 with a 1h TTL, only `a` (last seen at 00:00) is stale.
+        assert_eq!(prune_stale_nodes(&pool, 3600, &at(1, 30, 0)).unwrap(), 1);
+        assert_eq!(prune_stale_nodes(&pool, 3600, &at(1, 30, 0)).unwrap(), 0);
+        assert_eq!(times(&pool).len(), 1);
+    }
+}